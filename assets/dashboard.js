@@ -0,0 +1,34 @@
+const text = (id, value) => { document.getElementById(id).textContent = value; };
+const row = (cells, className) => {
+  const tr = document.createElement("tr");
+  if (className) {
+    tr.className = className;
+  }
+  for (const cell of cells) {
+    const td = document.createElement("td");
+    td.textContent = cell;
+    tr.appendChild(td);
+  }
+  return tr;
+};
+
+const events = new EventSource("/admin/stats/stream");
+events.onerror = () => text("state", "Disconnected, retrying...");
+events.onmessage = (event) => {
+  const s = JSON.parse(event.data);
+  text("state", "Updated " + new Date().toLocaleTimeString());
+  text("version", s.version);
+  text("rules-version", s.rules_version);
+  text("uptime", s.uptime_secs);
+  text("requests", s.requests);
+  text("error-rate", (s.error_rate * 100).toFixed(1) + "%");
+
+  const throughput = document.getElementById("throughput");
+  throughput.replaceChildren(
+    ...Object.entries(s.throughput_by_case).map(([c, rate]) => row([c, rate.toFixed(2)])));
+  const recent = document.getElementById("recent");
+  recent.replaceChildren(...s.recent.map((r) => row(
+    [r.at, r.identity || "-", r.tenant || "-", r.cases.join(", "), r.items, r.failed,
+     r.latency_ms.toFixed(1)],
+    r.failed > 0 ? "failed" : null)));
+};
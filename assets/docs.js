@@ -0,0 +1,79 @@
+// operations of the OpenAPI document, each with a form sending it to this server
+const element = (tag, text) => {
+  const e = document.createElement(tag);
+  if (text !== undefined) {
+    e.textContent = text;
+  }
+  return e;
+};
+
+// inlines `$ref`s to `components`, for display
+const resolve = (schema, components) => {
+  if (schema && schema.$ref) {
+    return resolve(components[schema.$ref.split("/").pop()], components);
+  }
+  return schema;
+};
+
+const operation = (path, method, op, components) => {
+  const section = element("section");
+  const heading = element("h2");
+  heading.append(element("span", method), path);
+  heading.firstChild.className = "method";
+  section.append(heading, element("p", op.summary || ""));
+
+  const inputs = {};
+  for (const param of op.parameters || []) {
+    const input = element("input");
+    input.placeholder = param.name;
+    inputs[param.name] = input;
+    section.append(element("p", param.in + " " + param.name + " "), input);
+  }
+  let body = null;
+  const content = op.requestBody && op.requestBody.content["application/json"];
+  if (content) {
+    section.append(element("h3", "Request body"));
+    section.append(element("pre", JSON.stringify(resolve(content.schema, components), null, 2)));
+    body = element("textarea", "{}");
+    body.rows = 6;
+    body.cols = 60;
+    section.append(body);
+  }
+  section.append(element("h3", "Responses"));
+  for (const [status, resp] of Object.entries(op.responses || {})) {
+    section.append(element("p", status + " " + resp.description));
+  }
+
+  const send = element("button", "Try it");
+  const result = element("pre");
+  send.addEventListener("click", async () => {
+    let target = path;
+    for (const [name, input] of Object.entries(inputs)) {
+      target = target.replace("{" + name + "}", encodeURIComponent(input.value));
+    }
+    const headers = { "X-Api-Key": document.getElementById("api-key").value };
+    if (body) {
+      headers["Content-Type"] = "application/json";
+    }
+    const resp = await fetch(target + "?pretty=true", {
+      method: method.toUpperCase(),
+      headers,
+      body: body ? body.value : undefined,
+    });
+    result.textContent = resp.status + " " + resp.statusText + "\n" + (await resp.text());
+  });
+  section.append(send, result);
+  return section;
+};
+
+(async () => {
+  const doc = await (await fetch("/openapi.json")).json();
+  const components = (doc.components && doc.components.schemas) || {};
+  document.getElementById("title").textContent = doc.info.title + " " + doc.info.version;
+  const operations = document.getElementById("operations");
+  for (const [path, methods] of Object.entries(doc.paths)) {
+    for (const [method, op] of Object.entries(methods)) {
+      operations.append(operation(path, method, op, components));
+    }
+  }
+})();
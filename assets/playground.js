@@ -0,0 +1,29 @@
+// token of the session opened with an API key, sent with every POST
+let csrfToken = null;
+
+document.getElementById("sign-in").addEventListener("click", async () => {
+  const result = document.getElementById("result");
+  const resp = await fetch("/session", {
+    method: "POST",
+    headers: { "X-Api-Key": document.getElementById("api-key").value },
+  });
+  if (resp.ok) {
+    csrfToken = (await resp.json()).csrf_token;
+    document.getElementById("api-key").value = "";
+  }
+  result.textContent = resp.status + " " + resp.statusText;
+});
+
+document.getElementById("send").addEventListener("click", async () => {
+  const result = document.getElementById("result");
+  const headers = { "Content-Type": "application/json" };
+  if (csrfToken) {
+    headers["X-CSRF-Token"] = csrfToken;
+  }
+  const resp = await fetch("/v1/compute?pretty=true", {
+    method: "POST",
+    headers,
+    body: document.getElementById("params").value,
+  });
+  result.textContent = resp.status + " " + resp.statusText + "\n" + (await resp.text());
+});
@@ -0,0 +1,165 @@
+//! `[access.admin]` and `[access.compute]` take `allow` and `deny` CIDR lists for `/admin`
+//! and every other route. Denied or not allowed client addresses get `403` before routing.
+
+use std::net::{AddrParseError, IpAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage};
+use futures::future::{err, ok, Either, Ready};
+use ipnet::IpNet;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::json_error;
+use crate::proxy::{parse_nets, ClientIp};
+
+/// CIDRs let in and kept out, `deny` wins and an empty `allow` lets everyone in
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IpRules {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+/// Rules for `/admin` and for every other route
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessLists {
+    pub compute: IpRules,
+    pub admin: IpRules,
+}
+
+struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    fn new(rules: &IpRules) -> Result<Self, AddrParseError> {
+        Ok(IpFilter {
+            allow: parse_nets(&rules.allow)?,
+            deny: parse_nets(&rules.deny)?,
+        })
+    }
+
+    fn permits(&self, ip: &IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+    }
+}
+
+pub struct AccessControl {
+    compute: IpFilter,
+    admin: IpFilter,
+}
+
+impl AccessControl {
+    pub fn new(lists: &AccessLists) -> Result<Self, AddrParseError> {
+        Ok(AccessControl {
+            compute: IpFilter::new(&lists.compute)?,
+            admin: IpFilter::new(&lists.admin)?,
+        })
+    }
+
+    pub fn permits(&self, path: &str, ip: &IpAddr) -> bool {
+        if path == "/admin" || path.starts_with("/admin/") {
+            self.admin.permits(ip)
+        } else {
+            self.compute.permits(ip)
+        }
+    }
+}
+
+/// Middleware answering 403 to client addresses the lists keep out of the route
+pub struct IpAccess(pub Arc<AccessControl>);
+
+impl<S, B> Transform<S> for IpAccess
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IpAccessMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IpAccessMiddleware {
+            service,
+            access: self.0.clone(),
+        })
+    }
+}
+
+pub struct IpAccessMiddleware<S> {
+    service: S,
+    access: Arc<AccessControl>,
+}
+
+impl<S, B> Service for IpAccessMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let ip = match req.extensions().get::<ClientIp>() {
+            Some(ClientIp(ip)) => Some(*ip),
+            None => req.peer_addr().map(|a| a.ip()),
+        };
+        // the path as the router decodes it, `/%61dmin` is routed to `/admin`
+        let path = req.match_info().path();
+        match ip {
+            Some(ip) if !self.access.permits(path, &ip) => {
+                let resp = json_error(StatusCode::FORBIDDEN, "Forbidden.");
+                Either::Right(err(
+                    InternalError::from_response("address denied", resp).into()
+                ))
+            }
+            _ => Either::Left(self.service.call(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = AccessControl::new(&AccessLists {
+            compute: IpRules {
+                allow: vec![],
+                deny: vec!["203.0.113.0/24".into()],
+            },
+            admin: IpRules {
+                allow: vec!["10.0.0.0/8".into()],
+                deny: vec!["10.0.0.13".into()],
+            },
+        })
+        .unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(access.permits("/v1/compute", &ip("198.51.100.7")));
+        assert!(!access.permits("/v1/compute", &ip("203.0.113.9")));
+        assert!(access.permits("/admin/config", &ip("10.1.2.3")));
+        assert!(!access.permits("/admin/config", &ip("10.0.0.13")));
+        assert!(!access.permits("/admin", &ip("198.51.100.7")));
+    }
+}
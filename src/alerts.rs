@@ -0,0 +1,299 @@
+//! With `[error_alert]` set, the share of failed computes (and other 5xx answers) over the
+//! last `window` seconds is watched. Once at least `min_requests` were seen and `threshold`
+//! is reached, its `webhook` gets the rate with the shapes of recent failing payloads
+//! (value types only), as `{"text": ...}` with `slack = true`, at most once per `cooldown`
+//! seconds.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::client::Client;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::stats::Outcomes;
+
+/// Failing payload shapes sent along with an alert
+const SAMPLES: usize = 5;
+
+/// Webhook told when the share of failing computes crosses `threshold`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ErrorAlertSettings {
+    /// URL the alert is POSTed to
+    pub webhook: String,
+    /// Post `{"text": ...}` as Slack incoming webhooks expect, instead of the alert itself
+    pub slack: bool,
+    /// Seconds requests are counted over
+    pub window: u64,
+    /// Share of failed requests within `window`, from 0 to 1, that fires the alert
+    pub threshold: f64,
+    /// Requests within `window` below which no alert fires
+    pub min_requests: usize,
+    /// Seconds after an alert during which no further one fires
+    pub cooldown: u64,
+}
+
+impl Default for ErrorAlertSettings {
+    fn default() -> Self {
+        ErrorAlertSettings {
+            webhook: String::new(),
+            slack: false,
+            window: 60,
+            threshold: 0.5,
+            min_requests: 20,
+            cooldown: 300,
+        }
+    }
+}
+
+/// Shapes of the failed payloads of a response, attached to response extensions by handlers
+pub struct FailedShapes(pub Vec<String>);
+
+fn shape_of(value: &Value) -> Value {
+    match value {
+        Value::Null => json!("null"),
+        Value::Bool(_) => json!("bool"),
+        Value::Number(_) => json!("number"),
+        Value::String(_) => json!("string"),
+        Value::Array(items) => Value::Array(items.iter().take(1).map(shape_of).collect()),
+        Value::Object(map) => {
+            Value::Object(map.iter().map(|(k, v)| (k.clone(), shape_of(v))).collect())
+        }
+    }
+}
+
+/// `payload` with every value replaced by its JSON type, e.g. `{"a":"bool","d":"null"}`
+pub fn shape<T: serde::Serialize>(payload: &T) -> String {
+    shape_of(&serde_json::to_value(payload).unwrap_or(Value::Null)).to_string()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Alert {
+    pub window_secs: u64,
+    pub requests: usize,
+    pub errors: usize,
+    pub error_rate: f64,
+    /// Shapes of the latest failed payloads, most recent first
+    pub samples: Vec<String>,
+}
+
+impl Alert {
+    fn text(&self) -> String {
+        format!(
+            "Error rate {:.0}% over the last {}s ({} of {} computes failed). Recent failing payloads: {}",
+            self.error_rate * 100.0,
+            self.window_secs,
+            self.errors,
+            self.requests,
+            self.samples.join(", ")
+        )
+    }
+}
+
+#[derive(Default)]
+struct State {
+    requests: VecDeque<(Instant, bool)>,
+    samples: VecDeque<String>,
+    alerted: Option<Instant>,
+}
+
+/// Error rate of computes over a sliding window
+pub struct ErrorMonitor {
+    settings: ErrorAlertSettings,
+    state: Mutex<State>,
+}
+
+impl ErrorMonitor {
+    pub fn new(settings: ErrorAlertSettings) -> Self {
+        ErrorMonitor {
+            settings,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Counts a request, the alert to send if it made the error rate cross the threshold
+    pub fn record(&self, now: Instant, failed: bool, shapes: Vec<String>) -> Option<Alert> {
+        let window = Duration::from_secs(self.settings.window);
+        let mut state = self.state.lock().unwrap();
+        while let Some((at, _)) = state.requests.front() {
+            if now.duration_since(*at) <= window {
+                break;
+            }
+            state.requests.pop_front();
+        }
+        state.requests.push_back((now, failed));
+        for shape in shapes {
+            state.samples.retain(|s| *s != shape);
+            state.samples.push_front(shape);
+        }
+        state.samples.truncate(SAMPLES);
+
+        let requests = state.requests.len();
+        let errors = state.requests.iter().filter(|(_, failed)| *failed).count();
+        let error_rate = errors as f64 / requests as f64;
+        let cooling = state.alerted.map_or(false, |at| {
+            now.duration_since(at) < Duration::from_secs(self.settings.cooldown)
+        });
+        if !failed || cooling || requests < self.settings.min_requests {
+            return None;
+        }
+        if error_rate < self.settings.threshold {
+            return None;
+        }
+        state.alerted = Some(now);
+        Some(Alert {
+            window_secs: self.settings.window,
+            requests,
+            errors,
+            error_rate,
+            samples: state.samples.iter().cloned().collect(),
+        })
+    }
+
+    async fn notify(self: Arc<Self>, alert: Alert) {
+        let url = self.settings.webhook.as_str();
+        warn!("{}", alert.text());
+        let sent = if self.settings.slack {
+            Client::new()
+                .post(url)
+                .send_json(&json!({ "text": alert.text() }))
+                .await
+        } else {
+            Client::new().post(url).send_json(&alert).await
+        };
+        match sent {
+            Ok(resp) if resp.status().is_success() => info!("Sent error alert to {}", url),
+            Ok(resp) => warn!("Error alert webhook {} answered {}", url, resp.status()),
+            Err(e) => warn!("Could not send error alert to {}: {:?}", url, e),
+        }
+    }
+}
+
+/// Middleware feeding computes, and other requests failing with 5xx, into `ErrorMonitor`
+pub struct WatchErrors(pub Arc<ErrorMonitor>);
+
+impl<S, B> Transform<S> for WatchErrors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = WatchErrorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(WatchErrorsMiddleware {
+            service,
+            monitor: self.0.clone(),
+        })
+    }
+}
+
+pub struct WatchErrorsMiddleware<S> {
+    service: S,
+    monitor: Arc<ErrorMonitor>,
+}
+
+impl<S, B> Service for WatchErrorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let monitor = self.monitor.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = match fut.await {
+                Ok(res) => res,
+                Err(e) => {
+                    // server errors that never became a response, e.g. timeouts, count
+                    // as failed, rejections like missing credentials are not computes
+                    let status = e.as_response_error().status_code();
+                    if status.is_server_error() {
+                        if let Some(alert) = monitor.record(Instant::now(), true, vec![]) {
+                            actix_rt::spawn(monitor.notify(alert));
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+            let failed = {
+                let extensions = res.response().extensions();
+                match extensions.get::<Outcomes>() {
+                    Some(Outcomes(outcomes)) => Some(outcomes.iter().any(|o| o.error.is_some())),
+                    None if res.status().is_server_error() => Some(true),
+                    None => None,
+                }
+            };
+            if let Some(failed) = failed {
+                let shapes = res
+                    .response_mut()
+                    .extensions_mut()
+                    .remove::<FailedShapes>()
+                    .map(|FailedShapes(shapes)| shapes)
+                    .unwrap_or_default();
+                if let Some(alert) = monitor.record(Instant::now(), failed, shapes) {
+                    actix_rt::spawn(monitor.notify(alert));
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Params;
+
+    #[test]
+    fn fires_once_above_threshold() {
+        let monitor = ErrorMonitor::new(ErrorAlertSettings {
+            min_requests: 4,
+            ..ErrorAlertSettings::default()
+        });
+        let now = Instant::now();
+        let failing = shape(&Params {
+            d: Some(1.5),
+            ..Params::default()
+        });
+        assert!(failing.contains(r#""a":"null""#));
+        assert!(failing.contains(r#""d":"number""#));
+
+        assert!(monitor.record(now, false, vec![]).is_none());
+        assert!(monitor.record(now, true, vec![failing.clone()]).is_none());
+        assert!(monitor.record(now, false, vec![]).is_none());
+        let alert = monitor.record(now, true, vec![failing.clone()]).unwrap();
+        assert_eq!((alert.requests, alert.errors), (4, 2));
+        assert_eq!(alert.samples, vec![failing.clone()]);
+        // cooling down
+        assert!(monitor.record(now, true, vec![]).is_none());
+
+        // the window moved past every earlier request
+        let later = now + Duration::from_secs(400);
+        assert!(monitor.record(later, true, vec![]).is_none());
+    }
+}
@@ -0,0 +1,77 @@
+//! A playground for `/v1/compute` is served from the binary at `/assets/playground.html`,
+//! and API docs of `/openapi.json` with a form to try each operation at
+//! `/assets/docs.html`. `assets = false` turns both off, the dashboard's files are served
+//! from the binary too.
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use rust_embed::RustEmbed;
+
+use crate::errors::json_error;
+use crate::etag;
+
+/// Files under `assets/`, compiled into the binary
+#[derive(RustEmbed)]
+#[folder = "assets/"]
+struct Asset;
+
+/// HTML is revalidated on every load, the files it references are cached for a day
+fn cache_control(path: &str) -> &'static str {
+    if path.ends_with(".html") {
+        "no-cache"
+    } else {
+        "public, max-age=86400"
+    }
+}
+
+pub async fn get(path: web::Path<String>, req: HttpRequest) -> HttpResponse {
+    serve(&path, &req)
+}
+
+/// `path` under `assets/`, `304` if the client holds it already
+pub fn serve(path: &str, req: &HttpRequest) -> HttpResponse {
+    let content = match Asset::get(path) {
+        Some(content) => content,
+        None => return json_error(StatusCode::NOT_FOUND, "Resource not found."),
+    };
+    let tag = format!("\"{}\"", sha1::Sha1::from(&content[..]).digest());
+    if etag::matches(req, &tag) {
+        return HttpResponse::NotModified()
+            .header(header::ETAG, tag)
+            .header(header::CACHE_CONTROL, cache_control(path))
+            .finish();
+    }
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    HttpResponse::Ok()
+        .header(header::ETAG, tag)
+        .header(header::CACHE_CONTROL, cache_control(path))
+        .content_type(mime.as_ref())
+        .body(content.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn serves_and_revalidates() {
+        let mut app =
+            test::init_service(App::new().route("/assets/{path:.*}", web::get().to(get))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/assets/playground.html")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/html");
+        let tag = resp.headers()[header::ETAG].clone();
+
+        let req = test::TestRequest::get()
+            .uri("/assets/playground.html")
+            .header(header::IF_NONE_MATCH, tag)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+}
@@ -0,0 +1,205 @@
+//! With `[audit]` set, every call changing state (admin `PUT`/`POST`, schedule creation and
+//! deletion) is appended to `file` as a JSON line with time, identity, client address,
+//! method, path, query and status, computes too with `computes = true`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::{Error, HttpMessage};
+use chrono::{DateTime, Utc};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::Auth;
+use crate::proxy::ClientIp;
+use crate::redact;
+use crate::tls;
+
+/// Where state-changing calls are recorded
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditSettings {
+    /// File entries are appended to, one JSON object per line
+    pub file: String,
+    /// Record compute requests as well
+    #[serde(default)]
+    pub computes: bool,
+}
+
+/// One audited call
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    time: DateTime<Utc>,
+    identity: Option<&'a str>,
+    ip: Option<String>,
+    method: &'a str,
+    path: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    query: &'a str,
+    status: u16,
+    /// Client certificate subject over mutual TLS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_cert: Option<String>,
+}
+
+/// Append-only sink of audit entries
+pub struct AuditLog {
+    file: Mutex<File>,
+    computes: bool,
+    redact: Vec<String>,
+}
+
+impl AuditLog {
+    /// Appends to `file`, masking the query values of `redact`
+    pub fn open(settings: &AuditSettings, redact: &[String]) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&settings.file)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            computes: settings.computes,
+            redact: redact.to_vec(),
+        })
+    }
+
+    /// Calls changing state, i.e. anything but reads, computes only if configured
+    fn covers(&self, method: &Method, path: &str) -> bool {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            return false;
+        }
+        let path = path.strip_prefix("/v1").unwrap_or(path);
+        self.computes || !(path == "/compute" || path == "/compute/batch")
+    }
+
+    fn append(&self, entry: &Entry) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => return warn!("Could not serialize audit entry: {:?}", e),
+        };
+        line.push(b'\n');
+        // a single write per line keeps entries whole with O_APPEND
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            warn!("Could not write audit entry: {:?}", e);
+        }
+    }
+}
+
+/// Middleware recording state-changing calls with their caller and status, rejected
+/// ones included, and every call with refused credentials. Passes everything through
+/// without a log.
+pub struct Audit(pub Option<Arc<AuditLog>>);
+
+impl<S, B> Transform<S> for Audit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuditMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuditMiddleware {
+            service,
+            log: self.0.clone(),
+        })
+    }
+}
+
+pub struct AuditMiddleware<S> {
+    service: S,
+    log: Option<Arc<AuditLog>>,
+}
+
+impl<S, B> Service for AuditMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let (identity, rejected) = match req.extensions().get::<Auth>() {
+            Some(Auth::Identified(identity)) => (Some(identity.name.clone()), false),
+            Some(Auth::Invalid(_)) | Some(Auth::LockedOut(_)) => (None, true),
+            _ => (None, false),
+        };
+        let log = match &self.log {
+            Some(log) if rejected || log.covers(req.method(), req.path()) => log.clone(),
+            _ => return Box::pin(self.service.call(req)),
+        };
+        let ip = req
+            .extensions()
+            .get::<ClientIp>()
+            .map(|ClientIp(ip)| *ip)
+            .or_else(|| req.peer_addr().map(|addr| addr.ip()));
+        let client_cert = tls::client_subject(&req);
+        let (method, path, query) = (
+            req.method().to_string(),
+            req.path().to_string(),
+            redact::query(req.query_string(), &log.redact).into_owned(),
+        );
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            log.append(&Entry {
+                time: Utc::now(),
+                identity: identity.as_deref(),
+                ip: ip.map(|ip| ip.to_string()),
+                method: &method,
+                path: &path,
+                query: &query,
+                status: status.as_u16(),
+                client_cert,
+            });
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covers_state_changes() {
+        let path = std::env::temp_dir().join("rtp-audit-test.log");
+        let log = AuditLog::open(
+            &AuditSettings {
+                file: path.to_string_lossy().into_owned(),
+                computes: false,
+            },
+            &[],
+        )
+        .unwrap();
+
+        assert!(log.covers(&Method::PUT, "/admin/maintenance"));
+        assert!(log.covers(&Method::DELETE, "/v1/schedules/1"));
+        assert!(!log.covers(&Method::GET, "/admin/maintenance"));
+        assert!(!log.covers(&Method::POST, "/v1/compute"));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,623 @@
+//! With `api_keys` set, compute, batch, schedule and result routes require one of them in
+//! `X-Api-Key`, answering `401`/`403` as `application/problem+json` otherwise. The key's
+//! `name` shows in the access log and in `by_identity` of `/stats`.
+//!
+//! Results, schedules and `/stats` are kept per identity, callers only see and delete their
+//! own and get `404` for those of others. `/stats` counts only their computes (`401`
+//! without credentials). Callers with `ops` see everything, as does everyone while
+//! authentication is off.
+//!
+//! Keys, tokens and signing clients carry `roles` (`compute` if none are listed). Compute,
+//! batch, schedule and result routes need `compute`, `/admin` needs `ops`, else `403`. JWTs
+//! take them from a `roles` claim, introspected tokens from their `scope`. `/admin` is
+//! closed while authentication is off, except to callers sending `admin_bootstrap_secret`
+//! in `X-Bootstrap-Secret`, who act as `ops`, e.g. to issue the first key. Roles like
+//! `case:C1` scope a caller to those cases, computing or scheduling any other one (the
+//! default case included) answers `403` naming the missing scope.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::{header, HeaderMap, Method, StatusCode};
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use chrono::Utc;
+use futures::future::{err, ok, Either, LocalBoxFuture, Ready};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::errors::problem;
+use crate::introspection::Introspector;
+use crate::jwt::Jwks;
+use crate::keys::KeyStore;
+use crate::lockout::Lockout;
+use crate::proxy::ClientIp;
+use crate::quota::{Exceeded, Quotas};
+use crate::session::{Sessions, CSRF_HEADER, SESSION_COOKIE};
+use crate::signature::{self, Refused, Signatures, SIGNATURE_HEADER};
+use crate::stats::Stats;
+use crate::types::Case;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// Name of the authenticated caller, set for the access log, never taken from clients
+pub const IDENTITY_HEADER: &str = "x-identity";
+/// Carries `admin_bootstrap_secret`, e.g. to issue the first key while authentication is off
+pub const BOOTSTRAP_HEADER: &str = "x-bootstrap-secret";
+/// Identity of callers presenting the bootstrap secret
+pub const BOOTSTRAP: &str = "bootstrap";
+
+/// Role of ordinary clients, the one keys get if they list none
+pub const COMPUTE: &str = "compute";
+/// Role required by `/admin`
+pub const OPS: &str = "ops";
+/// Prefix of roles limiting the cases a caller may compute, e.g. `case:C1`
+pub const CASE_SCOPE: &str = "case:";
+
+pub fn default_roles() -> Vec<String> {
+    vec![COMPUTE.to_string()]
+}
+
+/// Key a client authenticates with, `name` is what logs and stats show
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+    #[serde(default = "default_roles")]
+    pub roles: Vec<String>,
+}
+
+/// Authenticated caller and its roles, kept in request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    pub roles: Vec<String>,
+}
+
+impl Identity {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    /// Cases it may compute, any unless `case:<case>` scopes are listed among its roles
+    pub fn may_compute(&self, case: &Case) -> bool {
+        let scoped = self.roles.iter().any(|r| r.starts_with(CASE_SCOPE));
+        !scoped || self.has_role(&case_scope(case))
+    }
+}
+
+fn case_scope(case: &Case) -> String {
+    format!("{}{:?}", CASE_SCOPE, case)
+}
+
+/// Who is asking, for handlers limiting data to its owner
+#[derive(Debug, Clone)]
+pub struct Caller(Option<Auth>);
+
+impl Caller {
+    pub fn of(req: &HttpRequest) -> Caller {
+        Caller(req.extensions().get::<Auth>().cloned())
+    }
+
+    /// Name data is recorded under, `None` while authentication is off
+    pub fn owner(&self) -> Option<&str> {
+        match &self.0 {
+            Some(Auth::Identified(identity)) => Some(&identity.name),
+            _ => None,
+        }
+    }
+
+    /// With authentication off, and for `ops`, every owner's data is visible
+    pub fn sees_all(&self) -> bool {
+        match &self.0 {
+            None => true,
+            Some(Auth::Identified(identity)) => identity.has_role(OPS),
+            Some(_) => false,
+        }
+    }
+
+    pub fn may_see(&self, owner: Option<&str>) -> bool {
+        self.sees_all() || (self.owner().is_some() && self.owner() == owner)
+    }
+
+    /// `403` naming the scope missing for `case`
+    pub fn require_case(&self, case: &Case) -> Result<(), Error> {
+        match &self.0 {
+            Some(Auth::Identified(identity)) if !identity.may_compute(case) => {
+                let detail = format!("Missing scope {}", case_scope(case));
+                let resp = problem(StatusCode::FORBIDDEN, detail.clone());
+                Err(InternalError::from_response(detail, resp).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Result of checking the credentials of a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Auth {
+    Missing,
+    /// Credentials were sent but rejected, for the reason given
+    Invalid(&'static str),
+    /// Refused unchecked after repeated failures, for the seconds given
+    LockedOut(u64),
+    /// Could not be checked for now, for the seconds given
+    Unavailable(u64),
+    Identified(Identity),
+}
+
+/// Known API keys and the verifiers of bearer tokens,
+/// authentication is off while there are none
+pub struct Authenticator {
+    keys: HashMap<String, Identity>,
+    managed: web::Data<KeyStore>,
+    sessions: web::Data<Sessions>,
+    jwks: Option<Arc<Jwks>>,
+    introspector: Option<Arc<Introspector>>,
+    signatures: Option<Signatures>,
+    lockout: Option<Lockout>,
+    /// SHA-256 of `admin_bootstrap_secret`
+    bootstrap: Option<Vec<u8>>,
+}
+
+impl Authenticator {
+    pub fn new(
+        keys: &[ApiKey],
+        managed: web::Data<KeyStore>,
+        sessions: web::Data<Sessions>,
+        jwks: Option<Arc<Jwks>>,
+        introspector: Option<Arc<Introspector>>,
+        signatures: Option<Signatures>,
+        lockout: Option<Lockout>,
+    ) -> Self {
+        Authenticator {
+            keys: keys
+                .iter()
+                .map(|k| {
+                    let identity = Identity {
+                        name: k.name.clone(),
+                        roles: k.roles.clone(),
+                    };
+                    (k.key.clone(), identity)
+                })
+                .collect(),
+            managed,
+            sessions,
+            jwks,
+            introspector,
+            signatures,
+            lockout,
+            bootstrap: None,
+        }
+    }
+
+    /// Accepts `secret` in `BOOTSTRAP_HEADER` as an `ops` credential, also while
+    /// authentication is otherwise off
+    pub fn with_bootstrap(mut self, secret: Option<&str>) -> Self {
+        self.bootstrap = secret.map(|s| Sha256::digest(s.as_bytes()).to_vec());
+        self
+    }
+
+    /// Compares digests, so the time taken tells nothing about the secret
+    fn check_bootstrap(&self, req: &ServiceRequest) -> Auth {
+        let sent = req
+            .headers()
+            .get(BOOTSTRAP_HEADER)
+            .map(|v| Sha256::digest(v.as_bytes()).to_vec());
+        match (&self.bootstrap, sent) {
+            (Some(expected), Some(sent)) if *expected == sent => Auth::Identified(Identity {
+                name: BOOTSTRAP.into(),
+                roles: vec![OPS.into()],
+            }),
+            _ => Auth::Invalid("Wrong bootstrap secret"),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+            || !self.managed.is_empty()
+            || self.jwks.is_some()
+            || self.introspector.is_some()
+            || self.signatures.is_some()
+    }
+
+    async fn verify_signature(
+        signatures: &Signatures,
+        header: &str,
+        req: &mut ServiceRequest,
+    ) -> Auth {
+        let body = match signature::read_body(req, signature::MAX_BODY).await {
+            Ok(Some(body)) => body,
+            Ok(None) => return Auth::Invalid("Signed body too large"),
+            Err(_) => return Auth::Invalid("Could not read signed body"),
+        };
+        let target = req
+            .uri()
+            .path_and_query()
+            .map_or(req.path(), |target| target.as_str());
+        match signatures.verify(
+            header,
+            req.method().as_str(),
+            target,
+            &body,
+            Utc::now().timestamp(),
+        ) {
+            Ok(client) => Auth::Identified(client),
+            Err(Refused::Invalid(reason)) => Auth::Invalid(reason),
+            Err(Refused::Full(secs)) => Auth::Unavailable(secs),
+        }
+    }
+
+    pub fn check(&self, key: Option<&str>) -> Auth {
+        match key {
+            None => Auth::Missing,
+            Some(key) => match self.keys.get(key) {
+                Some(identity) => Auth::Identified(identity.clone()),
+                None => match self.managed.identify(key, Utc::now()) {
+                    Some(Ok(identity)) => Auth::Identified(identity),
+                    Some(Err(reason)) => Auth::Invalid(reason),
+                    None => Auth::Invalid("Unknown API key"),
+                },
+            },
+        }
+    }
+
+    /// Bearer tokens are checked if a verifier is configured, API keys otherwise.
+    /// JWTs go to the JWKS, opaque tokens to the introspection endpoint. Without an API key
+    /// the playground's session cookie is checked, CSRF token included.
+    async fn authenticate(&self, req: &ServiceRequest) -> Auth {
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim);
+        let is_jwt = bearer.map_or(false, |t| t.matches('.').count() == 2);
+        let verdict = match (bearer, &self.jwks, &self.introspector) {
+            (Some(token), Some(jwks), _) if is_jwt || self.introspector.is_none() => {
+                jwks.verify(token)
+            }
+            (Some(token), _, Some(introspector)) => introspector.verify(token).await,
+            _ => {
+                let key = req.headers().get(API_KEY_HEADER);
+                return match (key, req.cookie(SESSION_COOKIE)) {
+                    (None, Some(session)) => {
+                        let csrf = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+                        self.sessions.check(session.value(), csrf, req.method())
+                    }
+                    _ => self.check(key.map(|v| v.to_str().unwrap_or_default())),
+                };
+            }
+        };
+        match verdict {
+            Ok(identity) => Auth::Identified(identity),
+            Err(reason) => Auth::Invalid(reason),
+        }
+    }
+
+    /// Stores the `Auth` of `req` in its extensions and the caller in `IDENTITY_HEADER`.
+    /// Credentials from a locked out address are not checked at all. Failures count
+    /// against the address only, a client named by unverified credentials could
+    /// otherwise be locked out by anyone.
+    pub async fn identify(&self, req: &mut ServiceRequest) {
+        req.headers_mut().remove(IDENTITY_HEADER);
+        let bootstrap = self.bootstrap.is_some() && req.headers().contains_key(BOOTSTRAP_HEADER);
+        if !self.is_enabled() && !bootstrap {
+            return;
+        }
+        let signed = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .map(|v| v.to_str().unwrap_or_default().to_string());
+        let now = Utc::now().timestamp();
+        let subject = lockout_subject(req);
+        let locked = match (&self.lockout, &subject) {
+            (Some(lockout), Some(subject)) if has_credentials(req) => lockout.locked(subject, now),
+            _ => None,
+        };
+        let auth = match (locked, &self.signatures, signed) {
+            (Some(secs), _, _) => Auth::LockedOut(secs),
+            _ if bootstrap => self.check_bootstrap(req),
+            (None, Some(signatures), Some(header)) => {
+                Self::verify_signature(signatures, &header, req).await
+            }
+            _ => self.authenticate(req).await,
+        };
+        match (&self.lockout, &subject, &auth) {
+            (Some(lockout), Some(subject), Auth::Invalid(_)) => lockout.fail(subject, now),
+            (Some(lockout), Some(subject), Auth::Identified(_)) => lockout.clear(subject),
+            _ => {}
+        }
+        if let Auth::Identified(identity) = &auth {
+            if let Ok(v) = header::HeaderValue::from_str(&identity.name) {
+                req.headers_mut()
+                    .insert(header::HeaderName::from_static(IDENTITY_HEADER), v);
+            }
+        }
+        req.extensions_mut().insert(auth);
+    }
+}
+
+fn has_credentials(req: &ServiceRequest) -> bool {
+    [
+        API_KEY_HEADER,
+        SIGNATURE_HEADER,
+        BOOTSTRAP_HEADER,
+        header::AUTHORIZATION.as_str(),
+    ]
+    .iter()
+    .any(|name| req.headers().contains_key(*name))
+        || req.cookie(SESSION_COOKIE).is_some()
+}
+
+/// Client address failures are counted against
+fn lockout_subject(req: &ServiceRequest) -> Option<String> {
+    req.extensions()
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| *ip)
+        .or_else(|| req.peer_addr().map(|addr| addr.ip()))
+        .map(|ip| format!("ip {}", ip))
+}
+
+/// Middleware running `Authenticator::identify` ahead of logging and stats
+pub struct Authenticate(pub Arc<Authenticator>);
+
+impl<S, B> Transform<S> for Authenticate
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticateMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticateMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            authenticator: self.0.clone(),
+        })
+    }
+}
+
+pub struct AuthenticateMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    authenticator: Arc<Authenticator>,
+}
+
+impl<S, B> Service for AuthenticateMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let authenticator = self.authenticator.clone();
+
+        Box::pin(async move {
+            authenticator.identify(&mut req).await;
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+fn too_many(reason: &'static str, write: impl FnOnce(&mut HeaderMap)) -> Error {
+    let mut resp = problem(StatusCode::TOO_MANY_REQUESTS, reason);
+    write(resp.headers_mut());
+    InternalError::from_response(reason, resp).into()
+}
+
+/// Middleware rejecting requests without valid credentials, 401 if there were none
+/// and 403 for unknown ones or callers lacking the role, 429 once the caller is over
+/// its `Quotas` or locked out, 503 while signed requests cannot be checked for replays.
+/// Responses of callers with a quota, errors included, carry its `X-RateLimit-*`
+/// headers. Requests `Authenticator` did not look at, because authentication is off,
+/// pass unless they need `ops`.
+pub struct RequireAuth(pub &'static str);
+
+impl<S, B> Transform<S> for RequireAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequireAuthMiddleware {
+            service,
+            role: self.0,
+        })
+    }
+}
+
+pub struct RequireAuthMiddleware<S> {
+    service: S,
+    role: &'static str,
+}
+
+impl<S, B> Service for RequireAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<
+        Either<S::Future, LocalBoxFuture<'static, Result<Self::Response, Self::Error>>>,
+        Ready<Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        // preflights carry no credentials
+        if req.method() == Method::OPTIONS {
+            return Either::Left(Either::Left(self.service.call(req)));
+        }
+        let auth = req.extensions().get::<Auth>().cloned();
+        let record = |kind| {
+            if let Some(stats) = req.app_data::<Stats>() {
+                stats.record_error(kind);
+            }
+        };
+        let (status, detail) = match auth {
+            None if self.role != OPS => return Either::Left(Either::Left(self.service.call(req))),
+            None => (
+                StatusCode::UNAUTHORIZED,
+                format!(
+                    "Authentication is off, {} needs {}",
+                    req.path(),
+                    BOOTSTRAP_HEADER
+                ),
+            ),
+            Some(Auth::Identified(ref identity)) if !identity.has_role(self.role) => {
+                (StatusCode::FORBIDDEN, format!("Missing role {}", self.role))
+            }
+            Some(Auth::Identified(identity)) => {
+                let admitted = req
+                    .app_data::<Quotas>()
+                    .map_or(Ok(None), |q| q.admit(&identity.name, Utc::now()));
+                match admitted {
+                    Ok(None) => return Either::Left(Either::Left(self.service.call(req))),
+                    Ok(Some(budget)) => {
+                        let fut = self.service.call(req);
+                        return Either::Left(Either::Right(Box::pin(async move {
+                            budget.apply(fut.await)
+                        })));
+                    }
+                    Err(Exceeded { reason, budget }) => {
+                        return Either::Right(err(too_many(reason, |h| budget.write(h))))
+                    }
+                }
+            }
+            Some(Auth::LockedOut(secs)) => {
+                record("auth_locked_out");
+                return Either::Right(err(too_many("Too many failed authentications", |h| {
+                    h.insert(header::RETRY_AFTER, secs.into());
+                })));
+            }
+            Some(Auth::Unavailable(secs)) => {
+                let detail = "Too many signed requests, try again later";
+                let mut resp = problem(StatusCode::SERVICE_UNAVAILABLE, detail);
+                resp.headers_mut().insert(header::RETRY_AFTER, secs.into());
+                return Either::Right(err(InternalError::from_response(detail, resp).into()));
+            }
+            Some(Auth::Missing) => (StatusCode::UNAUTHORIZED, "Missing credentials".into()),
+            Some(Auth::Invalid(reason)) => {
+                record("auth_failed");
+                (StatusCode::FORBIDDEN, reason.into())
+            }
+        };
+        let resp = problem(status, detail.clone());
+        Either::Right(err(InternalError::from_response(detail, resp).into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn identifies_known_keys() {
+        let auth = Authenticator::new(
+            &[ApiKey {
+                name: "partner".into(),
+                key: "s3cr3t".into(),
+                roles: default_roles(),
+            }],
+            web::Data::new(KeyStore::load(None).unwrap()),
+            web::Data::new(Sessions::new(Duration::from_secs(60))),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        assert_eq!(auth.check(None), Auth::Missing);
+        assert_eq!(auth.check(Some("guess")), Auth::Invalid("Unknown API key"));
+        assert_eq!(
+            auth.check(Some("s3cr3t")),
+            Auth::Identified(Identity {
+                name: "partner".into(),
+                roles: vec![COMPUTE.into()],
+            })
+        );
+
+        let mut req = TestRequest::with_header(API_KEY_HEADER, "s3cr3t")
+            .header(IDENTITY_HEADER, "admin")
+            .to_srv_request();
+        auth.identify(&mut req).await;
+        assert_eq!(req.headers()[IDENTITY_HEADER], "partner");
+    }
+
+    #[actix_rt::test]
+    async fn bootstrap_secret_grants_ops() {
+        let auth = Authenticator::new(
+            &[],
+            web::Data::new(KeyStore::load(None).unwrap()),
+            web::Data::new(Sessions::new(Duration::from_secs(60))),
+            None,
+            None,
+            None,
+            None,
+        )
+        .with_bootstrap(Some("b00t"));
+
+        let mut req = TestRequest::default().to_srv_request();
+        auth.identify(&mut req).await;
+        assert!(req.extensions().get::<Auth>().is_none());
+
+        let mut req = TestRequest::with_header(BOOTSTRAP_HEADER, "guess").to_srv_request();
+        auth.identify(&mut req).await;
+        assert_eq!(
+            req.extensions().get::<Auth>(),
+            Some(&Auth::Invalid("Wrong bootstrap secret"))
+        );
+
+        let mut req = TestRequest::with_header(BOOTSTRAP_HEADER, "b00t").to_srv_request();
+        auth.identify(&mut req).await;
+        assert_eq!(req.headers()[IDENTITY_HEADER], BOOTSTRAP);
+    }
+
+    #[test]
+    fn case_scopes_limit_cases() {
+        let identity = |roles: &[&str]| Identity {
+            name: "partner".into(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+        };
+
+        assert!(identity(&[COMPUTE]).may_compute(&Case::C2));
+        assert!(identity(&[COMPUTE, "case:C1"]).may_compute(&Case::C1));
+        assert!(!identity(&[COMPUTE, "case:C1"]).may_compute(&Case::C2));
+    }
+}
@@ -0,0 +1,176 @@
+//! After `webhook_breaker.failures` failed deliveries in a row, runs for that webhook URL
+//! are dead-lettered for `open_secs` seconds, then a single probe decides whether
+//! deliveries resume. `GET /admin/stats/webhooks` shows the circuit state and delivery
+//! counts per webhook URL.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+
+/// When webhook targets are given up on for a while
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BreakerSettings {
+    /// Consecutive failed deliveries opening the circuit of a target
+    pub failures: u32,
+    /// Seconds an open circuit refuses deliveries before one probe is let through
+    pub open_secs: u64,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        BreakerSettings {
+            failures: 5,
+            open_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Closed,
+    Open,
+    /// One probe is in flight, its outcome closes or reopens the circuit
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Target {
+    pub state: State,
+    pub consecutive_failures: u32,
+    pub delivered: u64,
+    pub failed: u64,
+    /// Deliveries refused while the circuit was open
+    pub short_circuited: u64,
+    #[serde(skip)]
+    opened: Option<Instant>,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target {
+            state: State::Closed,
+            consecutive_failures: 0,
+            delivered: 0,
+            failed: 0,
+            short_circuited: 0,
+            opened: None,
+        }
+    }
+}
+
+/// Circuit breakers of the webhook targets, shared between workers and the runner.
+/// Circuits are kept per full URL so one tenant's failing endpoint never short-circuits
+/// another tenant's hook on the same host.
+pub struct Breakers {
+    settings: BreakerSettings,
+    targets: Mutex<HashMap<String, Target>>,
+}
+
+impl Breakers {
+    pub fn new(settings: BreakerSettings) -> Self {
+        Breakers {
+            settings,
+            targets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a delivery to `url` may go out now. An open circuit lets a single probe
+    /// through once `open_secs` passed.
+    pub fn allow(&self, url: &str, now: Instant) -> bool {
+        let mut targets = self.targets.lock().unwrap();
+        let target = targets.entry(url.to_string()).or_default();
+        let reopen = Duration::from_secs(self.settings.open_secs);
+        match target.state {
+            State::Closed => true,
+            State::Open
+                if target
+                    .opened
+                    .map_or(true, |at| now.duration_since(at) >= reopen) =>
+            {
+                target.state = State::HalfOpen;
+                true
+            }
+            State::Open | State::HalfOpen => {
+                target.short_circuited += 1;
+                false
+            }
+        }
+    }
+
+    /// Records the outcome of a delivery `allow` let through
+    pub fn record(&self, url: &str, delivered: bool, now: Instant) {
+        let mut targets = self.targets.lock().unwrap();
+        let target = targets.entry(url.to_string()).or_default();
+        if delivered {
+            target.delivered += 1;
+            target.consecutive_failures = 0;
+            target.state = State::Closed;
+            target.opened = None;
+            return;
+        }
+        target.failed += 1;
+        target.consecutive_failures += 1;
+        if target.state == State::HalfOpen || target.consecutive_failures >= self.settings.failures
+        {
+            target.state = State::Open;
+            target.opened = Some(now);
+        }
+    }
+
+    pub fn targets(&self) -> BTreeMap<String, Target> {
+        let targets = self.targets.lock().unwrap();
+        targets
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+/// Circuit state and delivery counts per webhook target
+pub async fn list(breakers: web::Data<Breakers>) -> HttpResponse {
+    HttpResponse::Ok().json(breakers.targets())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_and_probes() {
+        let breakers = Breakers::new(BreakerSettings {
+            failures: 2,
+            open_secs: 10,
+        });
+        let url = "http://hooks.example.com/run";
+        let now = Instant::now();
+
+        assert!(breakers.allow(url, now));
+        breakers.record(url, false, now);
+        assert!(breakers.allow(url, now));
+        breakers.record(url, false, now);
+        assert!(!breakers.allow(url, now));
+        // same host, other path
+        assert!(breakers.allow("http://hooks.example.com/other", now));
+
+        let later = now + Duration::from_secs(10);
+        assert!(breakers.allow(url, later));
+        assert!(!breakers.allow(url, later));
+        breakers.record(url, false, later);
+        assert!(!breakers.allow(url, later));
+
+        let probe = later + Duration::from_secs(10);
+        assert!(breakers.allow(url, probe));
+        breakers.record(url, true, probe);
+        assert!(breakers.allow(url, probe));
+
+        let target = &breakers.targets()[url];
+        assert_eq!(target.state, State::Closed);
+        assert_eq!((target.delivered, target.failed), (1, 3));
+        assert_eq!(target.short_circuited, 3);
+    }
+}
@@ -0,0 +1,38 @@
+use actix_web::dev::{Body, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue, StatusCode};
+use actix_web::HttpResponse;
+use bytes::Bytes;
+
+/// Snapshot of a plain-body response that can be served again
+#[derive(Clone)]
+pub struct CapturedResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+}
+
+impl CapturedResponse {
+    /// `None` for streamed or empty bodies
+    pub fn capture(res: &ServiceResponse<Body>) -> Option<Self> {
+        match res.response().body().as_ref() {
+            Some(Body::Bytes(body)) => Some(CapturedResponse {
+                status: res.status(),
+                headers: res
+                    .headers()
+                    .iter()
+                    .map(|(n, v)| (n.clone(), v.clone()))
+                    .collect(),
+                body: body.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status).body(self.body.clone());
+        for (name, value) in &self.headers {
+            resp.headers_mut().insert(name.clone(), value.clone());
+        }
+        resp
+    }
+}
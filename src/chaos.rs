@@ -0,0 +1,177 @@
+//! `[chaos]` injects faults on requests under its `paths` (`/v1` by default) to test client
+//! retries: `latency_ratio` of them are delayed by `latency_ms`, `error_ratio` answered 500
+//! and `reset_ratio` get their connection dropped mid-response. Off unless configured.
+
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{ErrorInternalServerError, InternalError};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpResponse};
+use bytes::Bytes;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::stream;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::json_error;
+
+/// Faults injected into a share of requests, to test how clients cope
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChaosSettings {
+    /// Path prefixes faults are injected on
+    pub paths: Vec<String>,
+    /// Share of requests delayed by `latency_ms` before they are handled, from 0 to 1
+    pub latency_ratio: f64,
+    pub latency_ms: u64,
+    /// Share of requests answered 500 instead of being handled
+    pub error_ratio: f64,
+    /// Share of requests whose connection is dropped instead of being answered
+    pub reset_ratio: f64,
+}
+
+impl Default for ChaosSettings {
+    fn default() -> Self {
+        ChaosSettings {
+            paths: vec!["/v1".into()],
+            latency_ratio: 0.0,
+            latency_ms: 0,
+            error_ratio: 0.0,
+            reset_ratio: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fault {
+    Error,
+    Reset,
+}
+
+impl ChaosSettings {
+    fn applies(&self, path: &str) -> bool {
+        self.paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    /// Fault for a request that rolled `roll` in `[0, 1)`, resets taking the lowest rolls
+    fn fault(&self, roll: f64) -> Option<Fault> {
+        if roll < self.reset_ratio {
+            Some(Fault::Reset)
+        } else if roll < self.reset_ratio + self.error_ratio {
+            Some(Fault::Error)
+        } else {
+            None
+        }
+    }
+}
+
+/// Error whose response fails while its body is written, so the connection is closed
+/// without a complete answer
+fn reset() -> Error {
+    let body = stream::once(futures::future::err::<Bytes, Error>(
+        ErrorInternalServerError("injected connection reset"),
+    ));
+    InternalError::from_response("injected reset", HttpResponse::Ok().streaming(body)).into()
+}
+
+fn injected_error() -> Error {
+    let resp = json_error(StatusCode::INTERNAL_SERVER_ERROR, "Injected fault");
+    InternalError::from_response("injected fault", resp).into()
+}
+
+/// Middleware injecting the faults of `ChaosSettings`
+pub struct Chaos(pub ChaosSettings);
+
+impl<S, B> Transform<S> for Chaos
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ChaosMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ChaosMiddleware {
+            service,
+            settings: self.0.clone(),
+        })
+    }
+}
+
+pub struct ChaosMiddleware<S> {
+    service: S,
+    settings: ChaosSettings,
+}
+
+impl<S, B> Service for ChaosMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if !self.settings.applies(req.path()) {
+            return Box::pin(self.service.call(req));
+        }
+        let delay = if rand::random::<f64>() < self.settings.latency_ratio {
+            Duration::from_millis(self.settings.latency_ms)
+        } else {
+            Duration::from_millis(0)
+        };
+        let fault = self.settings.fault(rand::random());
+        // requests getting a fault never reach the handler
+        let fut = match fault {
+            None => Some(self.service.call(req)),
+            Some(_) => None,
+        };
+
+        Box::pin(async move {
+            if delay > Duration::from_millis(0) {
+                actix_rt::time::delay_for(delay).await;
+            }
+            match fut {
+                Some(fut) => fut.await,
+                None if fault == Some(Fault::Reset) => Err(reset()),
+                None => Err(injected_error()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_faults_by_share() {
+        let settings = ChaosSettings {
+            error_ratio: 0.2,
+            reset_ratio: 0.1,
+            ..ChaosSettings::default()
+        };
+
+        assert!(settings.applies("/v1/compute"));
+        assert!(!settings.applies("/health"));
+        assert_eq!(settings.fault(0.05), Some(Fault::Reset));
+        assert_eq!(settings.fault(0.25), Some(Fault::Error));
+        assert_eq!(settings.fault(0.5), None);
+    }
+}
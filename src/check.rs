@@ -0,0 +1,154 @@
+//! `--check-config` validates the settings, templates and built-in test vectors, prints a
+//! summary and exits non-zero on failure, without starting the server. The same checks run
+//! on every start, a failing one is logged and the server exits before binding.
+
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+
+use log::{error, info};
+use tracing_subscriber::EnvFilter;
+
+use crate::access::AccessControl;
+use crate::config::Settings;
+use crate::jwe::Decrypter;
+use crate::jws::ResponseSigner;
+use crate::proxy::TrustedProxies;
+use crate::selftest;
+use crate::telemetry;
+use crate::templates::ResponseTemplates;
+use crate::tls::{self, CertStore};
+
+/// Named outcome of one verification
+pub type Check = (String, Result<(), String>);
+
+/// Validates `settings` and runs the built-in test vectors against the engine
+pub fn verify(settings: &Settings) -> Vec<Check> {
+    let addrs = std::iter::once(&settings.bind)
+        .chain(&settings.binds)
+        .chain(&settings.admin_bind);
+    let mut checks: Vec<Check> = addrs
+        .map(|addr| {
+            let resolved = addr
+                .to_socket_addrs()
+                .map(|_| ())
+                .map_err(|e| e.to_string());
+            (format!("address {}", addr), resolved)
+        })
+        .collect();
+    checks.push((
+        "log filter".into(),
+        EnvFilter::try_new(&settings.log_filter)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    ));
+    checks.push((
+        "trusted proxies".into(),
+        TrustedProxies::new(&settings.trusted_proxies)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    ));
+    checks.push((
+        "access lists".into(),
+        AccessControl::new(&settings.access)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    ));
+    checks.push((
+        "response templates".into(),
+        ResponseTemplates::new(&settings.response_templates)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    ));
+    if let Some(signing) = &settings.response_signing {
+        checks.push((
+            "response signing key".into(),
+            ResponseSigner::new(signing)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        ));
+    }
+    if let Some(encryption) = &settings.encryption {
+        checks.push((
+            "encryption key".into(),
+            Decrypter::new(encryption)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        ));
+    }
+    if let Some(otlp) = &settings.otlp {
+        checks.push(("OTLP export".into(), telemetry::check(otlp)));
+    }
+    if let Some(tls) = &settings.tls {
+        checks.push((
+            format!("TLS certificate {}", tls::describe(&tls.cert)),
+            CertStore::new(tls)
+                .and_then(|store| tls::server_config(Arc::new(store)))
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        ));
+    }
+    let report = selftest::run();
+    let failed: Vec<_> = report
+        .checks
+        .iter()
+        .filter(|c| !c.passed)
+        .map(|c| format!("{:?}/{:?}", c.case, c.h))
+        .collect();
+    checks.push((
+        format!("{} test vectors", report.checks.len()),
+        if report.passed {
+            Ok(())
+        } else {
+            Err(format!("failed {}", failed.join(", ")))
+        },
+    ));
+    checks
+}
+
+fn failed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Configuration check failed")
+}
+
+/// `--check-config`: prints the settings and every check, fails if any check does
+pub fn run(settings: &Settings) -> io::Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&settings.redacted()).unwrap_or_default()
+    );
+    let mut ok = true;
+    for (name, result) in verify(settings) {
+        match result {
+            Ok(()) => println!("ok    {}", name),
+            Err(e) => {
+                ok = false;
+                println!("FAIL  {}: {}", name, e);
+            }
+        }
+    }
+
+    if ok {
+        Ok(())
+    } else {
+        Err(failed())
+    }
+}
+
+/// Runs before binding, so a broken instance never takes traffic
+pub fn startup(settings: &Settings) -> io::Result<()> {
+    let checks = verify(settings);
+    let mut ok = true;
+    for (name, result) in &checks {
+        if let Err(e) = result {
+            ok = false;
+            error!("Startup check {} failed: {}", name, e);
+        }
+    }
+
+    if ok {
+        info!("All {} startup checks passed", checks.len());
+        Ok(())
+    } else {
+        Err(failed())
+    }
+}
@@ -0,0 +1,99 @@
+//! Command line flags, taking precedence over every other source of settings. Listen
+//! address and worker count, for example:
+//!
+//! ``` cargo run -- --bind 0.0.0.0:8080 --workers 4```
+
+use clap::{value_t, App, Arg, ArgMatches, SubCommand};
+
+use crate::config::Settings;
+
+pub fn matches() -> ArgMatches<'static> {
+    App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .env("RTP_CONFIG")
+                .value_name("FILE")
+                .help("TOML config file, flags override its values"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .env("RTP_PROFILE")
+                .value_name("NAME")
+                .help("Applies the [profiles.NAME] section of the config file, e.g. prod"),
+        )
+        .arg(
+            Arg::with_name("check_config")
+                .long("check-config")
+                .help("Validates the config and test vectors, then exits"),
+        )
+        .arg(
+            Arg::with_name("pid_file")
+                .long("pid-file")
+                .env("RTP_PID_FILE")
+                .value_name("FILE")
+                .help("Writes the process id to FILE while the server runs"),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .value_name("ADDR")
+                .help("Address to listen on, e.g. 0.0.0.0:8080"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .env("RTP_PORT")
+                .value_name("PORT")
+                .help("Port to listen on, replaces the port of the bind address"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .value_name("N")
+                .help("Number of worker threads, one per core by default"),
+        )
+        .subcommand(
+            SubCommand::with_name("print-schema")
+                .about("Prints the JSON Schemas of the compute request and response")
+                .arg(
+                    Arg::with_name("openapi")
+                        .long("openapi")
+                        .help("Prints the OpenAPI document of the API instead"),
+                ),
+        )
+        .get_matches()
+}
+
+/// Applies command line flags over `settings`, exiting on malformed values
+pub fn apply(settings: &mut Settings, matches: &ArgMatches) {
+    if let Some(bind) = matches.value_of("bind") {
+        settings.bind = bind.into();
+    }
+    if matches.is_present("port") {
+        let port = value_t!(matches, "port", u16).unwrap_or_else(|e| e.exit());
+        settings.bind = with_port(&settings.bind, port);
+    }
+    if matches.is_present("workers") {
+        let workers = value_t!(matches, "workers", usize).unwrap_or_else(|e| e.exit());
+        settings.workers = Some(workers);
+    }
+}
+
+fn with_port(bind: &str, port: u16) -> String {
+    let host = bind.rsplitn(2, ':').last().unwrap_or(bind);
+    format!("{}:{}", host, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_port() {
+        assert_eq!(with_port("127.0.0.1:3030", 8080), "127.0.0.1:8080");
+        assert_eq!(with_port("[::1]:3030", 80), "[::1]:80");
+    }
+}
@@ -0,0 +1,558 @@
+//! Any setting can be put in a TOML file, precedence is defaults < file < env < flags:
+//!
+//! ``` cargo run -- --config server.toml```
+//!
+//! `--profile prod` (or `RTP_PROFILE`) layers the file's `[profiles.prod]` section over the
+//! rest of it, so one file describes every environment.
+//!
+//! ``` cargo run -- --config server.toml --profile dev```
+//!
+//! Every setting is also read from an `RTP_` prefixed environment variable:
+//!
+//! ``` RTP_BIND=0.0.0.0:8080 RTP_JSON_LIMIT=65536 cargo run```
+//!
+//! Capacity is tuned with `workers`, `keep_alive`, `client_timeout`, `max_connections` and
+//! `max_connection_rate`, see `server.toml` for their defaults. Slow clients are cut off by
+//! `client_timeout` (request head) and `client_shutdown`. `binds` adds listeners next to
+//! `bind`, with `admin_bind` set `/admin` is served on that internal address only.
+//!
+//! Secrets stay out of the config file with `RTP_<KEY>_FILE` variables naming a file that
+//! holds the value of a secret setting, `__` separating nested ones, e.g.
+//! `RTP_INTROSPECTION__CLIENT_SECRET_FILE=/run/secrets/client_secret` or
+//! `RTP_API_KEYS_FILE` with a JSON array. Only `SECRETS` are read this way, since variables
+//! like `RTP_KEY_FILE` set file settings themselves. They are masked wherever settings are
+//! shown.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::Figment;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::access::AccessLists;
+use crate::alerts::ErrorAlertSettings;
+use crate::audit::AuditSettings;
+use crate::auth::ApiKey;
+use crate::breaker::BreakerSettings;
+use crate::chaos::ChaosSettings;
+use crate::consul::ConsulSettings;
+use crate::deprecation::{self, Deprecation};
+use crate::heartbeat::HeartbeatSettings;
+use crate::introspection::IntrospectionSettings;
+use crate::jwe::EncryptionSettings;
+use crate::jws::ResponseSigningSettings;
+use crate::jwt::JwtSettings;
+use crate::limit::JsonShape;
+use crate::lockout::LockoutSettings;
+use crate::logging::{AccessLogSettings, LogFormat};
+use crate::maintenance::MaintenanceWindow;
+use crate::mirror::MirrorSettings;
+use crate::quota::KeyLimit;
+use crate::ratelimit::RateLimitSettings;
+use crate::security::SecurityHeaderSettings;
+use crate::signature::SigningClient;
+use crate::telemetry::OtlpSettings;
+use crate::tenants::Tenant;
+use crate::tls::TlsSettings;
+use crate::types::Case;
+use crate::vault::VaultSettings;
+
+/// Runtime configuration of the server
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Address the server listens on
+    pub bind: String,
+    /// Further addresses serving the same app, e.g. `[::1]:3030` next to an IPv4 `bind`
+    pub binds: Vec<String>,
+    /// Internal address serving only `/admin`, which then is not mounted on the others
+    pub admin_bind: Option<String>,
+    /// Serve `bind` and `binds` over HTTPS, certificate files are reloaded when they change
+    pub tls: Option<TlsSettings>,
+    /// Consul agent to register the instance with, no registration if absent
+    pub consul: Option<ConsulSettings>,
+    /// Worker threads, one per core if absent
+    pub workers: Option<usize>,
+    /// Seconds in-flight requests may take to finish on SIGINT/SIGTERM
+    pub shutdown_timeout: u64,
+    /// Seconds between failing readiness and closing the listeners on SIGTERM
+    pub pre_stop_delay: u64,
+    /// Requests handled at once, unlimited if absent
+    pub max_in_flight: Option<usize>,
+    /// Token bucket per client address answering 429, no limit if absent
+    pub rate_limit: Option<RateLimitSettings>,
+    /// Requests waiting for a slot beyond `max_in_flight` before 503 is answered
+    pub max_queue: usize,
+    /// Queued requests above which `/readyz` answers 503, never if absent
+    pub ready_max_queued: Option<usize>,
+    /// Seconds an idle connection is kept open, 0 closes it after each response
+    pub keep_alive: usize,
+    /// Milliseconds a client has to send the request head
+    pub client_timeout: u64,
+    /// Milliseconds a client has to acknowledge the connection shutdown
+    pub client_shutdown: u64,
+    /// Size of request line and headers above which 431 is answered
+    pub max_header_bytes: usize,
+    /// Concurrent connections per worker
+    pub max_connections: usize,
+    /// Connections per worker that may be in TLS handshake at once
+    pub max_connection_rate: usize,
+    /// Maximum JSON payload size in bytes
+    pub json_limit: usize,
+    /// Per-route overrides of `json_limit`, keyed by path within `/v1`
+    pub json_limits: HashMap<String, usize>,
+    /// Nesting of objects and arrays above which JSON bodies are rejected
+    pub json_max_depth: usize,
+    /// Bytes of a JSON string, escapes included, above which bodies are rejected
+    pub json_max_string: usize,
+    /// Fields of a JSON object above which bodies are rejected
+    pub json_max_fields: usize,
+    /// `RUST_LOG` style filter used when the variable is not set
+    pub log_filter: String,
+    /// `text`, `json` or `logfmt` log lines
+    pub log_format: LogFormat,
+    /// Layout of access log lines and paths left out of the access log
+    pub access_log: AccessLogSettings,
+    /// Milliseconds above which a request is logged at WARN with its phases and params, off
+    /// if absent
+    pub slow_request_ms: Option<u64>,
+    /// Circuit breaking of schedule webhook targets that keep failing
+    pub webhook_breaker: BreakerSettings,
+    /// Let schedule webhooks reach loopback, private and link-local addresses
+    pub webhook_private_hosts: bool,
+    /// Schedules one identity may keep, all unauthenticated callers sharing one count
+    pub schedules_per_owner: usize,
+    /// Faults injected into a share of requests to test clients, never in production
+    pub chaos: Option<ChaosSettings>,
+    /// Shadow instance a sample of compute requests is copied to, none if absent
+    pub mirror: Option<MirrorSettings>,
+    /// Webhook notified when the error rate of computes spikes, no alerts if absent
+    pub error_alert: Option<ErrorAlertSettings>,
+    /// Collector request spans are exported to, none are if absent
+    pub otlp: Option<OtlpSettings>,
+    /// Monitoring endpoint heartbeats are pushed to, none are if absent
+    pub heartbeat: Option<HeartbeatSettings>,
+    /// Deprecated routes and rule sets
+    pub deprecations: Vec<Deprecation>,
+    /// Wrap JSON responses and errors of every endpoint in `Envelope` unless the request
+    /// says otherwise
+    pub envelope: bool,
+    /// Seconds a response is replayed for a repeated `Idempotency-Key`
+    pub idempotency_ttl: u64,
+    /// Milliseconds identical POST requests of a client are coalesced for, off if absent
+    pub dedup_window_ms: Option<u64>,
+    /// Seconds a compute result stays retrievable under `/results/{id}`
+    pub results_ttl: u64,
+    /// Seconds a playground session opened at `/session` lasts
+    pub session_ttl: u64,
+    /// Case applied to requests without one
+    pub default_case: Case,
+    /// Reject compute requests without `case` instead of applying `default_case`
+    pub require_case: bool,
+    /// Per-customer overrides of the case defaults, selected with `X-Tenant`
+    pub tenants: HashMap<String, Tenant>,
+    /// Milliseconds a request may take before it is answered with 504
+    pub request_timeout_ms: u64,
+    /// Per-route overrides of `request_timeout_ms`, keyed by path within `/v1`
+    pub request_timeouts_ms: HashMap<String, u64>,
+    /// Keys required in `X-Api-Key` by compute routes, open to everyone if empty
+    pub api_keys: Vec<ApiKey>,
+    /// File API keys issued through `/admin/keys` are kept in, hashed, lost on restart if absent
+    pub key_file: Option<String>,
+    /// Secret granting `ops` in `X-Bootstrap-Secret`, the only way into `/admin` while
+    /// authentication is off
+    pub admin_bootstrap_secret: Option<String>,
+    /// File dead-lettered batch items and webhook runs are kept in, lost on restart if absent
+    pub dead_letter_file: Option<String>,
+    /// Identity provider whose `Authorization: Bearer` tokens compute routes accept
+    pub jwt: Option<JwtSettings>,
+    /// OAuth2 introspection endpoint checking opaque bearer tokens
+    pub introspection: Option<IntrospectionSettings>,
+    /// Requests per minute and per UTC day of authenticated callers, keyed by identity
+    pub key_limits: HashMap<String, KeyLimit>,
+    /// File keeping daily usage across restarts, counted from zero if absent
+    pub usage_file: Option<String>,
+    /// File keeping usage reports across restarts, counted from zero if absent
+    pub metering_file: Option<String>,
+    /// Machine clients authenticating with an HMAC `X-Signature` over the request
+    pub signing_clients: Vec<SigningClient>,
+    /// Seconds a signature timestamp may differ from the server clock
+    pub signature_tolerance: u64,
+    /// Failed authentications locking a client address out, off if absent
+    pub lockout: Option<LockoutSettings>,
+    /// Client addresses let into `/admin` and into the other routes
+    pub access: AccessLists,
+    /// Security headers added to every response
+    pub security_headers: SecurityHeaderSettings,
+    /// Append-only log of state-changing calls, nothing is recorded if absent
+    pub audit: Option<AuditSettings>,
+    /// Params and query parameters whose values are masked in logs and audit records
+    pub redact: Vec<String>,
+    /// Vault secret whose fields override settings at startup
+    pub vault: Option<VaultSettings>,
+    /// Key compute results are signed with as JWS, unsigned if absent
+    pub response_signing: Option<ResponseSigningSettings>,
+    /// Key clients encrypt params to as JWE, plain bodies only if absent
+    pub encryption: Option<EncryptionSettings>,
+    /// CIDRs of proxies whose `Forwarded`/`X-Forwarded-For` name the client address
+    pub trusted_proxies: Vec<String>,
+    /// Planned downtime during which compute routes answer 503
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Feature flags gating experimental routes, toggled at runtime under `/admin/features`
+    pub features: BTreeMap<String, bool>,
+    /// Serve the embedded playground and API docs under `/assets`
+    pub assets: bool,
+    /// Handlebars templates for compute results, keyed by authenticated identity name
+    pub response_templates: HashMap<String, String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            bind: "127.0.0.1:3030".into(),
+            binds: vec![],
+            admin_bind: None,
+            tls: None,
+            consul: None,
+            workers: None,
+            shutdown_timeout: 30,
+            pre_stop_delay: 0,
+            max_in_flight: None,
+            rate_limit: None,
+            max_queue: 64,
+            ready_max_queued: None,
+            keep_alive: 5,
+            client_timeout: 5000,
+            client_shutdown: 5000,
+            max_header_bytes: 16 * 1024,
+            max_connections: 25_000,
+            max_connection_rate: 256,
+            json_limit: 4096,
+            json_limits: vec![
+                ("/compute".to_string(), 1024),
+                ("/compute/batch".to_string(), 256 * 1024),
+            ]
+            .into_iter()
+            .collect(),
+            json_max_depth: 32,
+            json_max_string: 4096,
+            json_max_fields: 64,
+            log_filter: "error".into(),
+            log_format: LogFormat::Text,
+            access_log: AccessLogSettings::default(),
+            slow_request_ms: None,
+            webhook_breaker: BreakerSettings::default(),
+            webhook_private_hosts: false,
+            schedules_per_owner: 100,
+            chaos: None,
+            mirror: None,
+            error_alert: None,
+            otlp: None,
+            heartbeat: None,
+            deprecations: deprecation::defaults(),
+            envelope: false,
+            idempotency_ttl: 24 * 60 * 60,
+            dedup_window_ms: None,
+            results_ttl: 60 * 60,
+            session_ttl: 60 * 60,
+            default_case: Case::B,
+            require_case: false,
+            tenants: HashMap::new(),
+            request_timeout_ms: 30_000,
+            request_timeouts_ms: vec![
+                ("/compute".to_string(), 2_000),
+                ("/compute/batch".to_string(), 60_000),
+            ]
+            .into_iter()
+            .collect(),
+            api_keys: vec![],
+            key_file: None,
+            admin_bootstrap_secret: None,
+            dead_letter_file: None,
+            jwt: None,
+            introspection: None,
+            key_limits: HashMap::new(),
+            usage_file: None,
+            metering_file: None,
+            signing_clients: vec![],
+            signature_tolerance: 300,
+            lockout: None,
+            access: AccessLists::default(),
+            security_headers: SecurityHeaderSettings::default(),
+            audit: None,
+            redact: vec![],
+            vault: None,
+            response_signing: None,
+            encryption: None,
+            trusted_proxies: vec![],
+            maintenance_windows: vec![],
+            features: vec![("batch".to_string(), true), ("schedules".to_string(), true)]
+                .into_iter()
+                .collect(),
+            assets: true,
+            response_templates: HashMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the optional TOML config file, its `[profiles.<profile>]` section over it and
+    /// `RTP_` prefixed environment variables over both, e.g. `RTP_JSON_LIMIT=65536`.
+    /// Keys none of them sets keep their defaults.
+    pub fn load(path: Option<&str>, profile: Option<&str>) -> Result<Self, figment::Error> {
+        let mut figment = Figment::new();
+        if let Some(path) = path {
+            if !Path::new(path).is_file() {
+                return Err(format!("No config file {}", path).into());
+            }
+            figment = figment.merge(Toml::file(path));
+            if let Some(profile) = profile {
+                let section = format!("profiles.{}", profile);
+                if figment.find_value(&section).is_err() {
+                    return Err(format!("No profile {} in {}", profile, path).into());
+                }
+                figment = figment.merge(Figment::from(Toml::file(path)).focus(&section));
+            }
+        } else if let Some(profile) = profile {
+            return Err(format!("Profile {} needs a config file", profile).into());
+        }
+        figment
+            .merge(Env::prefixed("RTP_"))
+            .merge(secret_files()?)
+            .extract()
+    }
+
+    /// Settings with `secrets`, keyed by dotted paths like `introspection.client_secret`,
+    /// put over them
+    pub fn overlay(
+        &self,
+        secrets: &serde_json::Map<String, Value>,
+    ) -> Result<Self, figment::Error> {
+        let mut figment = Figment::from(Serialized::defaults(self));
+        for (key, value) in secrets {
+            let value = match value {
+                Value::String(s) => secret_value(s)?,
+                value => value.clone(),
+            };
+            figment = figment.merge(Serialized::default(key, value));
+        }
+        figment.extract()
+    }
+
+    /// JSON payload limit of the route at `path`
+    pub fn json_limit_of(&self, path: &str) -> usize {
+        self.json_limits
+            .get(path)
+            .copied()
+            .unwrap_or(self.json_limit)
+    }
+
+    /// Structure limits of JSON bodies
+    pub fn json_shape(&self) -> JsonShape {
+        JsonShape {
+            max_depth: self.json_max_depth,
+            max_string: self.json_max_string,
+            max_fields: self.json_max_fields,
+        }
+    }
+
+    /// Request timeout of the route at `path`
+    pub fn timeout_of(&self, path: &str) -> Duration {
+        let ms = self
+            .request_timeouts_ms
+            .get(path)
+            .copied()
+            .unwrap_or(self.request_timeout_ms);
+        Duration::from_millis(ms)
+    }
+
+    /// JSON form of the settings with secret values masked
+    pub fn redacted(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or(Value::Null);
+        mask(&mut value, "");
+        value
+    }
+}
+
+/// A secret as stored, JSON if it is an array or object like `api_keys`, text otherwise
+fn secret_value(secret: &str) -> Result<Value, figment::Error> {
+    let secret = secret.trim();
+    if secret.starts_with('[') || secret.starts_with('{') {
+        serde_json::from_str(secret).map_err(|e| e.to_string().into())
+    } else {
+        Ok(Value::String(secret.into()))
+    }
+}
+
+/// Contents of the files named by `RTP_<KEY>_FILE` variables of secret keys, with `__`
+/// separating nested keys, e.g. `RTP_INTROSPECTION__CLIENT_SECRET_FILE`. Only `SECRETS`
+/// are read this way, others such as `RTP_KEY_FILE` or `RTP_USAGE_FILE` are settings of
+/// their own naming a file.
+fn secret_files() -> Result<Figment, figment::Error> {
+    let mut figment = Figment::new();
+    for (var, path) in env::vars_os() {
+        let var = var.to_string_lossy();
+        let key = match var
+            .strip_prefix("RTP_")
+            .and_then(|v| v.strip_suffix("_FILE"))
+        {
+            Some(key) => key.to_lowercase().replace("__", "."),
+            None => continue,
+        };
+        if !is_secret(&key) {
+            continue;
+        }
+        let secret = fs::read_to_string(&path).map_err(|e| {
+            format!(
+                "Could not read {} of {}: {}",
+                path.to_string_lossy(),
+                var,
+                e
+            )
+        })?;
+        figment = figment.merge(Serialized::default(&key, secret_value(&secret)?));
+    }
+    Ok(figment)
+}
+
+/// Dotted paths of the settings holding secrets, list items share the path of their list
+const SECRETS: &[&str] = &[
+    "api_keys",
+    "admin_bootstrap_secret",
+    "signing_clients.secret",
+    "introspection.client_secret",
+    "vault.token",
+    "tls.key",
+    "response_signing.key",
+    "encryption.key",
+];
+
+/// Settings masked when shown and settable from `RTP_<KEY>_FILE`
+fn is_secret(path: &str) -> bool {
+    SECRETS.contains(&path)
+}
+
+/// Whether the top-level setting `key` is a secret or has one among its fields
+pub fn holds_secret(key: &str) -> bool {
+    SECRETS
+        .iter()
+        .any(|s| *s == key || s.strip_prefix(key).map_or(false, |s| s.starts_with('.')))
+}
+
+fn mask(value: &mut Value, path: &str) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                let path = if path.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", path, k)
+                };
+                if is_secret(&path) && !v.is_null() {
+                    *v = Value::String("***".into());
+                } else {
+                    mask(v, &path);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| mask(v, path)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_overrides_file() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                "server.toml",
+                r#"
+                bind = "0.0.0.0:8080"
+                json_limit = 8192
+
+                [response_templates]
+                Legacy = "{{k}}"
+                "#,
+            )?;
+            jail.set_env("RTP_JSON_LIMIT", 65536);
+            let settings = Settings::load(Some("server.toml"), None)?;
+
+            assert_eq!(settings.bind, "0.0.0.0:8080");
+            assert_eq!(settings.json_limit, 65536);
+            assert_eq!(settings.log_filter, "error");
+            assert_eq!(settings.response_templates["Legacy"], "{{k}}");
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn profile_overlays_base() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                "server.toml",
+                r#"
+                bind = "0.0.0.0:8080"
+                log_filter = "info"
+
+                [profiles.dev]
+                log_filter = "debug"
+                "#,
+            )?;
+            let dev = Settings::load(Some("server.toml"), Some("dev"))?;
+            let base = Settings::load(Some("server.toml"), None)?;
+
+            assert_eq!(dev.bind, "0.0.0.0:8080");
+            assert_eq!(dev.log_filter, "debug");
+            assert_eq!(base.log_filter, "info");
+            assert!(Settings::load(Some("server.toml"), Some("prod")).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn reads_secret_files() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file("keys.json", r#"[{"name": "partner", "key": "s3cr3t"}]"#)?;
+            jail.create_file("usage.json", "{}")?;
+            jail.set_env("RTP_API_KEYS_FILE", "keys.json");
+            jail.set_env("RTP_USAGE_FILE", "usage.json");
+            jail.set_env("RTP_KEY_FILE", "managed.json");
+            let settings = Settings::load(None, None)?;
+
+            assert_eq!(settings.api_keys[0].key, "s3cr3t");
+            assert_eq!(settings.usage_file.as_deref(), Some("usage.json"));
+            assert_eq!(settings.key_file.as_deref(), Some("managed.json"));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn masks_secrets() {
+        let mut value = serde_json::json!({
+            "bind": "0.0.0.0:80",
+            "api_keys": ["a", "b"],
+            "key_file": "keys.json",
+            "key_limits": {"partner": {"per_minute": 10}},
+            "signing_clients": [{"name": "batch", "secret": "s"}],
+            "introspection": {"client_id": "rtp", "client_secret": "s"}
+        });
+        mask(&mut value, "");
+
+        assert_eq!(value["bind"], "0.0.0.0:80");
+        assert_eq!(value["api_keys"], "***");
+        assert_eq!(value["key_file"], "keys.json");
+        assert_eq!(value["key_limits"]["partner"]["per_minute"], 10);
+        assert_eq!(value["signing_clients"][0]["name"], "batch");
+        assert_eq!(value["signing_clients"][0]["secret"], "***");
+        assert_eq!(value["introspection"]["client_id"], "rtp");
+        assert_eq!(value["introspection"]["client_secret"], "***");
+    }
+}
@@ -0,0 +1,136 @@
+//! With `[consul]` set the instance registers with the local Consul agent once listening,
+//! tagged with its version and health-checked on `/readyz`, and deregisters as soon as
+//! SIGINT/SIGTERM arrives, before `pre_stop_delay`.
+
+use actix_web::client::Client;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Consul agent the instance registers with while it serves
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConsulSettings {
+    /// Base URL of the local agent
+    pub agent: String,
+    /// Service name consumers look the instance up by
+    pub service: String,
+    /// `host:port` other nodes reach the instance at, `bind` if absent
+    pub advertise: Option<String>,
+    /// Tags next to the `version=` one added for every instance
+    pub tags: Vec<String>,
+    /// How often Consul polls `/readyz`, e.g. `10s`
+    pub check_interval: String,
+}
+
+impl Default for ConsulSettings {
+    fn default() -> Self {
+        ConsulSettings {
+            agent: "http://127.0.0.1:8500".into(),
+            service: "rest-test-params".into(),
+            advertise: None,
+            tags: vec![],
+            check_interval: "10s".into(),
+        }
+    }
+}
+
+/// Registration of this instance, deregistered again on shutdown
+pub struct Registration {
+    agent: String,
+    id: String,
+}
+
+fn split(addr: &str) -> Option<(&str, u16)> {
+    let at = addr.rfind(':')?;
+    let port = addr[at + 1..].parse().ok()?;
+    Some((addr[..at].trim_matches(|c| c == '[' || c == ']'), port))
+}
+
+fn payload(
+    settings: &ConsulSettings,
+    id: &str,
+    addr: &str,
+    https: bool,
+) -> Option<serde_json::Value> {
+    let (host, port) = split(addr)?;
+    let mut tags = settings.tags.clone();
+    tags.push(format!("version={}", env!("CARGO_PKG_VERSION")));
+    let scheme = if https { "https" } else { "http" };
+    Some(json!({
+        "ID": id,
+        "Name": settings.service,
+        "Address": host,
+        "Port": port,
+        "Tags": tags,
+        "Check": {
+            "HTTP": format!("{}://{}/readyz", scheme, addr),
+            "Interval": settings.check_interval,
+            "TLSSkipVerify": https,
+            "DeregisterCriticalServiceAfter": "1m",
+        },
+    }))
+}
+
+/// Registers the instance reachable at `advertise` (or `bind`). Failures are logged,
+/// the server keeps running undiscovered rather than not at all.
+pub async fn register(settings: &ConsulSettings, bind: &str, https: bool) -> Option<Registration> {
+    let addr = settings.advertise.as_deref().unwrap_or(bind);
+    let id = format!("{}-{}", settings.service, addr);
+    let body = match payload(settings, &id, addr, https) {
+        Some(body) => body,
+        None => {
+            warn!("Cannot register {} with Consul, expected host:port", addr);
+            return None;
+        }
+    };
+    let url = format!("{}/v1/agent/service/register", settings.agent);
+    match Client::new().put(url.as_str()).send_json(&body).await {
+        Ok(resp) if resp.status().is_success() => {
+            info!("Registered {} with Consul at {}", id, settings.agent);
+            Some(Registration {
+                agent: settings.agent.clone(),
+                id,
+            })
+        }
+        Ok(resp) => {
+            warn!("Consul answered {} registering {}", resp.status(), id);
+            None
+        }
+        Err(e) => {
+            warn!("Could not register {} with Consul: {:?}", id, e);
+            None
+        }
+    }
+}
+
+impl Registration {
+    pub async fn deregister(self) {
+        let url = format!("{}/v1/agent/service/deregister/{}", self.agent, self.id);
+        match Client::new().put(url.as_str()).send().await {
+            Ok(resp) if resp.status().is_success() => info!("Deregistered {} from Consul", self.id),
+            Ok(resp) => warn!(
+                "Consul answered {} deregistering {}",
+                resp.status(),
+                self.id
+            ),
+            Err(e) => warn!("Could not deregister {} from Consul: {:?}", self.id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registration_payload() {
+        let settings = ConsulSettings::default();
+        let body = payload(&settings, "rtp-1", "[::1]:3030", false).unwrap();
+
+        assert_eq!(body["Address"], "::1");
+        assert_eq!(body["Port"], 3030);
+        assert_eq!(body["Check"]["HTTP"], "http://[::1]:3030/readyz");
+        assert!(payload(&settings, "rtp-1", "localhost", false).is_none());
+    }
+}
@@ -0,0 +1,149 @@
+//! `/admin/dashboard` is a page showing the last compute requests, their error rate,
+//! computes per second and case, and the version of the built-in rules, bumped whenever a
+//! case computes differently. It follows `GET /admin/stats/stream`, server-sent events
+//! carrying those figures every two seconds.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::stream;
+use serde_derive::Serialize;
+
+use crate::assets;
+use crate::stats::{Recent, Stats, Summary};
+use crate::types::RULES_VERSION;
+
+/// Seconds between two events of `/admin/stats/stream`
+const INTERVAL: u64 = 2;
+
+/// What the dashboard shows, sent as one server-sent event
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub version: &'static str,
+    pub rules_version: &'static str,
+    pub uptime_secs: u64,
+    pub requests: u64,
+    /// Failed share of the payloads of the recent requests
+    pub error_rate: f64,
+    /// Computes per second and case since the previous event, empty in the first one
+    pub throughput_by_case: BTreeMap<String, f64>,
+    pub recent: Vec<Recent>,
+}
+
+fn snapshot(
+    summary: Summary,
+    recent: Vec<Recent>,
+    previous: Option<(&BTreeMap<String, u64>, Duration)>,
+) -> Snapshot {
+    let items: usize = recent.iter().map(|r| r.items).sum();
+    let failed: usize = recent.iter().map(|r| r.failed).sum();
+    let throughput_by_case = match previous {
+        Some((before, elapsed)) if elapsed.as_secs_f64() > 0.0 => summary
+            .by_case
+            .iter()
+            .map(|(case, count)| {
+                let delta = count.saturating_sub(before.get(case).copied().unwrap_or(0));
+                (case.clone(), delta as f64 / elapsed.as_secs_f64())
+            })
+            .collect(),
+        _ => BTreeMap::new(),
+    };
+    Snapshot {
+        version: env!("CARGO_PKG_VERSION"),
+        rules_version: RULES_VERSION,
+        uptime_secs: summary.uptime_secs,
+        requests: summary.requests,
+        error_rate: if items == 0 {
+            0.0
+        } else {
+            failed as f64 / items as f64
+        },
+        throughput_by_case,
+        recent,
+    }
+}
+
+/// HTML page following `/admin/stats/stream`
+pub async fn page(req: HttpRequest) -> HttpResponse {
+    assets::serve("dashboard.html", &req)
+}
+
+/// `dashboard.js` or `dashboard.css`, served next to the page as the CSP allows no
+/// inline script or style
+pub async fn file(name: web::Path<String>, req: HttpRequest) -> HttpResponse {
+    assets::serve(&name, &req)
+}
+
+/// Server-sent events with a `Snapshot` every `INTERVAL` seconds, the first one right away
+pub async fn stream(stats: web::Data<Stats>) -> HttpResponse {
+    let tick = actix_rt::time::interval(Duration::from_secs(INTERVAL));
+    // counts per case of the previous event and when it was sent
+    let previous: Option<(BTreeMap<String, u64>, Instant)> = None;
+    let events = stream::unfold(
+        (stats, previous, tick),
+        |(stats, previous, mut tick)| async move {
+            tick.tick().await;
+            let summary = stats.summary();
+            let by_case = summary.by_case.clone();
+            let now = Instant::now();
+            let since = previous
+                .as_ref()
+                .map(|(before, at)| (before, now.duration_since(*at)));
+            let snapshot = snapshot(summary, stats.recent(), since);
+            let event = format!(
+                "data: {}\n\n",
+                serde_json::to_string(&snapshot).unwrap_or_default()
+            );
+            Some((
+                Ok::<_, Error>(Bytes::from(event)),
+                (stats, Some((by_case, now)), tick),
+            ))
+        },
+    );
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .streaming(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::Outcome;
+    use crate::types::{Case, H};
+
+    #[test]
+    fn rates_since_previous_snapshot() {
+        let stats = Stats::default();
+        let outcome = |error| Outcome {
+            case: Case::B,
+            h: H::M,
+            error,
+            compute: Duration::from_millis(1),
+        };
+        stats.record(&[outcome(None)], None, None, Duration::from_millis(1));
+        let before = stats.summary().by_case;
+        stats.record(
+            &[outcome(None), outcome(Some("missing_params"))],
+            None,
+            None,
+            Duration::from_millis(1),
+        );
+        stats.record(&[outcome(None)], None, None, Duration::from_millis(1));
+
+        let first = snapshot(stats.summary(), stats.recent(), None);
+        assert!(first.throughput_by_case.is_empty());
+        assert!((first.error_rate - 0.25).abs() < 1e-9);
+        assert_eq!(first.recent[0].items, 1);
+
+        let next = snapshot(
+            stats.summary(),
+            stats.recent(),
+            Some((&before, Duration::from_secs(2))),
+        );
+        assert!((next.throughput_by_case["B"] - 1.5).abs() < 1e-9);
+    }
+}
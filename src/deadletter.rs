@@ -0,0 +1,231 @@
+//! Schedule runs their webhook did not take and batch items the server failed to compute
+//! (`500` with kind `internal`, such as a K beyond the range of `f64`) are kept in a
+//! dead-letter queue of up to 10000 entries, saved to `dead_letter_file` every minute and
+//! at shutdown if set. Items rejected for their params are not kept, stored params have the
+//! `redact` fields masked. `GET /admin/dead-letters` lists them with the error of their
+//! last attempt, `POST /admin/dead-letters/{id}/retry` delivers or computes one again
+//! (removing it once that succeeds) and `DELETE /admin/dead-letters/{id}` discards it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{error, web, Error, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::breaker::Breakers;
+use crate::config::Settings;
+use crate::schedules;
+use crate::types::{Case, Params};
+
+/// Entries kept, new failures are only logged beyond, so a flood cannot push out the
+/// entries already waiting for a retry
+const MAX_ENTRIES: usize = 10_000;
+
+/// Seconds between writes of the dead letter file
+const PERSIST_INTERVAL: u64 = 60;
+
+/// Work that failed and can be retried
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Work {
+    /// Schedule run its webhook did not take
+    Webhook { url: String, run: Value },
+    /// Batch item the server failed to compute, with the case applied to it. `params`
+    /// are stored with the `redact` fields masked, such items cannot be computed again.
+    BatchItem { params: Value, default_case: Case },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub work: Work,
+    /// Why the last attempt failed
+    pub error: String,
+    pub attempts: u32,
+}
+
+/// Batch items failed by the server and undeliverable webhook runs, kept in `file`
+/// across restarts
+pub struct DeadLetters {
+    file: Option<String>,
+    entries: Mutex<BTreeMap<u64, DeadLetter>>,
+}
+
+impl DeadLetters {
+    /// Starts from the entries saved in `file`, if any
+    pub fn load(file: Option<String>) -> Self {
+        let entries: Vec<DeadLetter> = file
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        if !entries.is_empty() {
+            info!("Loaded {} dead letters", entries.len());
+        }
+        DeadLetters {
+            file,
+            entries: Mutex::new(entries.into_iter().map(|e| (e.id, e)).collect()),
+        }
+    }
+
+    /// Keeps `work` for a retry, `None` once `MAX_ENTRIES` are waiting
+    pub fn push(&self, work: Work, error: String) -> Option<u64> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            warn!("Dead letters full, dropping failed work: {}", error);
+            return None;
+        }
+        let id = entries.keys().next_back().map_or(1, |last| last + 1);
+        entries.insert(
+            id,
+            DeadLetter {
+                id,
+                at: Utc::now(),
+                work,
+                error,
+                attempts: 1,
+            },
+        );
+        Some(id)
+    }
+
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<DeadLetter> {
+        self.entries.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Counts another failed attempt of `id`
+    fn failed_again(&self, id: u64, error: String) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.attempts += 1;
+            entry.error = error;
+        }
+    }
+
+    pub fn discard(&self, id: u64) -> bool {
+        self.entries.lock().unwrap().remove(&id).is_some()
+    }
+
+    /// Writes the entries to `file`, periodically and at shutdown
+    pub fn save(&self) {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return,
+        };
+        let entries = self.list();
+        let json = serde_json::to_vec_pretty(&entries).unwrap_or_default();
+        if let Err(e) = fs::write(path, json) {
+            warn!("Could not save dead letters to {}: {:?}", path, e);
+        }
+    }
+}
+
+/// Saves the entries every `PERSIST_INTERVAL` seconds, so a crash loses at most that much
+pub fn spawn_persist(dead_letters: web::Data<DeadLetters>) {
+    if dead_letters.file.is_none() {
+        return;
+    }
+    actix_rt::spawn(async move {
+        let mut tick = actix_rt::time::interval(Duration::from_secs(PERSIST_INTERVAL));
+        loop {
+            tick.tick().await;
+            dead_letters.save();
+        }
+    });
+    info!("Persisting dead letters every {}s", PERSIST_INTERVAL);
+}
+
+pub async fn list(dead_letters: web::Data<DeadLetters>) -> HttpResponse {
+    HttpResponse::Ok().json(dead_letters.list())
+}
+
+/// Runs the work of an entry again, removing it once it succeeds
+pub async fn retry(
+    id: web::Path<u64>,
+    dead_letters: web::Data<DeadLetters>,
+    breakers: web::Data<Breakers>,
+    settings: web::Data<Settings>,
+) -> Result<HttpResponse, Error> {
+    let entry = dead_letters
+        .get(*id)
+        .ok_or_else(|| error::ErrorNotFound(format!("No dead letter {}", id)))?;
+    let result = match &entry.work {
+        Work::Webhook { url, run } => {
+            let sent = schedules::send(url, None, run, settings.webhook_private_hosts).await;
+            breakers.record(url, sent.is_ok(), Instant::now());
+            sent.map(|_| Value::Null).map_err(error::ErrorBadGateway)
+        }
+        Work::BatchItem {
+            params,
+            default_case,
+        } => serde_json::from_value::<Params>(params.clone())
+            .map_err(|_| error::ErrorUnprocessableEntity("Params were stored redacted"))
+            .and_then(|params| {
+                crate::compute(&params, default_case)
+                    .map(|output| json!(output))
+                    .map_err(|e| error::ErrorUnprocessableEntity(e.to_string()))
+            }),
+    };
+    match result {
+        Ok(output) => {
+            dead_letters.discard(entry.id);
+            Ok(HttpResponse::Ok().json(json!({ "id": entry.id, "output": output })))
+        }
+        Err(e) => {
+            dead_letters.failed_again(entry.id, e.to_string());
+            Err(e)
+        }
+    }
+}
+
+pub async fn discard(
+    id: web::Path<u64>,
+    dead_letters: web::Data<DeadLetters>,
+) -> Result<HttpResponse, Error> {
+    if dead_letters.discard(*id) {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(error::ErrorNotFound(format!("No dead letter {}", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_and_discards_entries() {
+        let dead_letters = DeadLetters::load(None);
+        let webhook = Work::Webhook {
+            url: "http://hooks.example.com".into(),
+            run: json!({"at": "2026-01-01T00:00:00Z"}),
+        };
+        let item = Work::BatchItem {
+            params: json!({"a": true}),
+            default_case: Case::B,
+        };
+
+        assert_eq!(dead_letters.push(webhook, "timeout".into()), Some(1));
+        assert_eq!(dead_letters.push(item, "overflow".into()), Some(2));
+        dead_letters.failed_again(2, "overflow".into());
+        assert_eq!(dead_letters.get(2).unwrap().attempts, 2);
+
+        let listed = serde_json::to_value(dead_letters.list()).unwrap();
+        assert_eq!(listed[0]["kind"], "webhook");
+        assert_eq!(listed[1]["kind"], "batch_item");
+
+        assert!(dead_letters.discard(1));
+        assert!(!dead_letters.discard(1));
+        assert_eq!(dead_letters.list().len(), 1);
+    }
+}
@@ -0,0 +1,220 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, Payload, PayloadStream, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, Method};
+use actix_web::{Error, HttpMessage};
+use bytes::{Bytes, BytesMut};
+use futures::channel::oneshot;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::{stream, StreamExt};
+
+use crate::auth::Caller;
+use crate::capture::CapturedResponse;
+use crate::proxy::ClientIp;
+
+/// Bodies above this size are never coalesced
+const MAX_BODY: u64 = 64 * 1024;
+
+/// Caller, client address, path and body of a POST request
+type Key = (String, Bytes);
+
+enum Entry {
+    InFlight(Vec<oneshot::Sender<Option<CapturedResponse>>>),
+    Done(Instant, CapturedResponse),
+}
+
+enum Role {
+    Leader,
+    Follower(oneshot::Receiver<Option<CapturedResponse>>),
+    Replay(CapturedResponse),
+}
+
+/// Recent and in-flight POST requests, shared between workers
+pub struct DedupWindow {
+    window: Duration,
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+impl DedupWindow {
+    pub fn new(window: Duration) -> Self {
+        DedupWindow {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn join(&self, key: &Key) -> Role {
+        let mut entries = self.entries.lock().unwrap();
+        let window = self.window;
+        entries.retain(|_, e| match e {
+            Entry::Done(at, _) => at.elapsed() < window,
+            Entry::InFlight(_) => true,
+        });
+
+        match entries.get_mut(key) {
+            Some(Entry::Done(_, res)) => Role::Replay(res.clone()),
+            Some(Entry::InFlight(waiters)) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Role::Follower(rx)
+            }
+            None => {
+                entries.insert(key.clone(), Entry::InFlight(vec![]));
+                Role::Leader
+            }
+        }
+    }
+
+    fn finish(&self, key: Key, res: Option<CapturedResponse>) {
+        let mut entries = self.entries.lock().unwrap();
+        let waiters = match entries.remove(&key) {
+            Some(Entry::InFlight(waiters)) => waiters,
+            _ => vec![],
+        };
+        for tx in waiters {
+            let _ = tx.send(res.clone());
+        }
+        if let Some(res) = res {
+            entries.insert(key, Entry::Done(Instant::now(), res));
+        }
+    }
+}
+
+/// Releases the followers even if the leading request is dropped midway
+struct Leader {
+    window: Arc<DedupWindow>,
+    key: Option<Key>,
+}
+
+impl Leader {
+    fn finish(mut self, res: Option<CapturedResponse>) {
+        if let Some(key) = self.key.take() {
+            self.window.finish(key, res);
+        }
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.window.finish(key, None);
+        }
+    }
+}
+
+/// Middleware coalescing byte-identical POST requests of one caller and client
+/// address onto a single in-flight computation.
+/// Works on plain `Body`, so it has to be registered before `Logger` and other
+/// middlewares changing the body type.
+pub struct Dedup(pub Arc<DedupWindow>);
+
+impl<S> Transform<S> for Dedup
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = DedupMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(DedupMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            window: self.0.clone(),
+        })
+    }
+}
+
+pub struct DedupMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    window: Arc<DedupWindow>,
+}
+
+impl<S> Service for DedupMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let small = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .map_or(false, |len| len <= MAX_BODY);
+        let client = match req.extensions().get::<ClientIp>() {
+            Some(ClientIp(ip)) => Some(ip.to_string()),
+            None => req.peer_addr().map(|a| a.ip().to_string()),
+        };
+        let client = match client {
+            Some(client) if small && *req.method() == Method::POST => {
+                let caller = Caller::of(req.request());
+                format!("{} {}", caller.owner().unwrap_or("-"), client)
+            }
+            _ => return Box::pin(self.service.borrow_mut().call(req)),
+        };
+
+        let service = self.service.clone();
+        let window = self.window.clone();
+
+        Box::pin(async move {
+            let mut body = BytesMut::new();
+            let mut payload = req.take_payload();
+            while let Some(chunk) = payload.next().await {
+                body.extend_from_slice(&chunk?);
+            }
+            let body = body.freeze();
+            let replay: PayloadStream = Box::pin(stream::once(futures::future::ok::<
+                _,
+                PayloadError,
+            >(body.clone())));
+            req.set_payload(Payload::from(replay));
+
+            let key = (format!("{} {}", client, req.path()), body);
+            match window.join(&key) {
+                Role::Replay(res) => Ok(req.into_response(res.to_response())),
+                Role::Follower(rx) => match rx.await {
+                    Ok(Some(res)) => Ok(req.into_response(res.to_response())),
+                    _ => {
+                        let fut = service.borrow_mut().call(req);
+                        fut.await
+                    }
+                },
+                Role::Leader => {
+                    let leader = Leader {
+                        window,
+                        key: Some(key),
+                    };
+                    let fut = service.borrow_mut().call(req);
+                    let res = fut.await;
+                    let captured = res
+                        .as_ref()
+                        .ok()
+                        .filter(|r| !r.status().is_server_error())
+                        .and_then(CapturedResponse::capture);
+                    leader.finish(captured);
+                    res
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,158 @@
+//! Unversioned paths (`/compute`, `/help`, `/examples`) are deprecated aliases of `/v1`.
+
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::types::Case;
+
+/// A deprecated route or rule set, matched either by `path` or by `case`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Deprecation {
+    /// Deprecated path, matches itself and everything below it
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Deprecated rule set
+    #[serde(default)]
+    pub case: Option<Case>,
+    /// HTTP-date the deprecation took effect, `Deprecation: true` if absent
+    #[serde(default)]
+    pub since: Option<String>,
+    /// HTTP-date after which the route or rule set may be removed
+    #[serde(default)]
+    pub sunset: Option<String>,
+    /// Replacement, sent as `Link: <..>; rel="successor-version"`
+    #[serde(default)]
+    pub link: Option<String>,
+}
+
+impl Deprecation {
+    fn route(path: &str, link: &str) -> Self {
+        Deprecation {
+            path: Some(path.into()),
+            case: None,
+            since: None,
+            sunset: None,
+            link: Some(link.into()),
+        }
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        match &self.path {
+            Some(p) => path == p || path.starts_with(&format!("{}/", p.trim_end_matches('/'))),
+            None => false,
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let since = self.since.as_deref().unwrap_or("true");
+        if let Ok(v) = HeaderValue::from_str(since) {
+            headers.insert(header::HeaderName::from_static("deprecation"), v);
+        }
+        if let Some(Ok(v)) = self.sunset.as_deref().map(HeaderValue::from_str) {
+            headers.insert(header::HeaderName::from_static("sunset"), v);
+        }
+        if let Some(link) = &self.link {
+            let link = format!("<{}>; rel=\"successor-version\"", link);
+            if let Ok(v) = HeaderValue::from_str(&link) {
+                headers.append(header::LINK, v);
+            }
+        }
+    }
+}
+
+/// Deprecations shipped with the server: unversioned aliases of `/v1`.
+pub fn defaults() -> Vec<Deprecation> {
+    vec![
+        Deprecation::route("/compute", "/v1/compute"),
+        Deprecation::route("/help", "/v1/help"),
+        Deprecation::route("/examples", "/v1/examples"),
+    ]
+}
+
+pub fn for_case<'a>(
+    rules: &'a [Deprecation],
+    case: &'a Case,
+) -> impl Iterator<Item = &'a Deprecation> {
+    rules.iter().filter(move |r| r.case.as_ref() == Some(case))
+}
+
+/// Middleware adding `Deprecation`, `Sunset` and `Link` headers
+/// to responses of deprecated routes.
+pub struct DeprecationHeaders {
+    rules: Rc<Vec<Deprecation>>,
+}
+
+impl DeprecationHeaders {
+    pub fn new(rules: Vec<Deprecation>) -> Self {
+        DeprecationHeaders {
+            rules: Rc::new(rules.into_iter().filter(|r| r.path.is_some()).collect()),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for DeprecationHeaders
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = DeprecationHeadersMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(DeprecationHeadersMiddleware {
+            service,
+            rules: self.rules.clone(),
+        })
+    }
+}
+
+pub struct DeprecationHeadersMiddleware<S> {
+    service: S,
+    rules: Rc<Vec<Deprecation>>,
+}
+
+impl<S, B> Service for DeprecationHeadersMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let matched: Vec<Deprecation> = self
+            .rules
+            .iter()
+            .filter(|r| r.matches_path(req.path()))
+            .cloned()
+            .collect();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            for rule in &matched {
+                rule.apply(res.headers_mut());
+            }
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,250 @@
+//! With `?envelope=true`, or `envelope` on and no `?envelope=false`, JSON responses and
+//! errors of any endpoint come as `{"data": .., "error": .., "meta": {"duration_ms": ..}}`.
+//! JSON error bodies go in `error` as they are, plain ones as `{"code", "message",
+//! "kind"}`.
+
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderMap, HeaderValue, StatusCode};
+use actix_web::{web, Error};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::Settings;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EnvelopeQuery {
+    /// Overrides `Settings::envelope` for a single request
+    #[serde(default)]
+    pub envelope: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Meta {
+    pub duration_ms: f64,
+}
+
+/// Uniform response wrapper, `{"data": .., "error": .., "meta": {"duration_ms": ..}}`
+#[derive(Debug, Serialize)]
+pub struct Envelope {
+    pub data: Option<Value>,
+    pub error: Option<Value>,
+    pub meta: Meta,
+}
+
+impl Envelope {
+    /// Wraps the body of a response, `None` for successful ones that are not JSON
+    fn of(status: StatusCode, headers: &HeaderMap, bytes: &[u8], started: Instant) -> Option<Self> {
+        let (data, error) = if status.is_client_error() || status.is_server_error() {
+            (None, Some(error_of(status, headers, bytes)))
+        } else if is_json(headers) {
+            (Some(serde_json::from_slice(bytes).ok()?), None)
+        } else {
+            return None;
+        };
+        Some(Envelope {
+            data,
+            error,
+            meta: Meta {
+                duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+            },
+        })
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("application/json"))
+}
+
+/// JSON error bodies as they are, plain ones as `ErrorMessage` with the code of
+/// `X-Error-Code` as `kind`
+fn error_of(status: StatusCode, headers: &HeaderMap, bytes: &[u8]) -> Value {
+    if let Ok(error @ Value::Object(_)) = serde_json::from_slice(bytes) {
+        return error;
+    }
+    let message = match std::str::from_utf8(bytes) {
+        Ok(text) if !text.is_empty() => text,
+        _ => status.canonical_reason().unwrap_or_default(),
+    };
+    let mut error = json!({"code": status.as_u16(), "message": message});
+    if let Some(kind) = headers.get("x-error-code").and_then(|v| v.to_str().ok()) {
+        error["kind"] = kind.into();
+    }
+    error
+}
+
+/// Middleware wrapping JSON responses and errors of every endpoint in `Envelope` with
+/// `?envelope=true`, or unless the request has `?envelope=false` if `Settings::envelope`
+/// is on. Errors of the services it wraps are turned into responses here to be wrapped.
+/// Works on plain `Body`, so it has to be registered right after `JsonTransform`.
+pub struct Enveloping;
+
+impl<S> Transform<S> for Enveloping
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = EnvelopingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(EnvelopingMiddleware { service })
+    }
+}
+
+pub struct EnvelopingMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service for EnvelopingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let envelope = web::Query::<EnvelopeQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|q| q.envelope)
+            .or_else(|| req.app_data::<Settings>().map(|s| s.envelope))
+            .unwrap_or(false);
+        if !envelope {
+            return Box::pin(self.service.call(req));
+        }
+        let started = Instant::now();
+        let request = req.request().clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = match fut.await {
+                Ok(res) => res,
+                Err(e) => ServiceResponse::from_err(e, request),
+            };
+            Ok(res.map_body(|head, body| {
+                let bytes = match &body {
+                    ResponseBody::Body(Body::Bytes(bytes))
+                    | ResponseBody::Other(Body::Bytes(bytes)) => bytes.clone(),
+                    ResponseBody::Body(Body::Empty) | ResponseBody::Other(Body::Empty) => {
+                        bytes::Bytes::new()
+                    }
+                    _ => return body,
+                };
+                match Envelope::of(head.status, &head.headers, &bytes, started) {
+                    Some(envelope) => {
+                        head.headers.insert(
+                            header::CONTENT_TYPE,
+                            HeaderValue::from_static("application/json"),
+                        );
+                        let json = serde_json::to_string(&envelope).unwrap_or_default();
+                        ResponseBody::Body(Body::from(json))
+                    }
+                    None => body,
+                }
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{json_error, problem};
+    use actix_web::{test, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn wraps_responses_and_errors() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Enveloping)
+                .route(
+                    "/data",
+                    web::get().to(|| async { HttpResponse::Ok().json(json!({"k": 4.0})) }),
+                )
+                .route(
+                    "/text",
+                    web::get().to(|| async { HttpResponse::Ok().body("plain") }),
+                )
+                .route(
+                    "/denied",
+                    web::get().to(|| async {
+                        Err::<HttpResponse, Error>(
+                            actix_web::error::InternalError::from_response(
+                                "denied",
+                                problem(StatusCode::FORBIDDEN, "Missing role ops"),
+                            )
+                            .into(),
+                        )
+                    }),
+                )
+                .route(
+                    "/missing",
+                    web::get().to(|| async { json_error(StatusCode::NOT_FOUND, "Not found.") }),
+                ),
+        )
+        .await;
+        let body = |resp: ServiceResponse<Body>| match resp.response().body().as_ref() {
+            Some(Body::Bytes(bytes)) => serde_json::from_slice::<Value>(bytes).unwrap(),
+            _ => panic!("Response error"),
+        };
+
+        let resp = test::call_service(
+            &mut app,
+            test::TestRequest::with_uri("/data?envelope=true").to_request(),
+        )
+        .await;
+        let data = body(resp);
+        assert_eq!(data["data"]["k"], 4.0);
+        assert!(data["error"].is_null());
+        assert!(data["meta"]["duration_ms"].is_number());
+
+        let resp = test::call_service(
+            &mut app,
+            test::TestRequest::with_uri("/denied?envelope=true").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let denied = body(resp);
+        assert!(denied["data"].is_null());
+        assert_eq!(denied["error"]["detail"], "Missing role ops");
+
+        let resp = test::call_service(
+            &mut app,
+            test::TestRequest::with_uri("/missing?envelope=true").to_request(),
+        )
+        .await;
+        assert_eq!(body(resp)["error"]["code"], 404);
+
+        let resp = test::call_service(
+            &mut app,
+            test::TestRequest::with_uri("/text?envelope=true").to_request(),
+        )
+        .await;
+        match resp.response().body().as_ref() {
+            Some(Body::Bytes(bytes)) => assert_eq!(bytes.as_ref(), b"plain"),
+            _ => panic!("Response error"),
+        }
+
+        let resp =
+            test::call_service(&mut app, test::TestRequest::with_uri("/data").to_request()).await;
+        assert_eq!(body(resp)["k"], 4.0);
+    }
+}
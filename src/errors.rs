@@ -0,0 +1,84 @@
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpResponse};
+use futures::future::{ready, Ready};
+use serde_derive::Serialize;
+
+use crate::stats::Stats;
+use crate::types::ErrorMessage;
+
+pub fn json_error(status: StatusCode, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorMessage {
+        code: status.as_u16(),
+        message: message.into(),
+        kind: None,
+    })
+}
+
+/// RFC 7807 body, for errors whose clients expect `application/problem+json`
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+}
+
+pub fn problem(status: StatusCode, detail: impl Into<String>) -> HttpResponse {
+    let body = Problem {
+        kind: "about:blank",
+        title: status.canonical_reason().unwrap_or_default(),
+        status: status.as_u16(),
+        detail: detail.into(),
+    };
+    HttpResponse::build(status)
+        .content_type("application/problem+json")
+        .body(serde_json::to_string(&body).unwrap_or_default())
+}
+
+/// App-wide fallback for paths no resource matched
+pub async fn not_found() -> HttpResponse {
+    json_error(StatusCode::NOT_FOUND, "Resource not found.")
+}
+
+/// Resource fallback for methods the resource has no route for.
+/// `allow` is echoed in the `Allow` header, e.g. `"GET"` or `"POST"`.
+pub fn method_not_allowed(allow: &'static str) -> impl Fn() -> Ready<HttpResponse> + Clone {
+    move || {
+        let mut resp = json_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("Method not allowed, expected one of: {}", allow),
+        );
+        resp.headers_mut()
+            .insert(header::ALLOW, header::HeaderValue::from_static(allow));
+        ready(resp)
+    }
+}
+
+/// JSON extractor config rejecting bodies over `limit` bytes with a 413 naming the limit
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(move |err, req| {
+            let stats = req.app_data::<web::Data<Stats>>();
+            match err {
+                JsonPayloadError::Overflow => {
+                    if let Some(stats) = stats {
+                        stats.record_error("payload_too_large");
+                    }
+                    let resp = json_error(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        format!("Payload is larger than the limit of {} bytes", limit),
+                    );
+                    InternalError::from_response(err, resp).into()
+                }
+                err => {
+                    if let Some(stats) = stats {
+                        stats.record_error("invalid_json");
+                    }
+                    err.into()
+                }
+            }
+        })
+}
@@ -0,0 +1,25 @@
+use actix_web::http::{header, Method};
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+/// Weak ETag of the JSON form of `value`. Middlewares may still reshape the bytes sent
+/// (envelope, templates, pretty printing), so it only vouches for the data.
+pub fn of<T: Serialize>(value: &T) -> String {
+    let json = serde_json::to_vec(value).unwrap_or_default();
+    format!("W/\"{}\"", sha1::Sha1::from(json).digest())
+}
+
+/// Weak comparison of `etag` against the request's `If-None-Match`, only `GET` and `HEAD`
+/// can be answered `304`
+pub fn matches(req: &HttpRequest, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return false;
+    }
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
@@ -0,0 +1,50 @@
+//! Random valid payload for a scenario:
+//!
+//! ``` curl 'localhost:3030/v1/examples?case=C2&h=M' ```
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_derive::Deserialize;
+
+use crate::types::*;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExampleQuery {
+    #[serde(default)]
+    pub case: Option<Case>,
+    #[serde(default)]
+    pub h: Option<H>,
+}
+
+/// A/B/C combinations that resolve to the given H within a case.
+fn flags(case: &Case, h: &H) -> &'static [(bool, bool, bool)] {
+    match (case, h) {
+        (Case::C2, H::M) => &[(true, true, false), (true, false, true)],
+        (_, H::M) => &[(true, true, false)],
+        (_, H::P) => &[(true, true, true)],
+        (_, H::T) => &[(false, true, true)],
+        (_, H::E) => &[],
+    }
+}
+
+/// Generates a random `Params` payload that `/compute` accepts for the requested scenario.
+/// Missing H is picked at random, `H::E` has no valid payload and yields `None`.
+pub fn generate(query: &ExampleQuery) -> Option<Params> {
+    let mut rng = rand::thread_rng();
+    let case = query.case.clone().map_or(Case::B, |v| v);
+    let h = match &query.h {
+        Some(h) => h.clone(),
+        None => [H::M, H::P, H::T].choose(&mut rng)?.clone(),
+    };
+    let (a, b, c) = *flags(&case, &h).choose(&mut rng)?;
+
+    Some(Params {
+        a: Some(a),
+        b: Some(b),
+        c: Some(c),
+        d: Some((rng.gen_range(0.0, 100.0) * 100.0_f64).round() / 100.0),
+        e: Some(rng.gen_range(0, 100)),
+        f: Some(rng.gen_range(0, 100)),
+        case: Some(case),
+    })
+}
@@ -0,0 +1,132 @@
+//! Experimental routes (`batch`, `schedules`) sit behind feature flags from `features`,
+//! answering 404 while off. Unknown flags are off. Toggle at runtime:
+//!
+//! ``` curl -H "Content-Type: application/json" -X PUT -d '{"enabled": false}' localhost:3030/admin/features/batch ```
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpResponse};
+use futures::future::{ok, Either, Ready};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::json_error;
+
+/// Runtime feature flags, seeded from `Settings::features` and shared between workers
+#[derive(Debug, Default)]
+pub struct Features(RwLock<BTreeMap<String, bool>>);
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeatureState {
+    pub enabled: bool,
+}
+
+impl Features {
+    pub fn new(flags: BTreeMap<String, bool>) -> Self {
+        Features(RwLock::new(flags))
+    }
+
+    /// Unknown features are off, so new ones ship dark
+    pub fn enabled(&self, name: &str) -> bool {
+        self.0.read().unwrap().get(name).copied().unwrap_or(false)
+    }
+
+    pub fn set(&self, name: &str, enabled: bool) {
+        self.0.write().unwrap().insert(name.to_string(), enabled);
+    }
+
+    pub fn all(&self) -> BTreeMap<String, bool> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+pub async fn list(features: web::Data<Features>) -> HttpResponse {
+    HttpResponse::Ok().json(features.all())
+}
+
+pub async fn put(
+    name: web::Path<String>,
+    data: web::Json<FeatureState>,
+    features: web::Data<Features>,
+) -> HttpResponse {
+    features.set(&name, data.enabled);
+    HttpResponse::Ok().json(features.all())
+}
+
+/// Middleware hiding a resource behind a feature flag, answering 404 while it is off
+pub struct FeatureGate(pub &'static str);
+
+impl<S, B> Transform<S> for FeatureGate
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = FeatureGateMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(FeatureGateMiddleware {
+            service,
+            feature: self.0,
+        })
+    }
+}
+
+pub struct FeatureGateMiddleware<S> {
+    service: S,
+    feature: &'static str,
+}
+
+impl<S, B> Service for FeatureGateMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        // without a flag store, e.g. in tests, everything is on
+        let enabled = req
+            .app_data::<Features>()
+            .map_or(true, |f| f.enabled(self.feature));
+
+        if enabled {
+            Either::Left(self.service.call(req))
+        } else {
+            let resp = json_error(StatusCode::NOT_FOUND, "Resource not found.");
+            Either::Right(ok(req.into_response(resp.into_body())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_features_are_off() {
+        let features = Features::new(vec![("batch".to_string(), true)].into_iter().collect());
+
+        assert!(features.enabled("batch"));
+        assert!(!features.enabled("jobs"));
+        features.set("batch", false);
+        assert!(!features.enabled("batch"));
+    }
+}
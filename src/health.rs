@@ -0,0 +1,123 @@
+//! `/readyz` answers `503` while shutting down and while more than `ready_max_queued`
+//! requests wait for a `max_in_flight` slot. Its body lists the `reasons`, e.g. `{"status":
+//! "not_ready", "reasons": ["40 requests queued, over 32"]}`. `GET /health` reports
+//! `{"status": "maintenance"}` during maintenance.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use actix_web::{web, HttpResponse};
+use serde_derive::Serialize;
+
+use crate::limit::Limiter;
+use crate::maintenance::Maintenance;
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    /// `ok`, or `maintenance` while compute routes answer 503
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+/// Always 200 while the process serves requests, maintenance is reported in the body
+pub async fn health(maintenance: web::Data<Maintenance>) -> HttpResponse {
+    let retry_after = maintenance.retry_after();
+    HttpResponse::Ok().json(Health {
+        status: if retry_after.is_some() {
+            "maintenance"
+        } else {
+            "ok"
+        },
+        retry_after,
+    })
+}
+
+/// Whether the instance should get new traffic, cleared when shutdown begins or while
+/// too many requests wait for a `max_in_flight` slot
+pub struct Readiness {
+    serving: AtomicBool,
+    limiter: Option<Arc<Limiter>>,
+    max_queued: Option<usize>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness::new(None, None)
+    }
+}
+
+impl Readiness {
+    pub fn new(limiter: Option<Arc<Limiter>>, max_queued: Option<usize>) -> Self {
+        Readiness {
+            serving: AtomicBool::new(true),
+            limiter,
+            max_queued,
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        !self.serving.load(Ordering::SeqCst)
+    }
+
+    pub fn drain(&self) {
+        self.serving.store(false, Ordering::SeqCst);
+    }
+
+    /// Why the instance is not ready, empty if it is
+    pub fn reasons(&self) -> Vec<String> {
+        let mut reasons = vec![];
+        if self.is_draining() {
+            reasons.push("Shutting down".to_string());
+        }
+        if let (Some(limiter), Some(max)) = (&self.limiter, self.max_queued) {
+            let (_, queued) = limiter.load();
+            if queued > max {
+                reasons.push(format!("{} requests queued, over {}", queued, max));
+            }
+        }
+        reasons
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Ready {
+    /// `ready`, `draining` once SIGTERM/SIGINT was received, or `not_ready`
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reasons: Vec<String>,
+}
+
+/// 503 once shutdown begins, so load balancers stop routing here before the listener closes,
+/// and while the request queue is over `ready_max_queued`
+pub async fn readyz(readiness: web::Data<Readiness>) -> HttpResponse {
+    let reasons = readiness.reasons();
+    if reasons.is_empty() {
+        return HttpResponse::Ok().json(Ready {
+            status: "ready",
+            reasons,
+        });
+    }
+    let status = if readiness.is_draining() {
+        "draining"
+    } else {
+        "not_ready"
+    };
+    HttpResponse::ServiceUnavailable().json(Ready { status, reasons })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_reasons() {
+        let limiter = Arc::new(Limiter::new(1, 4));
+        let readiness = Readiness::new(Some(limiter), Some(0));
+        assert!(readiness.reasons().is_empty());
+        assert!(!readiness.is_draining());
+
+        readiness.drain();
+        assert_eq!(readiness.reasons(), vec!["Shutting down"]);
+    }
+}
@@ -0,0 +1,138 @@
+//! With `[heartbeat]` set, a heartbeat is POSTed to its `url` every `interval_secs` (60):
+//! the `instance` name (the host name if absent), version, rules version, readiness,
+//! uptime, request and error counts, and p50/p99 latency.
+
+use std::fs;
+use std::time::Duration;
+
+use actix_web::client::Client;
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::health::Readiness;
+use crate::stats::{Stats, Summary};
+use crate::types::RULES_VERSION;
+
+/// Monitoring endpoint the instance reports to, for systems preferring push over scraping
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HeartbeatSettings {
+    /// URL every heartbeat is POSTed to
+    pub url: String,
+    /// Seconds between two heartbeats
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Name the instance reports under, the host name if absent
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Serialize)]
+pub struct Heartbeat {
+    pub instance: String,
+    pub at: DateTime<Utc>,
+    pub version: &'static str,
+    pub rules_version: &'static str,
+    /// Whether `/readyz` answers 200
+    pub ready: bool,
+    pub uptime_secs: u64,
+    pub requests: u64,
+    /// Failed computes and requests rejected before computing, since startup
+    pub errors: u64,
+    pub latency_p50_ms: f64,
+    pub latency_p99_ms: f64,
+}
+
+fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+fn heartbeat(instance: &str, summary: Summary, ready: bool) -> Heartbeat {
+    Heartbeat {
+        instance: instance.to_string(),
+        at: Utc::now(),
+        version: env!("CARGO_PKG_VERSION"),
+        rules_version: RULES_VERSION,
+        ready,
+        uptime_secs: summary.uptime_secs,
+        requests: summary.requests,
+        errors: summary.by_error.values().sum(),
+        latency_p50_ms: summary.latency_ms.p50,
+        latency_p99_ms: summary.latency_ms.p99,
+    }
+}
+
+/// POSTs a `Heartbeat` every `interval_secs`, the first one right away. A heartbeat that
+/// fails is logged and not sent again, the next one carries newer figures.
+pub fn spawn(
+    settings: HeartbeatSettings,
+    stats: web::Data<Stats>,
+    readiness: web::Data<Readiness>,
+) {
+    let instance = settings.instance.clone().unwrap_or_else(host_name);
+    let interval = Duration::from_secs(settings.interval_secs.max(1));
+    info!(
+        "Sending heartbeats of {} to {} every {}s",
+        instance,
+        settings.url,
+        interval.as_secs()
+    );
+    actix_rt::spawn(async move {
+        let mut tick = actix_rt::time::interval(interval);
+        loop {
+            tick.tick().await;
+            let beat = heartbeat(&instance, stats.summary(), readiness.reasons().is_empty());
+            let client = Client::build().timeout(interval).finish();
+            match client.post(settings.url.as_str()).send_json(&beat).await {
+                Ok(resp) if resp.status().is_success() => {
+                    debug!("Heartbeat sent to {}", settings.url)
+                }
+                Ok(resp) => warn!(
+                    "Heartbeat endpoint {} answered {}",
+                    settings.url,
+                    resp.status()
+                ),
+                Err(e) => warn!("Could not send heartbeat to {}: {:?}", settings.url, e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::Outcome;
+    use crate::types::{Case, H};
+
+    #[test]
+    fn sums_up_stats() {
+        let stats = Stats::default();
+        let failed = Outcome {
+            case: Case::B,
+            h: H::E,
+            error: Some("unsupported_params"),
+            compute: Duration::from_millis(3),
+        };
+        stats.record(&[failed], None, None, Duration::from_millis(3));
+        stats.record_error("invalid_json");
+
+        let beat = heartbeat("rtp-1", stats.summary(), true);
+        assert_eq!((beat.requests, beat.errors), (2, 2));
+        assert!((beat.latency_p99_ms - 3.0).abs() < 1e-9);
+        assert_eq!(beat.rules_version, RULES_VERSION);
+
+        let json = serde_json::to_value(&beat).unwrap();
+        assert_eq!(json["instance"], "rtp-1");
+        assert_eq!(json["ready"], true);
+    }
+}
@@ -0,0 +1,121 @@
+//! Error messages follow `Accept-Language` (en, de, uk), the stable error code is in the
+//! `kind` field of JSON errors and in the `X-Error-Code` header of plain ones.
+
+use std::fmt;
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+/// Languages error messages are translated to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lang {
+    En,
+    De,
+    Uk,
+}
+
+impl Lang {
+    fn parse(tag: &str) -> Option<Lang> {
+        let primary = tag.split('-').next()?.trim().to_lowercase();
+        match primary.as_str() {
+            "en" => Some(Lang::En),
+            "de" => Some(Lang::De),
+            "uk" => Some(Lang::Uk),
+            _ => None,
+        }
+    }
+
+    /// Best supported language of an `Accept-Language` value, English if none matches
+    pub fn negotiate(accept: &str) -> Lang {
+        let mut best = (Lang::En, 0.0);
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let tag = parts.next().unwrap_or_default();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if let Some(lang) = Lang::parse(tag) {
+                if q > best.1 {
+                    best = (lang, q);
+                }
+            }
+        }
+        best.0
+    }
+
+    pub fn of(req: &HttpRequest) -> Lang {
+        req.headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .map_or(Lang::En, Lang::negotiate)
+    }
+}
+
+/// Validation and compute failures with stable codes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    MissingParam(&'static str),
+    MissingCase,
+    Unsupported,
+    NoExample,
+}
+
+impl Fault {
+    /// Machine-readable code, the same in every language
+    pub fn code(&self) -> &'static str {
+        match self {
+            Fault::MissingParam(_) => "missing_param",
+            Fault::MissingCase => "missing_case",
+            Fault::Unsupported => "unsupported_params",
+            Fault::NoExample => "no_example",
+        }
+    }
+
+    pub fn message(&self, lang: Lang) -> String {
+        let prefix = match lang {
+            Lang::En => "Wrong params",
+            Lang::De => "Falsche Parameter",
+            Lang::Uk => "Хибні параметри",
+        };
+        let detail = match (self, lang) {
+            (Fault::MissingParam(p), Lang::En) => format!("no {} param", p),
+            (Fault::MissingParam(p), Lang::De) => format!("Parameter {} fehlt", p),
+            (Fault::MissingParam(p), Lang::Uk) => format!("бракує параметра {}", p),
+            (Fault::MissingCase, Lang::En) => "case is required".into(),
+            (Fault::MissingCase, Lang::De) => "case muss angegeben werden".into(),
+            (Fault::MissingCase, Lang::Uk) => "параметр case обов'язковий".into(),
+            (Fault::Unsupported, Lang::En) => "set of parameters is not supported".into(),
+            (Fault::Unsupported, Lang::De) => "Parameterkombination wird nicht unterstützt".into(),
+            (Fault::Unsupported, Lang::Uk) => "такий набір параметрів не підтримується".into(),
+            (Fault::NoExample, Lang::En) => "no valid example for this scenario".into(),
+            (Fault::NoExample, Lang::De) => "kein gültiges Beispiel für dieses Szenario".into(),
+            (Fault::NoExample, Lang::Uk) => "для цього сценарію немає прикладу".into(),
+        };
+        format!("{}: {}", prefix, detail)
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message(Lang::En))
+    }
+}
+
+impl std::error::Error for Fault {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_language() {
+        assert_eq!(Lang::negotiate("de-DE,de;q=0.9,en;q=0.8"), Lang::De);
+        assert_eq!(Lang::negotiate("fr, uk;q=0.5, en;q=0.3"), Lang::Uk);
+        assert_eq!(Lang::negotiate("fr"), Lang::En);
+        assert_eq!(
+            Fault::MissingParam("D").message(Lang::De),
+            "Falsche Parameter: Parameter D fehlt"
+        );
+    }
+}
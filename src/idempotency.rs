@@ -0,0 +1,189 @@
+//! POST requests with an `Idempotency-Key` header are answered with the stored original
+//! response when the same caller repeats them within a day, and with `422` if the key comes
+//! back with another body.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, Payload, PayloadStream, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::{HeaderName, HeaderValue, Method, StatusCode};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use bytes::BytesMut;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::{stream, StreamExt};
+use sha2::{Digest, Sha256};
+
+use crate::auth::Caller;
+use crate::capture::CapturedResponse;
+use crate::errors::json_error;
+use crate::limit;
+
+pub const HEADER: &str = "idempotency-key";
+
+#[derive(Clone)]
+struct Stored {
+    at: Instant,
+    /// SHA-256 of the request body, a key reused for another body is refused
+    body: Vec<u8>,
+    response: CapturedResponse,
+}
+
+impl Stored {
+    fn to_response(&self) -> HttpResponse {
+        let mut resp = self.response.to_response();
+        resp.headers_mut().insert(
+            HeaderName::from_static("idempotent-replayed"),
+            HeaderValue::from_static("true"),
+        );
+        resp
+    }
+}
+
+/// Responses of POST requests carrying an `Idempotency-Key`, kept for `ttl` per caller.
+/// Shared between workers.
+pub struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Stored>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration) -> Self {
+        IdempotencyStore {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Stored> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|s| s.at.elapsed() < self.ttl)
+            .cloned()
+    }
+
+    fn put(&self, key: String, stored: Stored) {
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, s| s.at.elapsed() < ttl);
+        entries.insert(key, stored);
+    }
+}
+
+fn rejected(status: StatusCode, message: &'static str) -> Error {
+    actix_web::error::InternalError::from_response(message, json_error(status, message)).into()
+}
+
+/// Middleware replaying stored responses for repeated `Idempotency-Key`s of the same
+/// caller, 422 if the key comes back with another body.
+/// Works on plain `Body`, so it has to be registered before `Logger` and other
+/// middlewares changing the body type.
+pub struct Idempotency(pub Arc<IdempotencyStore>);
+
+impl<S> Transform<S> for Idempotency
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IdempotencyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IdempotencyMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            store: self.0.clone(),
+        })
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    store: Arc<IdempotencyStore>,
+}
+
+impl<S> Service for IdempotencyMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let key = match req.headers().get(HEADER).and_then(|v| v.to_str().ok()) {
+            Some(key) if *req.method() == Method::POST => {
+                let caller = Caller::of(req.request());
+                let owner = caller.owner().unwrap_or("-");
+                format!("{} {} {}", owner, req.path(), key)
+            }
+            _ => return Box::pin(self.service.borrow_mut().call(req)),
+        };
+
+        let service = self.service.clone();
+        let store = self.store.clone();
+        // read whole to compare it, so no more than the route takes
+        let limit = limit::body_limit(&req);
+
+        Box::pin(async move {
+            let mut body = BytesMut::new();
+            let mut payload = req.take_payload();
+            while let Some(chunk) = payload.next().await {
+                body.extend_from_slice(&chunk?);
+                if body.len() > limit {
+                    return Err(rejected(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "Payload too large.",
+                    ));
+                }
+            }
+            let body = body.freeze();
+            let digest = Sha256::digest(&body).to_vec();
+            let replay: PayloadStream =
+                Box::pin(stream::once(futures::future::ok::<_, PayloadError>(body)));
+            req.set_payload(Payload::from(replay));
+
+            match store.get(&key) {
+                Some(stored) if stored.body == digest => {
+                    return Ok(req.into_response(stored.to_response()))
+                }
+                Some(_) => {
+                    return Err(rejected(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "Idempotency-Key was used with another body.",
+                    ))
+                }
+                None => {}
+            }
+
+            let fut = service.borrow_mut().call(req);
+            let res = fut.await?;
+            if !res.status().is_server_error() {
+                if let Some(response) = CapturedResponse::capture(&res) {
+                    let stored = Stored {
+                        at: Instant::now(),
+                        body: digest,
+                        response,
+                    };
+                    store.put(key, stored);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
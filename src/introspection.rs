@@ -0,0 +1,147 @@
+//! Opaque bearer tokens are checked at the OAuth2 introspection endpoint of
+//! `[introspection]`, each answer cached for `cache_ttl` seconds (30).
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::client::Client;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::{default_roles, Identity};
+
+/// OAuth2 token introspection endpoint (RFC 7662) checking opaque bearer tokens
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IntrospectionSettings {
+    pub url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Seconds an answer is reused for the same token
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+}
+
+fn default_cache_ttl() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize)]
+struct Introspection {
+    active: bool,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    client_id: Option<String>,
+    /// Space separated, taken as the caller's roles
+    #[serde(default)]
+    scope: Option<String>,
+}
+
+type Verdict = Result<Identity, &'static str>;
+
+/// Introspects bearer tokens, caching each verdict for `cache_ttl`
+pub struct Introspector {
+    settings: IntrospectionSettings,
+    cache: Mutex<HashMap<String, (Instant, Verdict)>>,
+}
+
+impl Introspector {
+    pub fn new(settings: &IntrospectionSettings) -> Self {
+        Introspector {
+            settings: settings.clone(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cached(&self, token: &str, now: Instant) -> Option<Verdict> {
+        let cache = self.cache.lock().unwrap();
+        match cache.get(token) {
+            Some((expires, verdict)) if *expires > now => Some(verdict.clone()),
+            _ => None,
+        }
+    }
+
+    fn remember(&self, token: &str, verdict: Verdict, now: Instant) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (expires, _)| *expires > now);
+        let expires = now + Duration::from_secs(self.settings.cache_ttl);
+        cache.insert(token.to_string(), (expires, verdict));
+    }
+
+    /// Subject and scopes of an active `token`, the reason it is rejected otherwise
+    pub async fn verify(&self, token: &str) -> Verdict {
+        if let Some(verdict) = self.cached(token, Instant::now()) {
+            return verdict;
+        }
+        let resp = Client::new()
+            .post(self.settings.url.as_str())
+            .basic_auth(&self.settings.client_id, Some(&self.settings.client_secret))
+            .send_form(&[("token", token), ("token_type_hint", "access_token")])
+            .await;
+        let answer = match resp {
+            Ok(mut resp) if resp.status().is_success() => resp.json::<Introspection>().await.ok(),
+            Ok(resp) => {
+                warn!("Token introspection answered {}", resp.status());
+                None
+            }
+            Err(e) => {
+                warn!("Could not introspect token: {:?}", e);
+                None
+            }
+        };
+        // failures to ask are not cached, the next request tries again
+        let answer = answer.ok_or("Token introspection failed")?;
+        let verdict = match answer {
+            Introspection { active: false, .. } => Err("Inactive bearer token"),
+            Introspection {
+                sub,
+                username,
+                client_id,
+                scope,
+                ..
+            } => sub
+                .or(username)
+                .or(client_id)
+                .map(|name| Identity {
+                    name,
+                    roles: scope
+                        .map(|s| s.split_whitespace().map(String::from).collect())
+                        .unwrap_or_else(default_roles),
+                })
+                .ok_or("Bearer token names no subject"),
+        };
+        self.remember(token, verdict.clone(), Instant::now());
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verdicts_expire() {
+        let introspector = Introspector::new(&IntrospectionSettings {
+            url: "https://id.example.com/introspect".into(),
+            client_id: "rtp".into(),
+            client_secret: "s".into(),
+            cache_ttl: 30,
+        });
+        let now = Instant::now();
+        let partner = Identity {
+            name: "partner".into(),
+            roles: default_roles(),
+        };
+        introspector.remember("t1", Ok(partner.clone()), now);
+
+        assert_eq!(introspector.cached("t1", now), Some(Ok(partner)));
+        assert_eq!(
+            introspector.cached("t1", now + Duration::from_secs(31)),
+            None
+        );
+        assert_eq!(introspector.cached("t2", now), None);
+    }
+}
@@ -0,0 +1,349 @@
+//! With `[encryption]` set, params may be sent as compact JWE (`Content-Type:
+//! application/jose`, `RSA-OAEP-256` and `A256GCM`) to the key published at
+//! `/v1/encryption-key`, so proxies on the way never see D/E/F. Requests also accepting
+//! `application/jose` get the response encrypted with the same content key (`alg: dir`).
+
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpResponse};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use bytes::Bytes;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use rand_core::OsRng;
+use rsa::{PaddingScheme, PublicKeyParts, RSAPrivateKey};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::errors::json_error;
+use crate::signature;
+
+/// Media type of compact JWS/JWE bodies
+pub const JOSE: &str = "application/jose";
+
+/// The one answer to bodies failing to decrypt, whichever step failed
+const UNDECRYPTABLE: &str = "Could not decrypt the JWE";
+
+/// Private key of the server clients encrypt params to
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EncryptionSettings {
+    /// PEM file of the RSA private key (PKCS#8 or PKCS#1), or the PEM text
+    pub key: String,
+    /// `kid` of the published public key
+    #[serde(default)]
+    pub kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct JweHeader {
+    alg: String,
+    enc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cty: Option<String>,
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(part: &str) -> Option<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok()
+}
+
+/// Content encryption key of a request, responses to it are encrypted with it again
+struct Cek(Vec<u8>);
+
+/// Decrypts `RSA-OAEP-256`/`A256GCM` request bodies and encrypts responses
+pub struct Decrypter {
+    key: RSAPrivateKey,
+    kid: Option<String>,
+}
+
+impl Decrypter {
+    pub fn new(settings: &EncryptionSettings) -> io::Result<Self> {
+        let pem = if settings.key.trim_start().starts_with("-----BEGIN") {
+            settings.key.clone().into_bytes()
+        } else {
+            fs::read(&settings.key)?
+        };
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let der = pem::parse(pem).map_err(|e| invalid(e.to_string()))?;
+        let key = RSAPrivateKey::from_pkcs8(&der.contents)
+            .or_else(|_| RSAPrivateKey::from_pkcs1(&der.contents))
+            .map_err(|e| invalid(format!("Unusable encryption key: {}", e)))?;
+        Ok(Decrypter {
+            key,
+            kid: settings.kid.clone(),
+        })
+    }
+
+    /// Public key as JWK for clients to encrypt with
+    pub fn jwk(&self) -> serde_json::Value {
+        json!({
+            "kty": "RSA",
+            "use": "enc",
+            "alg": "RSA-OAEP-256",
+            "kid": self.kid,
+            "n": b64(&self.key.n().to_bytes_be()),
+            "e": b64(&self.key.e().to_bytes_be()),
+        })
+    }
+
+    /// Plaintext, its content type and the key it was encrypted with
+    fn decrypt(&self, jwe: &[u8]) -> Result<(Vec<u8>, Option<String>, Cek), &'static str> {
+        let jwe = std::str::from_utf8(jwe).map_err(|_| "Malformed JWE")?;
+        let parts: Vec<_> = jwe.trim().split('.').collect();
+        let (protected, key, iv, ciphertext, tag) = match parts.as_slice() {
+            [p, k, i, c, t] => (*p, *k, *i, *c, *t),
+            _ => return Err("Malformed JWE"),
+        };
+        let header: JweHeader = unb64(protected)
+            .and_then(|h| serde_json::from_slice(&h).ok())
+            .ok_or("Malformed JWE header")?;
+        if header.alg != "RSA-OAEP-256" || header.enc != "A256GCM" {
+            return Err("Unsupported JWE algorithm, expected RSA-OAEP-256 and A256GCM");
+        }
+        let (key, iv, mut ciphertext, tag) =
+            match (unb64(key), unb64(iv), unb64(ciphertext), unb64(tag)) {
+                (Some(k), Some(i), Some(c), Some(t)) if i.len() == 12 => (k, i, c, t),
+                _ => return Err("Malformed JWE"),
+            };
+        // a key that does not unwrap is replaced by a random one and fails like a
+        // tampered payload, leaving no oracle on the RSA padding (RFC 7516 section 11.5)
+        let fallback: [u8; 32] = rand::random();
+        let cek =
+            match self
+                .key
+                .decrypt_blinded(&mut OsRng, PaddingScheme::new_oaep::<Sha256>(), &key)
+            {
+                Ok(cek) if cek.len() == 32 => cek,
+                _ => fallback.to_vec(),
+            };
+        ciphertext.extend_from_slice(&tag);
+        let plaintext = Aes256Gcm::new(GenericArray::from_slice(&cek))
+            .decrypt(
+                GenericArray::from_slice(&iv),
+                Payload {
+                    msg: &ciphertext,
+                    aad: protected.as_bytes(),
+                },
+            )
+            .map_err(|_| UNDECRYPTABLE)?;
+        Ok((plaintext, header.cty, Cek(cek)))
+    }
+}
+
+/// Compact JWE of `plaintext` under the `dir` key `cek`
+fn encrypt(cek: &Cek, plaintext: &[u8], cty: Option<String>) -> Option<String> {
+    let header = JweHeader {
+        alg: "dir".into(),
+        enc: "A256GCM".into(),
+        kid: None,
+        cty,
+    };
+    let protected = b64(&serde_json::to_vec(&header).ok()?);
+    let iv: [u8; 12] = rand::random();
+    let mut sealed = Aes256Gcm::new(GenericArray::from_slice(&cek.0))
+        .encrypt(
+            GenericArray::from_slice(&iv),
+            Payload {
+                msg: plaintext,
+                aad: protected.as_bytes(),
+            },
+        )
+        .ok()?;
+    let tag = sealed.split_off(sealed.len() - 16);
+    Some(format!(
+        "{}..{}.{}.{}",
+        protected,
+        b64(&iv),
+        b64(&sealed),
+        b64(&tag)
+    ))
+}
+
+fn is_jose(value: Option<&HeaderValue>) -> bool {
+    value
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.contains(JOSE))
+}
+
+fn undecryptable(reason: &'static str) -> Error {
+    InternalError::from_response(reason, json_error(StatusCode::BAD_REQUEST, reason)).into()
+}
+
+/// Middleware decrypting `application/jose` request bodies. Responses to them are
+/// encrypted with the same content key if the request accepts `application/jose`.
+/// Works on plain `Body`, so it has to be registered right after `SignResponses`.
+pub struct Encryption(pub Option<Arc<Decrypter>>);
+
+impl<S> Transform<S> for Encryption
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = EncryptionMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(EncryptionMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            decrypter: self.0.clone(),
+        })
+    }
+}
+
+pub struct EncryptionMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    decrypter: Option<Arc<Decrypter>>,
+}
+
+impl<S> Service for EncryptionMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let decrypter = match &self.decrypter {
+            Some(decrypter) if is_jose(req.headers().get(header::CONTENT_TYPE)) => {
+                decrypter.clone()
+            }
+            _ => return Box::pin(self.service.borrow_mut().call(req)),
+        };
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let jwe = match signature::read_body(&mut req, signature::MAX_BODY).await {
+                Ok(Some(jwe)) => jwe,
+                Ok(None) => return Err(undecryptable("Encrypted body too large")),
+                Err(_) => return Err(undecryptable("Could not read encrypted body")),
+            };
+            let (plaintext, cty, cek) = decrypter.decrypt(&jwe).map_err(undecryptable)?;
+            let content_type = match cty {
+                Some(cty) if !cty.contains('/') => format!("application/{}", cty),
+                Some(cty) => cty,
+                None => "application/json".into(),
+            };
+            let headers = req.headers_mut();
+            if let Ok(v) = HeaderValue::from_str(&content_type) {
+                headers.insert(header::CONTENT_TYPE, v);
+            }
+            headers.insert(header::CONTENT_LENGTH, plaintext.len().into());
+            let encrypt_response = is_jose(req.headers().get(header::ACCEPT));
+            signature::set_body(&mut req, Bytes::from(plaintext));
+
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+            if !encrypt_response {
+                return Ok(res);
+            }
+            let plaintext = match res.response().body().as_ref() {
+                Some(Body::Bytes(bytes)) => bytes.clone(),
+                _ => return Ok(res),
+            };
+            let cty = res
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim_start_matches("application/").to_string());
+            let jwe = match encrypt(&cek, &plaintext, cty) {
+                Some(jwe) => jwe,
+                None => return Ok(res),
+            };
+            res.headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(JOSE));
+            Ok(res.map_body(|_, _| ResponseBody::Body(Body::Bytes(Bytes::from(jwe)))))
+        })
+    }
+}
+
+/// Public key params are encrypted to, 404 while encryption is off
+pub async fn key(decrypter: web::Data<Option<Arc<Decrypter>>>) -> HttpResponse {
+    match decrypter.as_ref() {
+        Some(decrypter) => HttpResponse::Ok().json(json!({ "keys": [decrypter.jwk()] })),
+        None => json_error(StatusCode::NOT_FOUND, "Encryption is not enabled."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::{PublicKey, RSAPublicKey};
+
+    #[test]
+    fn encrypts_responses_with_the_request_key() {
+        let cek = Cek(vec![7; 32]);
+        let jwe = encrypt(&cek, br#"{"k":4.0}"#, Some("json".into())).unwrap();
+        let parts: Vec<_> = jwe.split('.').collect();
+        assert_eq!(parts[1], "");
+
+        let mut sealed = unb64(parts[3]).unwrap();
+        sealed.extend(unb64(parts[4]).unwrap());
+        let plaintext = Aes256Gcm::new(GenericArray::from_slice(&cek.0))
+            .decrypt(
+                GenericArray::from_slice(&unb64(parts[2]).unwrap()),
+                Payload {
+                    msg: &sealed,
+                    aad: parts[0].as_bytes(),
+                },
+            )
+            .unwrap();
+        assert_eq!(plaintext, br#"{"k":4.0}"#);
+    }
+
+    #[test]
+    fn fails_alike_on_bad_keys_and_payloads() {
+        let decrypter = Decrypter {
+            key: RSAPrivateKey::new(&mut OsRng, 1024).unwrap(),
+            kid: None,
+        };
+        let protected = b64(br#"{"alg":"RSA-OAEP-256","enc":"A256GCM"}"#);
+        let jwe = |key: &[u8]| {
+            format!(
+                "{}.{}.{}.{}.{}",
+                protected,
+                b64(key),
+                b64(&[0; 12]),
+                b64(b"params"),
+                b64(&[0; 16])
+            )
+        };
+
+        let wrapped = RSAPublicKey::from(&decrypter.key)
+            .encrypt(&mut OsRng, PaddingScheme::new_oaep::<Sha256>(), &[7; 32])
+            .unwrap();
+        for key in &[vec![1; 128], wrapped] {
+            assert_eq!(
+                decrypter.decrypt(jwe(key).as_bytes()).err(),
+                Some(UNDECRYPTABLE)
+            );
+        }
+    }
+}
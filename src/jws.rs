@@ -0,0 +1,217 @@
+//! With `[response_signing]` set, compute results are signed with the server `key` as JWS
+//! (`algorithm` RS256 by default, `kid` in the header). The detached form
+//! `<header>..<signature>` goes in `X-Jws-Signature` over the body as sent, with `detached
+//! = false` the body is replaced by the compact JWS (`application/jose`).
+
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::Error;
+use bytes::Bytes;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use jsonwebtoken::{crypto, Algorithm, EncodingKey, Header};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::stats::Outcomes;
+
+/// Response header carrying the detached JWS of the body
+pub const JWS_HEADER: &str = "x-jws-signature";
+
+/// Server key compute results are signed with
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResponseSigningSettings {
+    /// PEM file of the private key (or the PEM text), the shared secret for `HS*`
+    pub key: String,
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    /// `kid` put in the JWS header so verifiers can pick the public key
+    #[serde(default)]
+    pub kid: Option<String>,
+    /// Send the signature in `X-Jws-Signature` and leave the body as is,
+    /// otherwise the body is replaced by the compact JWS
+    #[serde(default = "default_detached")]
+    pub detached: bool,
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::RS256
+}
+
+fn default_detached() -> bool {
+    true
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Signs response bodies as compact JWS
+pub struct ResponseSigner {
+    key: EncodingKey,
+    algorithm: Algorithm,
+    /// Encoded JWS header, the same for every response
+    header: String,
+    detached: bool,
+}
+
+impl ResponseSigner {
+    pub fn new(settings: &ResponseSigningSettings) -> io::Result<Self> {
+        if settings.key.trim_start().starts_with("-----BEGIN") {
+            Self::with_key(settings, settings.key.as_bytes())
+        } else {
+            Self::with_key(settings, &fs::read(&settings.key)?)
+        }
+    }
+
+    fn with_key(settings: &ResponseSigningSettings, secret: &[u8]) -> io::Result<Self> {
+        let key = match settings.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                Ok(EncodingKey::from_secret(secret))
+            }
+            Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(secret),
+            _ => EncodingKey::from_rsa_pem(secret),
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut header = Header::new(settings.algorithm);
+        header.kid = settings.kid.clone();
+        // the payload is an API result, not a JWT
+        header.typ = None;
+        let header = serde_json::to_vec(&header)?;
+        Ok(ResponseSigner {
+            key,
+            algorithm: settings.algorithm,
+            header: b64(&header),
+            detached: settings.detached,
+        })
+    }
+
+    /// Compact JWS of `payload`, with the payload part left empty if `detached`
+    fn sign(&self, payload: &[u8]) -> jsonwebtoken::errors::Result<String> {
+        let input = format!("{}.{}", self.header, b64(payload));
+        let signature = crypto::sign(&input, &self.key, self.algorithm)?;
+        if self.detached {
+            Ok(format!("{}..{}", self.header, signature))
+        } else {
+            Ok(format!("{}.{}", input, signature))
+        }
+    }
+}
+
+/// Middleware signing the bodies of compute responses, i.e. those carrying `Outcomes`.
+/// Works on plain `Body`, so it has to be registered right after `PrettyJson`.
+pub struct SignResponses(pub Option<Arc<ResponseSigner>>);
+
+impl<S> Transform<S> for SignResponses
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SignResponsesMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SignResponsesMiddleware {
+            service,
+            signer: self.0.clone(),
+        })
+    }
+}
+
+pub struct SignResponsesMiddleware<S> {
+    service: S,
+    signer: Option<Arc<ResponseSigner>>,
+}
+
+impl<S> Service for SignResponsesMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let signer = match &self.signer {
+            Some(signer) => signer.clone(),
+            None => return Box::pin(self.service.call(req)),
+        };
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if res.response().extensions().get::<Outcomes>().is_none() {
+                return Ok(res);
+            }
+            let payload = match res.response().body().as_ref() {
+                Some(Body::Bytes(bytes)) => bytes.clone(),
+                _ => return Ok(res),
+            };
+            let jws = match signer.sign(&payload) {
+                Ok(jws) => jws,
+                Err(e) => {
+                    warn!("Could not sign response: {:?}", e);
+                    return Ok(res);
+                }
+            };
+            if signer.detached {
+                if let Ok(v) = HeaderValue::from_str(&jws) {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static(JWS_HEADER), v);
+                }
+                return Ok(res);
+            }
+            res.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/jose"),
+            );
+            Ok(res.map_body(|_, _| ResponseBody::Body(Body::Bytes(Bytes::from(jws)))))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{decode, DecodingKey, Validation};
+
+    #[test]
+    fn signs_detached() {
+        let settings = ResponseSigningSettings {
+            key: "/run/secrets/jws".into(),
+            algorithm: Algorithm::HS256,
+            kid: Some("k1".into()),
+            detached: true,
+        };
+        let signer = ResponseSigner::with_key(&settings, b"s3cr3t").unwrap();
+        let body = br#"{"h":"M","k":4.0}"#;
+        let jws = signer.sign(body).unwrap();
+        let parts: Vec<_> = jws.split('.').collect();
+        assert_eq!(parts[1], "");
+
+        let attached = format!("{}.{}.{}", parts[0], b64(body), parts[2]);
+        let validation = Validation {
+            validate_exp: false,
+            ..Validation::new(Algorithm::HS256)
+        };
+        let secret = DecodingKey::from_secret(b"s3cr3t");
+        let token = decode::<serde_json::Value>(&attached, &secret, &validation).unwrap();
+        assert_eq!(token.header.kid.as_deref(), Some("k1"));
+        assert_eq!(token.claims["h"], "M");
+    }
+}
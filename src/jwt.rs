@@ -0,0 +1,152 @@
+//! With `[jwt]` set, `Authorization: Bearer` tokens signed by a key of `jwks_url`
+//! (refreshed every 10 minutes) are accepted, checking `issuer`, `audience` and expiry with
+//! `leeway` seconds of clock skew. The token's `sub` is the caller's identity.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::client::Client;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::{default_roles, Identity};
+
+/// Seconds between fetches of the signing keys
+const REFRESH_INTERVAL: u64 = 600;
+
+/// Identity provider bearer tokens are accepted from
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JwtSettings {
+    /// Expected `iss` claim
+    pub issuer: String,
+    /// Expected `aud` claim
+    pub audience: String,
+    /// URL of the provider's JSON Web Key Set
+    pub jwks_url: String,
+    /// Seconds of clock skew tolerated on `exp` and `nbf`
+    #[serde(default = "default_leeway")]
+    pub leeway: u64,
+}
+
+fn default_leeway() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kid: String,
+    kty: String,
+    #[serde(default)]
+    n: String,
+    #[serde(default)]
+    e: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default = "default_roles")]
+    roles: Vec<String>,
+}
+
+/// RSA signing keys of the provider by `kid`, refreshed in the background
+pub struct Jwks {
+    settings: JwtSettings,
+    keys: RwLock<HashMap<String, (String, String)>>,
+}
+
+impl Jwks {
+    pub fn new(settings: &JwtSettings) -> Self {
+        Jwks {
+            settings: settings.clone(),
+            keys: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn set(&self, set: JwkSet) {
+        let keys: HashMap<_, _> = set
+            .keys
+            .into_iter()
+            .filter(|k| k.kty == "RSA")
+            .map(|k| (k.kid, (k.n, k.e)))
+            .collect();
+        info!(
+            "Loaded {} signing keys from {}",
+            keys.len(),
+            self.settings.jwks_url
+        );
+        *self.keys.write().unwrap() = keys;
+    }
+
+    /// Subject and `roles` claim of a valid `token`, the reason it is rejected otherwise
+    pub fn verify(&self, token: &str) -> Result<Identity, &'static str> {
+        let kid = decode_header(token)
+            .ok()
+            .and_then(|h| h.kid)
+            .ok_or("Malformed bearer token")?;
+        let keys = self.keys.read().unwrap();
+        let (n, e) = keys.get(&kid).ok_or("Unknown token signing key")?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.leeway = self.settings.leeway;
+        validation.iss = Some(self.settings.issuer.clone());
+        validation.set_audience(&[&self.settings.audience]);
+        decode::<Claims>(token, &DecodingKey::from_rsa_components(n, e), &validation)
+            .map(|data| Identity {
+                name: data.claims.sub,
+                roles: data.claims.roles,
+            })
+            .map_err(|_| "Invalid bearer token")
+    }
+}
+
+async fn fetch(url: &str) -> Result<JwkSet, String> {
+    let mut resp = Client::new()
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    resp.json::<JwkSet>().await.map_err(|e| e.to_string())
+}
+
+/// Fetches the signing keys now and every `REFRESH_INTERVAL` seconds, keeping the
+/// previous ones if the provider is unreachable
+pub fn spawn_refresh(jwks: Arc<Jwks>) {
+    actix_rt::spawn(async move {
+        let mut tick = actix_rt::time::interval(Duration::from_secs(REFRESH_INTERVAL));
+        loop {
+            tick.tick().await;
+            match fetch(&jwks.settings.jwks_url).await {
+                Ok(set) => jwks.set(set),
+                Err(e) => warn!("Could not fetch {}: {}", jwks.settings.jwks_url, e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unknown_tokens() {
+        let jwks = Jwks::new(&JwtSettings {
+            issuer: "https://id.example.com".into(),
+            audience: "rest-test-params".into(),
+            jwks_url: "https://id.example.com/jwks".into(),
+            leeway: 60,
+        });
+
+        assert_eq!(jwks.verify("not a jwt"), Err("Malformed bearer token"));
+        // {"alg":"RS256","kid":"k1"} with an empty payload and signature
+        let token = "eyJhbGciOiJSUzI1NiIsImtpZCI6ImsxIn0.e30.c2ln";
+        assert_eq!(jwks.verify(token), Err("Unknown token signing key"));
+    }
+}
@@ -0,0 +1,309 @@
+//! More keys are issued at runtime with `POST /admin/keys` (`name`, `roles`, optional
+//! `expires` and `metadata`), the answer is the only place the key is shown. They are kept
+//! as SHA-256 hashes in `key_file`, listed with `GET /admin/keys`, replaced with `POST
+//! /admin/keys/{id}/rotate` and revoked with `DELETE /admin/keys/{id}`:
+//!
+//! ``` curl -H "Content-Type: application/json" -X POST -d '{"name": "partner", "expires": "2027-01-01T00:00:00Z"}' localhost:3030/admin/keys ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{default_roles, Identity};
+use crate::errors::json_error;
+use crate::session::Sessions;
+
+/// What `/admin/keys` is asked to issue
+#[derive(Debug, Deserialize)]
+pub struct NewKey {
+    pub name: String,
+    #[serde(default = "default_roles")]
+    pub roles: Vec<String>,
+    /// Refused from then on, valid until revoked if absent
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+    /// Free-form labels, e.g. owner or ticket
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Issued key as listed, the key itself is only shown once
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ManagedKey {
+    pub id: String,
+    pub name: String,
+    pub roles: Vec<String>,
+    pub created: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A key as kept in `key_file`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Stored {
+    /// Hex SHA-256 of the key, random keys need no salt
+    hash: String,
+    #[serde(flatten)]
+    key: ManagedKey,
+}
+
+/// Answer to creating or rotating a key
+#[derive(Debug, Serialize)]
+pub struct Issued {
+    #[serde(flatten)]
+    pub info: ManagedKey,
+    pub key: String,
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn random_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// API keys issued at runtime, kept hashed in `file` across restarts
+pub struct KeyStore {
+    file: Option<String>,
+    keys: Mutex<HashMap<String, Stored>>,
+    /// Held from taking the keys to renaming the file, so saves land in the order the
+    /// keys changed in and do not share the temporary file
+    saving: Mutex<()>,
+}
+
+impl KeyStore {
+    /// Starts from the keys saved in `file`, if any. A file that cannot be read or
+    /// parsed is an error rather than an empty store, whose next save would drop the keys.
+    pub fn load(file: Option<String>) -> Result<Self, String> {
+        let keys: Vec<Stored> = match &file {
+            Some(path) => match fs::read(path) {
+                Ok(bytes) => serde_json::from_slice(&bytes)
+                    .map_err(|e| format!("Invalid key file {}: {}", path, e))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(format!("Could not read key file {}: {}", path, e)),
+            },
+            None => Vec::new(),
+        };
+        if !keys.is_empty() {
+            info!("Loaded {} managed API keys", keys.len());
+        }
+        Ok(KeyStore {
+            file,
+            keys: Mutex::new(keys.into_iter().map(|s| (s.key.id.clone(), s)).collect()),
+            saving: Mutex::new(()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.lock().unwrap().is_empty()
+    }
+
+    /// Identity of `key` at `now`, `None` if it was never issued or got revoked
+    pub fn identify(
+        &self,
+        key: &str,
+        now: DateTime<Utc>,
+    ) -> Option<Result<Identity, &'static str>> {
+        let hash = hash(key);
+        let keys = self.keys.lock().unwrap();
+        let stored = keys.values().find(|s| s.hash == hash)?;
+        if stored.key.expires.map_or(false, |at| at <= now) {
+            return Some(Err("Expired API key"));
+        }
+        Some(Ok(Identity {
+            name: stored.key.name.clone(),
+            roles: stored.key.roles.clone(),
+        }))
+    }
+
+    pub fn list(&self) -> Vec<ManagedKey> {
+        let mut all: Vec<ManagedKey> = self
+            .keys
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| s.key.clone())
+            .collect();
+        all.sort_by_key(|k| k.created);
+        all
+    }
+
+    pub fn create(&self, new: NewKey) -> Issued {
+        let key = random_id();
+        let info = ManagedKey {
+            id: random_id(),
+            name: new.name,
+            roles: new.roles,
+            created: Utc::now(),
+            expires: new.expires,
+            metadata: new.metadata,
+        };
+        let stored = Stored {
+            hash: hash(&key),
+            key: info.clone(),
+        };
+        self.keys.lock().unwrap().insert(info.id.clone(), stored);
+        self.save();
+        Issued { info, key }
+    }
+
+    /// Replaces the key of `id`, the old one is refused at once
+    pub fn rotate(&self, id: &str) -> Option<Issued> {
+        let key = random_id();
+        let info = {
+            let mut keys = self.keys.lock().unwrap();
+            let stored = keys.get_mut(id)?;
+            stored.hash = hash(&key);
+            stored.key.clone()
+        };
+        self.save();
+        Some(Issued { info, key })
+    }
+
+    /// Name of the revoked key, `None` if there was none with `id`
+    pub fn revoke(&self, id: &str) -> Option<String> {
+        let removed = self.keys.lock().unwrap().remove(id)?;
+        self.save();
+        Some(removed.key.name)
+    }
+
+    /// Writes the keys to `file`, after every change
+    fn save(&self) {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return,
+        };
+        let _saving = self.saving.lock().unwrap();
+        let keys: Vec<Stored> = self.keys.lock().unwrap().values().cloned().collect();
+        let json = serde_json::to_vec_pretty(&keys).unwrap_or_default();
+        if let Err(e) = write_private(path, &json) {
+            warn!("Could not save API keys to {}: {:?}", path, e);
+        }
+    }
+}
+
+/// Replaces `path` by a file only the owner can read, written next to it first so a
+/// crash leaves either the old or the new keys
+fn write_private(path: &str, contents: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+pub async fn list(store: web::Data<KeyStore>) -> HttpResponse {
+    HttpResponse::Ok().json(store.list())
+}
+
+pub async fn create(data: web::Json<NewKey>, store: web::Data<KeyStore>) -> HttpResponse {
+    HttpResponse::Created().json(store.create(data.into_inner()))
+}
+
+/// Browser sessions opened with the old key are ended too
+pub async fn rotate(
+    id: web::Path<String>,
+    store: web::Data<KeyStore>,
+    sessions: web::Data<Sessions>,
+) -> HttpResponse {
+    match store.rotate(&id) {
+        Some(issued) => {
+            sessions.close_all(&issued.info.name);
+            HttpResponse::Ok().json(issued)
+        }
+        None => json_error(StatusCode::NOT_FOUND, format!("No API key {}", id)),
+    }
+}
+
+/// Browser sessions opened with the key are ended too
+pub async fn revoke(
+    id: web::Path<String>,
+    store: web::Data<KeyStore>,
+    sessions: web::Data<Sessions>,
+) -> HttpResponse {
+    match store.revoke(&id) {
+        Some(name) => {
+            sessions.close_all(&name);
+            HttpResponse::NoContent().finish()
+        }
+        None => json_error(StatusCode::NOT_FOUND, format!("No API key {}", id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn new_key(expires: Option<DateTime<Utc>>) -> NewKey {
+        NewKey {
+            name: "partner".into(),
+            roles: default_roles(),
+            expires,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn manages_key_lifecycle() {
+        let store = KeyStore::load(None).unwrap();
+        let now = Utc::now();
+        let issued = store.create(new_key(None));
+
+        assert_eq!(
+            store.identify(&issued.key, now).unwrap().map(|i| i.name),
+            Ok("partner".into())
+        );
+        assert!(store.identify("guess", now).is_none());
+
+        let rotated = store.rotate(&issued.info.id).unwrap();
+        assert!(store.identify(&issued.key, now).is_none());
+        assert!(store.identify(&rotated.key, now).is_some());
+
+        assert_eq!(store.revoke(&issued.info.id), Some("partner".into()));
+        assert!(store.identify(&rotated.key, now).is_none());
+        assert!(store.is_empty());
+
+        let expiring = store.create(new_key(Some(now + Duration::hours(1))));
+        assert_eq!(
+            store.identify(&expiring.key, now + Duration::hours(2)),
+            Some(Err("Expired API key"))
+        );
+    }
+
+    #[test]
+    fn saves_keys_privately_and_refuses_broken_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("rtp-keys-{}.json", random_id()));
+        let path = path.to_str().unwrap().to_string();
+        let store = KeyStore::load(Some(path.clone())).unwrap();
+        let issued = store.create(new_key(None));
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let reloaded = KeyStore::load(Some(path.clone())).unwrap();
+        assert!(reloaded.identify(&issued.key, Utc::now()).is_some());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(KeyStore::load(Some(path.clone())).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,480 @@
+//! `json_limit` caps request bodies (4 KiB), `json_limits` overrides it per route
+//! (`/compute` takes 1 KiB, `/compute/batch` 256 KiB). Larger bodies get `413` naming the
+//! limit. Before parsing, JSON bodies nested deeper than `json_max_depth` (32), with
+//! strings over `json_max_string` bytes (4096) or objects of more than `json_max_fields`
+//! fields (64) get `400`.
+//!
+//! With `max_in_flight` set, requests beyond it wait in a queue of `max_queue`, once that
+//! is full they get `503` with `Retry-After`. Heads above `max_header_bytes` get `431`.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{Error, HttpMessage};
+use futures::channel::oneshot;
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::config::Settings;
+use crate::errors::json_error;
+use crate::reload::Live;
+use crate::signature;
+use crate::stats::Stats;
+
+/// Seconds rejected clients are asked to wait
+const RETRY_AFTER: u64 = 1;
+
+/// Caps requests in flight, letting up to `queue` more wait for a free slot
+pub struct Limiter {
+    max: usize,
+    queue: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    waiting: VecDeque<oneshot::Sender<()>>,
+}
+
+enum Entry {
+    Admitted,
+    Queued(oneshot::Receiver<()>),
+    Rejected,
+}
+
+impl Limiter {
+    pub fn new(max: usize, queue: usize) -> Self {
+        Limiter {
+            max,
+            queue,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn enter(&self) -> Entry {
+        let mut state = self.state.lock().unwrap();
+        state.waiting.retain(|tx| !tx.is_canceled());
+        if state.in_flight < self.max {
+            state.in_flight += 1;
+            Entry::Admitted
+        } else if state.waiting.len() < self.queue {
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(tx);
+            Entry::Queued(rx)
+        } else {
+            Entry::Rejected
+        }
+    }
+
+    /// Requests holding a slot and requests waiting for one
+    pub fn load(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        let waiting = state.waiting.iter().filter(|tx| !tx.is_canceled()).count();
+        (state.in_flight, waiting)
+    }
+
+    /// Hands the slot over to the next live waiter or frees it
+    fn leave(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(tx) = state.waiting.pop_front() {
+            if tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.in_flight -= 1;
+    }
+}
+
+/// Slot of an admitted request, released when dropped
+struct Permit(Arc<Limiter>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.leave();
+    }
+}
+
+/// Waiting request, releasing a slot handed over after it was dropped
+struct Waiting(oneshot::Receiver<()>, Arc<Limiter>);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Ok(Some(())) = self.0.try_recv() {
+            self.1.leave();
+        }
+    }
+}
+
+fn saturated() -> Error {
+    let mut resp = json_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Too many requests in flight, retry later.",
+    );
+    resp.headers_mut()
+        .insert(header::RETRY_AFTER, header::HeaderValue::from(RETRY_AFTER));
+    InternalError::from_response("saturated", resp).into()
+}
+
+/// Bytes of the request line and headers as sent, give or take separators
+pub fn head_size(req: &ServiceRequest) -> usize {
+    let line = req.method().as_str().len() + req.uri().to_string().len();
+    req.headers()
+        .iter()
+        .fold(line, |n, (k, v)| n + k.as_str().len() + v.len() + 4)
+}
+
+/// Error for requests whose head exceeds `limit` bytes
+pub fn head_too_large(limit: usize) -> Error {
+    let resp = json_error(
+        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        format!(
+            "Request headers are larger than the limit of {} bytes",
+            limit
+        ),
+    );
+    InternalError::from_response("head too large", resp).into()
+}
+
+/// Bounds on the structure of JSON bodies, checked before they are parsed
+#[derive(Debug, Clone, Copy)]
+pub struct JsonShape {
+    pub max_depth: usize,
+    pub max_string: usize,
+    pub max_fields: usize,
+}
+
+impl JsonShape {
+    /// Scans `body` without building it, naming the first limit it breaks.
+    /// Malformed documents pass, the parser rejects them.
+    pub fn check(&self, body: &[u8]) -> Result<(), String> {
+        // fields of each open container, `None` for arrays
+        let mut open: Vec<Option<usize>> = vec![];
+        let mut string: Option<usize> = None;
+        let mut escaped = false;
+        for &byte in body {
+            if let Some(len) = string.as_mut() {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => {
+                        string = None;
+                        continue;
+                    }
+                    _ => {}
+                }
+                *len += 1;
+                if *len > self.max_string {
+                    return Err(format!(
+                        "JSON string longer than the limit of {} bytes",
+                        self.max_string
+                    ));
+                }
+                continue;
+            }
+            match byte {
+                b'"' => string = Some(0),
+                b'{' | b'[' => {
+                    open.push(if byte == b'{' { Some(0) } else { None });
+                    if open.len() > self.max_depth {
+                        return Err(format!(
+                            "JSON nested deeper than the limit of {} levels",
+                            self.max_depth
+                        ));
+                    }
+                }
+                b'}' | b']' => {
+                    open.pop();
+                }
+                b':' => {
+                    if let Some(Some(fields)) = open.last_mut() {
+                        *fields += 1;
+                        if *fields > self.max_fields {
+                            return Err(format!(
+                                "JSON object with more than the limit of {} fields",
+                                self.max_fields
+                            ));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `*/json` and `*/*+json` types, in any case and with any parameters, as the `Json`
+/// extractor accepts them
+fn is_json(req: &ServiceRequest) -> bool {
+    match req.mime_type() {
+        Ok(Some(mime)) => mime.subtype() == "json" || mime.suffix().map_or(false, |s| s == "json"),
+        _ => false,
+    }
+}
+
+/// `json_limit_of` the route, whose paths are given within `/v1`, as last reloaded
+pub fn body_limit(req: &ServiceRequest) -> usize {
+    let path = req.path();
+    let route = path.strip_prefix("/v1").unwrap_or(path);
+    if let Some(live) = req.app_data::<Live>() {
+        return live.json_limit_of(route);
+    }
+    req.app_data::<Settings>()
+        .map_or(signature::MAX_BODY, |settings| {
+            settings.json_limit_of(route)
+        })
+}
+
+fn malformed(status: StatusCode, kind: &str, message: String, req: &ServiceRequest) -> Error {
+    if let Some(stats) = req.app_data::<Stats>() {
+        stats.record_error(kind);
+    }
+    let resp = json_error(status, message.clone());
+    InternalError::from_response(message, resp).into()
+}
+
+/// Middleware rejecting JSON bodies breaking the `JsonShape` with 400
+pub struct JsonGuard(pub JsonShape);
+
+impl<S, B> Transform<S> for JsonGuard
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = JsonGuardMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(JsonGuardMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            shape: self.0,
+        })
+    }
+}
+
+pub struct JsonGuardMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    shape: JsonShape,
+}
+
+impl<S, B> Service for JsonGuardMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        if !is_json(&req) {
+            return Box::pin(self.service.borrow_mut().call(req));
+        }
+        let service = self.service.clone();
+        let shape = self.shape;
+        let limit = body_limit(&req);
+
+        Box::pin(async move {
+            let body = match signature::read_body(&mut req, limit).await {
+                Ok(Some(body)) => body,
+                Ok(None) => {
+                    let message = format!("Payload is larger than the limit of {} bytes", limit);
+                    let status = StatusCode::PAYLOAD_TOO_LARGE;
+                    return Err(malformed(status, "payload_too_large", message, &req));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if let Err(message) = shape.check(&body) {
+                return Err(malformed(
+                    StatusCode::BAD_REQUEST,
+                    "json_shape",
+                    message,
+                    &req,
+                ));
+            }
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+/// Middleware answering 503 with `Retry-After` once the limiter is saturated
+pub struct ConcurrencyLimit(pub Arc<Limiter>);
+
+impl<S, B> Transform<S> for ConcurrencyLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ConcurrencyLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ConcurrencyLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            limiter: self.0.clone(),
+        })
+    }
+}
+
+pub struct ConcurrencyLimitMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    limiter: Arc<Limiter>,
+}
+
+impl<S, B> Service for ConcurrencyLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            match limiter.enter() {
+                Entry::Admitted => {}
+                Entry::Queued(rx) => {
+                    let mut waiting = Waiting(rx, limiter.clone());
+                    (&mut waiting.0).await.map_err(|_| saturated())?
+                }
+                Entry::Rejected => return Err(saturated()),
+            }
+            let _permit = Permit(limiter);
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_json_shape() {
+        let shape = JsonShape {
+            max_depth: 2,
+            max_string: 4,
+            max_fields: 2,
+        };
+
+        assert!(shape.check(br#"{"a": [1, {"b": "x:{"}]}"#).is_err());
+        assert!(shape.check(br#"{"a": [1, 2], "b": "four"}"#).is_ok());
+        assert!(shape.check(br#"{"a": "\"[["}"#).is_ok());
+        assert!(shape.check(br#"{"a": "fives"}"#).is_err());
+        assert!(shape.check(br#"{"a": 1, "b": 2, "c": 3}"#).is_err());
+        assert!(shape
+            .check(br#"[{"a": 1, "b": 2}, {"a": 1, "b": 2}]"#)
+            .is_ok());
+    }
+
+    #[test]
+    fn guards_json_types_with_route_limits() {
+        use actix_web::test::TestRequest;
+        use actix_web::web;
+
+        let typed =
+            |value: &str| TestRequest::with_header(header::CONTENT_TYPE, value).to_srv_request();
+        assert!(is_json(&typed("application/json")));
+        assert!(is_json(&typed("Application/JSON; charset=utf-8")));
+        assert!(is_json(&typed("application/problem+json")));
+        assert!(is_json(&typed("text/json")));
+        assert!(is_json(&typed("text/vnd.api+json")));
+        assert!(!is_json(&typed("application/jsonl")));
+        assert!(!is_json(&typed("text/plain")));
+
+        let req = TestRequest::with_uri("/v1/compute/batch")
+            .app_data(web::Data::new(Settings::default()))
+            .to_srv_request();
+        assert_eq!(body_limit(&req), 256 * 1024);
+    }
+
+    #[actix_rt::test]
+    async fn names_the_limit_of_large_payloads() {
+        use actix_web::{test, web, App, HttpResponse, ResponseError};
+
+        let settings = Settings {
+            json_limit: 16,
+            ..Settings::default()
+        };
+        let mut app = test::init_service(
+            App::new()
+                .wrap(JsonGuard(settings.json_shape()))
+                .data(settings)
+                .route(
+                    "/",
+                    web::post().to(|| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .header(header::CONTENT_TYPE, "text/json")
+            .set_payload(r#"{"d": 3.7, "e": 5, "f": 2}"#)
+            .to_request();
+        let e = match app.call(req).await {
+            Err(e) => e,
+            Ok(_) => panic!("Payload was accepted"),
+        };
+
+        assert_eq!(
+            e.as_response_error().status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            e.to_string(),
+            "Payload is larger than the limit of 16 bytes"
+        );
+    }
+
+    #[test]
+    fn queues_then_rejects() {
+        let limiter = Arc::new(Limiter::new(1, 1));
+
+        assert!(matches!(limiter.enter(), Entry::Admitted));
+        let mut queued = match limiter.enter() {
+            Entry::Queued(rx) => rx,
+            _ => panic!("expected a queued entry"),
+        };
+        assert!(matches!(limiter.enter(), Entry::Rejected));
+
+        limiter.leave();
+        assert_eq!(queued.try_recv(), Ok(Some(())));
+        limiter.leave();
+        assert!(matches!(limiter.enter(), Entry::Admitted));
+    }
+}
@@ -0,0 +1,132 @@
+//! With `[lockout]` set, `max_failures` (5) failed authentications within `window` seconds
+//! (300) from one client address refuse its credentials unchecked for `duration` seconds
+//! (900) with `429`. Failures count as `auth_failed` and lockouts as `auth_locked_out` in
+//! `/stats`, both are written to the audit log. A successful authentication resets the
+//! count of its address.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+/// Subjects tracked before ones without recent failures are forgotten
+const MAX_SUBJECTS: usize = 10_000;
+
+/// How many failed authentications lock a client address out
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LockoutSettings {
+    /// Failures within `window` that trigger a lockout
+    pub max_failures: u32,
+    /// Seconds failures are counted over
+    pub window: u64,
+    /// Seconds a locked out subject is refused
+    pub duration: u64,
+}
+
+impl Default for LockoutSettings {
+    fn default() -> Self {
+        LockoutSettings {
+            max_failures: 5,
+            window: 300,
+            duration: 900,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    count: u32,
+    since: i64,
+    locked_until: i64,
+}
+
+/// Failed authentications per subject, e.g. `ip 203.0.113.7`
+pub struct Lockout {
+    settings: LockoutSettings,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl Lockout {
+    pub fn new(settings: &LockoutSettings) -> Self {
+        Lockout {
+            settings: settings.clone(),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Seconds `subject` stays locked out at `now`, `None` if it is not
+    pub fn locked(&self, subject: &str, now: i64) -> Option<u64> {
+        let failures = self.failures.lock().unwrap();
+        failures
+            .get(subject)
+            .filter(|f| f.locked_until > now)
+            .map(|f| (f.locked_until - now) as u64)
+    }
+
+    /// Counts a failure of `subject`, locking it out once `max_failures` fall in a window
+    pub fn fail(&self, subject: &str, now: i64) {
+        let (window, duration) = (self.settings.window as i64, self.settings.duration as i64);
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_SUBJECTS {
+            failures.retain(|_, f| f.locked_until > now || f.since + window > now);
+        }
+        let entry = failures.entry(subject.to_string()).or_default();
+        if entry.since + window <= now {
+            entry.count = 0;
+            entry.since = now;
+        }
+        entry.count += 1;
+        if entry.count >= self.settings.max_failures && entry.locked_until <= now {
+            entry.locked_until = now + duration;
+            warn!(
+                "Locking out {} for {}s after {} failed authentications",
+                subject, duration, entry.count
+            );
+        }
+    }
+
+    /// Forgets the failures of `subject` once it authenticated
+    pub fn clear(&self, subject: &str) {
+        self.failures.lock().unwrap().remove(subject);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_out_after_repeated_failures() {
+        let lockout = Lockout::new(&LockoutSettings {
+            max_failures: 3,
+            window: 60,
+            duration: 600,
+        });
+
+        lockout.fail("ip 10.0.0.1", 1000);
+        lockout.fail("ip 10.0.0.1", 1070);
+        lockout.fail("ip 10.0.0.1", 1080);
+        assert_eq!(lockout.locked("ip 10.0.0.1", 1080), None);
+
+        lockout.fail("ip 10.0.0.1", 1090);
+        assert_eq!(lockout.locked("ip 10.0.0.1", 1090), Some(600));
+        assert_eq!(lockout.locked("ip 10.0.0.1", 1690), None);
+        assert_eq!(lockout.locked("ip 10.0.0.2", 1090), None);
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let lockout = Lockout::new(&LockoutSettings {
+            max_failures: 2,
+            window: 60,
+            duration: 600,
+        });
+
+        lockout.fail("ip 10.0.0.1", 1000);
+        lockout.clear("ip 10.0.0.1");
+        lockout.fail("ip 10.0.0.1", 1010);
+        assert_eq!(lockout.locked("ip 10.0.0.1", 1010), None);
+    }
+}
@@ -0,0 +1,473 @@
+//! Log verbosity can be changed at runtime:
+//!
+//! ``` curl -H "Content-Type: application/json" -X PUT -d '{"filter": "debug"}' localhost:3030/admin/loglevel ```
+//!
+//! `log_format = "json"` writes one JSON object per line, `log_format = "logfmt"` one line
+//! of `key=value` pairs starting with `ts`, `level` and `target`.
+//!
+//! `[access_log]` picks the access log layout, `format` is `combined` (the default), `json`
+//! or an actix `Logger` format string. `json` lines are objects with the `client`,
+//! `identity`, `request` line, `status`, `bytes`, `duration_s` and `request_id`. Paths in
+//! `exclude`, e.g. `/health` and `/readyz`, are not logged.
+
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, BodySize, MessageBody, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Logger;
+use actix_web::{error, web, Error, HttpResponse};
+use chrono::{SecondsFormat, Utc};
+use futures::future::{ok, LocalBoxFuture};
+use log::info;
+use opentelemetry::sdk::trace::Tracer;
+use serde_derive::{Deserialize, Serialize};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+use crate::auth::IDENTITY_HEADER;
+use crate::proxy::{self, CLIENT_IP_HEADER};
+use crate::redact::REQUEST_LINE_HEADER;
+use crate::request_id::REQUEST_ID_HEADER;
+
+/// Target access log lines are logged with, `Logger`'s, so filters apply to both layouts
+const ACCESS_LOG_TARGET: &str = "actix_web::middleware::logger";
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, with the fields of the enclosing spans
+    Json,
+    /// `key=value` pairs per line, with the fields of the enclosing spans
+    Logfmt,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+/// Layout of access log lines and requests left out of them
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessLogSettings {
+    /// `combined`, `json` or a `Logger` format string of its own, e.g. `%a "%r" %s %T`
+    pub format: String,
+    /// Paths not logged, e.g. `/health` and `/readyz` polled by orchestrators
+    pub exclude: Vec<String>,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        AccessLogSettings {
+            format: "combined".into(),
+            exclude: vec![],
+        }
+    }
+}
+
+impl AccessLogSettings {
+    fn template(&self) -> &str {
+        match self.format.as_str() {
+            "combined" => proxy::LOG_FORMAT,
+            custom => custom,
+        }
+    }
+
+    fn logger(&self) -> Logger {
+        self.exclude
+            .iter()
+            .fold(Logger::new(self.template()), |logger, path| {
+                logger.exclude(path.as_str())
+            })
+    }
+}
+
+/// `json` access log line, the fields as in `proxy::LOG_FORMAT`
+#[derive(Debug, Serialize)]
+struct AccessRecord {
+    client: Option<String>,
+    identity: Option<String>,
+    request: Option<String>,
+    status: u16,
+    /// Unknown for streamed bodies
+    bytes: Option<u64>,
+    duration_s: f64,
+    request_id: Option<String>,
+}
+
+impl AccessRecord {
+    fn of(req: &ServiceRequest) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        AccessRecord {
+            client: header(CLIENT_IP_HEADER),
+            identity: header(IDENTITY_HEADER),
+            request: header(REQUEST_LINE_HEADER),
+            status: 0,
+            bytes: None,
+            duration_s: 0.0,
+            request_id: header(REQUEST_ID_HEADER),
+        }
+    }
+
+    fn log(mut self, result: &Result<ServiceResponse<impl MessageBody>, Error>, started: Instant) {
+        match result {
+            Ok(res) => {
+                self.status = res.status().as_u16();
+                self.bytes = match res.response().body().size() {
+                    BodySize::Empty | BodySize::None => Some(0),
+                    BodySize::Sized(n) => Some(n as u64),
+                    BodySize::Sized64(n) => Some(n),
+                    BodySize::Stream => None,
+                };
+            }
+            Err(e) => self.status = e.as_response_error().status_code().as_u16(),
+        }
+        self.duration_s = started.elapsed().as_secs_f64();
+        match serde_json::to_string(&self) {
+            Ok(line) => info!(target: ACCESS_LOG_TARGET, "{}", line),
+            Err(e) => info!(target: ACCESS_LOG_TARGET, "Could not log request: {}", e),
+        }
+    }
+}
+
+fn boxed<B: MessageBody + 'static>(res: ServiceResponse<B>) -> ServiceResponse<Body> {
+    res.map_body(|_, body| ResponseBody::Body(Body::from_message(body)))
+}
+
+/// Middleware writing the access log, through actix's `Logger` for `combined` and custom
+/// templates, and as JSON serialized here for `json`, so values are escaped
+pub struct AccessLog(pub AccessLogSettings);
+
+impl<S, B> Transform<S> for AccessLog
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLogMiddleware<S, <Logger as Transform<S>>::Transform>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        if self.0.format == "json" {
+            return Box::pin(ok(AccessLogMiddleware::Json {
+                service,
+                exclude: self.0.exclude.clone(),
+            }));
+        }
+        let logger = self.0.logger().new_transform(service);
+        Box::pin(async move { Ok(AccessLogMiddleware::Logger(logger.await?)) })
+    }
+}
+
+pub enum AccessLogMiddleware<S, L> {
+    Logger(L),
+    Json { service: S, exclude: Vec<String> },
+}
+
+impl<S, B, L, LB> Service for AccessLogMiddleware<S, L>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+    L: Service<Request = ServiceRequest, Response = ServiceResponse<LB>, Error = Error>,
+    L::Future: 'static,
+    LB: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        match self {
+            AccessLogMiddleware::Logger(logger) => logger.poll_ready(cx),
+            AccessLogMiddleware::Json { service, .. } => service.poll_ready(cx),
+        }
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let (service, exclude) = match self {
+            AccessLogMiddleware::Logger(logger) => {
+                let fut = logger.call(req);
+                return Box::pin(async move { fut.await.map(boxed) });
+            }
+            AccessLogMiddleware::Json { service, exclude } => (service, exclude),
+        };
+        let record = if exclude.iter().any(|path| path == req.path()) {
+            None
+        } else {
+            Some(AccessRecord::of(&req))
+        };
+        let started = Instant::now();
+        let fut = service.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+            if let Some(record) = record {
+                record.log(&result, started);
+            }
+            result.map(boxed)
+        })
+    }
+}
+
+/// Writes `value` as a logfmt value, quoted if it is empty or holds spaces, `=` or `"`
+fn logfmt_value(out: &mut String, value: &str) {
+    let quote = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c <= ' ' || c == '=' || c == '"' || c == '\\');
+    if !quote {
+        out.push_str(value);
+        return;
+    }
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Collects fields as logfmt pairs. `message` becomes `msg`, the target of `log` records
+/// is kept apart so it replaces the one of the bridge.
+#[derive(Default)]
+struct Pairs {
+    line: String,
+    log_target: Option<String>,
+}
+
+impl Pairs {
+    fn pair(&mut self, name: &str, value: &str) {
+        let name = match name {
+            "message" => "msg",
+            "log.target" => {
+                self.log_target = Some(value.to_string());
+                return;
+            }
+            name if name.starts_with("log.") => return,
+            name => name,
+        };
+        if !self.line.is_empty() {
+            self.line.push(' ');
+        }
+        self.line.push_str(name);
+        self.line.push('=');
+        logfmt_value(&mut self.line, value);
+    }
+}
+
+impl Visit for Pairs {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.pair(field.name(), value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.pair(field.name(), &format!("{:?}", value));
+    }
+}
+
+/// logfmt lines: `ts`, `level`, `target`, the fields of the enclosing spans, outermost
+/// first, then those of the event
+pub struct Logfmt;
+
+impl<'writer> FormatFields<'writer> for Logfmt {
+    fn format_fields<R: RecordFields>(
+        &self,
+        writer: &'writer mut dyn std::fmt::Write,
+        fields: R,
+    ) -> std::fmt::Result {
+        let mut pairs = Pairs::default();
+        fields.record(&mut pairs);
+        writer.write_str(&pairs.line)
+    }
+}
+
+impl<S> FormatEvent<S, Logfmt> for Logfmt
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, Logfmt>,
+        writer: &mut dyn std::fmt::Write,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let mut pairs = Pairs::default();
+        event.record(&mut pairs);
+        let meta = event.metadata();
+
+        let mut line = String::from("ts=");
+        line.push_str(&Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true));
+        line.push_str(" level=");
+        line.push_str(&meta.level().to_string().to_lowercase());
+        line.push_str(" target=");
+        logfmt_value(
+            &mut line,
+            pairs.log_target.as_deref().unwrap_or(meta.target()),
+        );
+        ctx.visit_spans(|span| {
+            let extensions = span.extensions();
+            match extensions.get::<FormattedFields<Logfmt>>() {
+                Some(fields) if !fields.is_empty() => write!(line, " {}", fields.as_str()),
+                _ => Ok(()),
+            }
+        })?;
+        if !pairs.line.is_empty() {
+            line.push(' ');
+            line.push_str(&pairs.line);
+        }
+        writeln!(writer, "{}", line)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogLevel {
+    /// `RUST_LOG` style directives, e.g. `debug` or `info,actix_web=warn`
+    pub filter: String,
+}
+
+/// Handle to change the log filter of the running process
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    current: Mutex<String>,
+}
+
+impl LogControl {
+    pub fn set(&self, filter: &str) -> Result<(), Error> {
+        let parsed = EnvFilter::try_new(filter)
+            .map_err(|e| error::ErrorBadRequest(format!("Wrong filter: {}", e)))?;
+        self.handle
+            .reload(parsed)
+            .map_err(error::ErrorInternalServerError)?;
+        *self.current.lock().unwrap() = filter.to_string();
+        Ok(())
+    }
+}
+
+/// Installs the global subscriber writing `format`, also capturing `log` records, and
+/// exporting spans to `tracer` if given. `RUST_LOG` takes precedence over the configured
+/// `default` filter.
+pub fn init(default: &str, format: LogFormat, tracer: Option<Tracer>) -> LogControl {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| default.to_string());
+    let (layer, handle) = reload::Layer::new(EnvFilter::new(&filter));
+
+    let registry = tracing_subscriber::registry()
+        .with(layer)
+        .with(tracer.map(|t| tracing_opentelemetry::layer().with_tracer(t)));
+    match format {
+        LogFormat::Text => registry.with(fmt::layer()).init(),
+        LogFormat::Json => registry.with(fmt::layer().json()).init(),
+        LogFormat::Logfmt => registry
+            .with(fmt::layer().event_format(Logfmt).fmt_fields(Logfmt))
+            .init(),
+    }
+
+    LogControl {
+        handle,
+        current: Mutex::new(filter),
+    }
+}
+
+pub async fn get(control: web::Data<LogControl>) -> HttpResponse {
+    HttpResponse::Ok().json(LogLevel {
+        filter: control.current.lock().unwrap().clone(),
+    })
+}
+
+pub async fn put(
+    data: web::Json<LogLevel>,
+    control: web::Data<LogControl>,
+) -> Result<HttpResponse, Error> {
+    control.set(&data.filter)?;
+    Ok(HttpResponse::Ok().json(data.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reloads_filter() {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("error"));
+        let _subscriber = tracing_subscriber::registry().with(layer);
+        let control = LogControl {
+            handle,
+            current: Mutex::new("error".into()),
+        };
+
+        assert!(control.set("debug").is_ok());
+        assert_eq!(*control.current.lock().unwrap(), "debug");
+        assert!(control.set("foo=notalevel").is_err());
+    }
+
+    #[test]
+    fn picks_access_log_template() {
+        let mut settings = AccessLogSettings::default();
+        assert_eq!(settings.template(), proxy::LOG_FORMAT);
+
+        settings.format = r#"%a "%r" %s"#.into();
+        assert_eq!(settings.template(), r#"%a "%r" %s"#);
+    }
+
+    #[test]
+    fn escapes_json_access_records() {
+        let req = actix_web::test::TestRequest::default()
+            .header(REQUEST_LINE_HEADER, r#"GET /v1/results/"x\y HTTP/1.1"#)
+            .header(IDENTITY_HEADER, "partner")
+            .to_srv_request();
+        let line = serde_json::to_string(&AccessRecord::of(&req)).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(record["request"], r#"GET /v1/results/"x\y HTTP/1.1"#);
+        assert_eq!(record["identity"], "partner");
+        assert!(record["client"].is_null());
+    }
+
+    #[test]
+    fn writes_logfmt_pairs() {
+        let mut pairs = Pairs::default();
+        pairs.pair("message", "Delivered schedule run");
+        pairs.pair("log.target", "rest_test_params::schedules");
+        pairs.pair("log.line", "42");
+        pairs.pair("route", "/v1/compute");
+        pairs.pair("request", "");
+        pairs.pair("body", r#"{"d": "x\y"}"#);
+
+        assert_eq!(
+            pairs.line,
+            r#"msg="Delivered schedule run" route=/v1/compute request="" body="{\"d\": \"x\\y\"}""#
+        );
+        assert_eq!(
+            pairs.log_target.as_deref(),
+            Some("rest_test_params::schedules")
+        );
+    }
+}
@@ -48,9 +48,12 @@
 //!
 //! ``` RUST_LOG=info cargo run```
 //!
+//! Settings are described on `config::Settings` and in `server.toml`, every feature in the
+//! module implementing it.
+//!
 //! # Test:
 //!
-//! ``` curl -H "Content-Type: application/json" -X POST -d '{"a":true,"b":true, "c": true, "d": 4.7, "e": 5, "f": 2, "case": "C1"}' localhost:3030/compute ```
+//! ``` curl -H "Content-Type: application/json" -X POST -d '{"a":true,"b":true, "c": true, "d": 4.7, "e": 5, "f": 2, "case": "C1"}' localhost:3030/v1/compute ```
 //! 
 //! ## Web framework of choice:
 //! Actix has testing utilities included so it is a convenient choice.
@@ -65,13 +68,137 @@
 //!
 
 
-use anyhow::{anyhow, Result};
-use log::warn;
+use anyhow::Result;
+use futures::future::{self, Either};
+use listenfd::ListenFd;
+use log::{info, warn};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+mod access;
+mod alerts;
+mod assets;
+mod audit;
+mod auth;
+mod breaker;
+mod capture;
+mod chaos;
+mod check;
+mod cli;
+mod config;
+mod consul;
+mod dashboard;
+mod deadletter;
+mod dedup;
+mod deprecation;
+mod envelope;
+mod errors;
+mod etag;
+mod examples;
+mod features;
+mod health;
+mod heartbeat;
+mod i18n;
+mod idempotency;
+mod introspection;
+mod jwe;
+mod jws;
+mod jwt;
+mod keys;
+mod limit;
+mod lockout;
+mod logging;
+mod maintenance;
+mod metering;
+mod mirror;
+mod pidfile;
+mod pretty;
+mod process;
+mod proxy;
+mod quota;
+mod ratelimit;
+mod redact;
+mod reload;
+mod request_id;
+mod requirements;
+mod results;
+mod schedules;
+mod schema;
+mod security;
+mod selftest;
+mod session;
+mod shutdown;
+mod signature;
+mod slow;
+mod stats;
+mod telemetry;
+mod templates;
+mod tenants;
+mod timeout;
+mod timing;
+mod tls;
+mod trace;
+mod transform;
 mod types;
+mod vault;
+use access::{AccessControl, IpAccess};
+use alerts::{ErrorMonitor, FailedShapes, WatchErrors};
+use audit::{Audit, AuditLog};
+use auth::{Authenticate, Authenticator, Caller, RequireAuth};
+use breaker::Breakers;
+use chaos::Chaos;
+use config::Settings;
+use deadletter::{DeadLetters, Work};
+use dedup::{Dedup, DedupWindow};
+use deprecation::DeprecationHeaders;
+use envelope::Enveloping;
+use examples::ExampleQuery;
+use features::{FeatureGate, Features};
+use health::Readiness;
+use i18n::{Fault, Lang};
+use idempotency::{Idempotency, IdempotencyStore};
+use introspection::Introspector;
+use jwe::{Decrypter, Encryption};
+use jws::{ResponseSigner, SignResponses};
+use jwt::Jwks;
+use keys::KeyStore;
+use limit::{ConcurrencyLimit, JsonGuard, Limiter};
+use lockout::Lockout;
+use logging::{AccessLog, LogControl};
+use maintenance::{Maintenance, MaintenanceGuard};
+use metering::{Meter, Metering};
+use mirror::{Mirror, MirrorTraffic};
+use pidfile::PidFile;
+use pretty::PrettyJson;
+use process::Process;
+use proxy::TrustedProxies;
+use quota::Quotas;
+use ratelimit::{IpRateLimit, RateLimiter};
+use reload::{Live, Reloader};
+use request_id::{AssignRequestId, TagErrors};
+use results::ResultStore;
+use schedules::Schedules;
+use security::SecurityHeaders;
+use session::Sessions;
+use signature::Signatures;
+use slow::{LoggedParams, SlowRequests};
+use stats::{Outcome, Outcomes, Stats, StatsRecorder};
+use templates::{ResponseTemplates, Templating};
+use timeout::Timeout;
+use timing::{RequestStart, Timings};
+use tls::CertStore;
+use trace::RequestSpan;
+use transform::JsonTransform;
 use types::*;
 
-use actix_web::{error, middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_service::Service;
+use actix_web::dev::Server;
+use actix_web::http::{self, header};
+use actix_web::{
+    error, middleware, web, App, Error, HttpMessage, HttpRequest, HttpResponse, HttpServer,
+};
 
 async fn help() -> HttpResponse {
     HttpResponse::Ok().json(format!(
@@ -85,72 +212,986 @@ async fn index() -> HttpResponse {
     HttpResponse::Ok().json("You are asking my help, doing so without parameters...")
 }
 
+/// Returns a random payload that is valid for the requested case and H
+async fn example(query: web::Query<ExampleQuery>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    match examples::generate(&query) {
+        Some(params) => Ok(HttpResponse::Ok().json(params)),
+        None => Err(error::ErrorBadRequest(
+            Fault::NoExample.message(Lang::of(&req)),
+        )),
+    }
+}
+
+/// Warns clients relying on the default case, which turns into an error with `require_case`
+fn missing_case_warning(default: &Case) -> header::HeaderValue {
+    let warning = format!(
+        r#"299 - "Missing case defaults to {:?}, this will be rejected in a future release""#,
+        default
+    );
+    header::HeaderValue::from_str(&warning)
+        .unwrap_or_else(|_| header::HeaderValue::from_static("299"))
+}
+
+/// Code of failed computations that are not a `Fault` of the params
+const INTERNAL: &str = "internal";
+
+/// K beyond the range of `f64`, which the engine gets to with huge D and is a server
+/// fault rather than one of the params
+#[derive(Debug)]
+struct Overflow;
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "K is out of range")
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+fn finite(output: Output) -> Result<Output> {
+    if output.k.is_finite() {
+        Ok(output)
+    } else {
+        Err(Overflow.into())
+    }
+}
+
+/// Preflight for `/compute`: supported methods and accepted body format
+async fn compute_options() -> HttpResponse {
+    HttpResponse::NoContent()
+        .header(header::ALLOW, "POST, OPTIONS")
+        .header("Accept-Post", "application/json")
+        .finish()
+}
+
 /// This handler uses json extractor with limit
 async fn compute_factory(
     data: web::Json<Params>,
-    _req: HttpRequest,
+    settings: web::Data<Settings>,
+    results: web::Data<ResultStore>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
-    match compute(&data) {
-        Ok(a) => Ok(HttpResponse::Ok().json(a)),
+    let started = Instant::now();
+
+    let mut timings = Timings::default();
+    if let Some(RequestStart(at)) = req.extensions().get::<RequestStart>() {
+        timings.record("deserialize", started.duration_since(*at));
+    }
+    let policy = tenants::policy(&settings, &req)?;
+    let case = data
+        .case
+        .clone()
+        .unwrap_or_else(|| policy.default_case.clone());
+    Caller::of(&req).require_case(&case)?;
+    let h = timings.measure("validate", || classify(&data, &case));
+    let result = match data.case {
+        None if policy.require_case => Err(Fault::MissingCase.into()),
+        _ => timings.measure("compute", || {
+            output(h.clone(), &data, case.clone()).and_then(finite)
+        }),
+    };
+    let outcome = Outcome::of(&case, &h, &result, timings.total("compute"));
+
+    let mut resp = match result {
+        Ok(a) => {
+            let id = results.insert(a.clone(), Caller::of(&req).owner());
+            let location = format!("/v1/results/{}", id);
+            // for conditional `GET`s of `Content-Location`, a `POST` is always computed
+            let tag = etag::of(&a);
+            let mut resp = HttpResponse::Ok().json(a);
+            if let Ok(v) = header::HeaderValue::from_str(&tag) {
+                resp.headers_mut().insert(header::ETAG, v);
+            }
+            if let Ok(v) = header::HeaderValue::from_str(&location) {
+                resp.headers_mut().insert(header::CONTENT_LOCATION, v);
+            }
+            for rule in deprecation::for_case(&settings.deprecations, &case) {
+                rule.apply(resp.headers_mut());
+            }
+            if data.case.is_none() {
+                resp.headers_mut()
+                    .insert(header::WARNING, missing_case_warning(&case));
+            }
+            if let Ok(v) = header::HeaderValue::from_str(&format!("{:?}", case)) {
+                resp.headers_mut()
+                    .insert(header::HeaderName::from_static("x-applied-case"), v);
+            }
+            resp
+        }
         Err(e) => {
-            warn!("Could not compute value: {:?}", e);
-            Err(error::ErrorBadRequest(format!("Wrong params: {:?}", data)))
+            let logged = redact::params(&*data, &settings.redact);
+            warn!("Could not compute value of {}: {:?}", logged, e);
+            let (mut resp, code): (HttpResponse, _) = match e.downcast_ref::<Fault>() {
+                Some(fault) => (
+                    error::ErrorBadRequest(fault.message(Lang::of(&req))).into(),
+                    fault.code(),
+                ),
+                None => (
+                    error::ErrorInternalServerError(e.to_string()).into(),
+                    INTERNAL,
+                ),
+            };
+            resp.headers_mut().insert(
+                header::HeaderName::from_static("x-error-code"),
+                header::HeaderValue::from_static(code),
+            );
+            resp
         }
+    };
+
+    if let Ok(v) = header::HeaderValue::from_str(&timings.header_value()) {
+        resp.headers_mut()
+            .insert(header::HeaderName::from_static("server-timing"), v);
     }
+    if settings.error_alert.is_some() && outcome.error.is_some() {
+        resp.extensions_mut()
+            .insert(FailedShapes(vec![alerts::shape(&*data)]));
+    }
+    if settings.slow_request_ms.is_some() {
+        let logged = redact::params(&*data, &settings.redact);
+        resp.extensions_mut().insert(LoggedParams(logged));
+    }
+    resp.extensions_mut().insert(timings);
+    resp.extensions_mut().insert(Outcomes(vec![outcome]));
+    Ok(resp)
+}
+
+/// Computes every item on its own, answering 207 Multi-Status if any of them failed.
+/// An optional per-item `id` is echoed back on its result:
+///
+/// ``` curl -H "Content-Type: application/json" -X POST -d '[{"id":1,"a":true,"b":true,"c":false,"d":1.5,"e":2}, {"id":2,"a":false}]' localhost:3030/v1/compute/batch ```
+async fn compute_batch(
+    data: web::Json<Vec<BatchRequest>>,
+    settings: web::Data<Settings>,
+    dead_letters: web::Data<DeadLetters>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let policy = tenants::policy(&settings, &req)?;
+    let caller = Caller::of(&req);
+    for BatchRequest { params: p, .. } in data.iter() {
+        caller.require_case(p.case.as_ref().unwrap_or(&policy.default_case))?;
+    }
+    let lang = Lang::of(&req);
+    let mut outcomes = vec![];
+    let mut timings = Timings::default();
+    let items: Vec<BatchItem> = data
+        .iter()
+        .map(|BatchRequest { id, params: p }| {
+            let case = p
+                .case
+                .clone()
+                .unwrap_or_else(|| policy.default_case.clone());
+            let h = timings.measure("validate", || classify(p, &case));
+            let computed = timings.total("compute");
+            let result = match p.case {
+                None if policy.require_case => Err(Fault::MissingCase.into()),
+                _ => timings.measure("compute", || {
+                    output(h.clone(), p, case.clone()).and_then(finite)
+                }),
+            };
+            let compute = timings.total("compute") - computed;
+            outcomes.push(Outcome::of(&case, &h, &result, compute));
+
+            match result {
+                Ok(output) => BatchItem {
+                    id: id.clone(),
+                    status: http::StatusCode::OK.as_u16(),
+                    case: Some(case),
+                    data: Some(output),
+                    error: None,
+                },
+                Err(e) => {
+                    let logged = redact::params(p, &settings.redact);
+                    warn!("Could not compute batch item {}: {:?}", logged, e);
+                    // invalid params are the client's to fix, only server faults are kept
+                    let (status, message, kind) = match e.downcast_ref::<Fault>() {
+                        Some(fault) => (
+                            http::StatusCode::BAD_REQUEST,
+                            fault.message(lang),
+                            fault.code(),
+                        ),
+                        None => {
+                            dead_letters.push(
+                                Work::BatchItem {
+                                    params: redact::value(p, &settings.redact),
+                                    default_case: policy.default_case.clone(),
+                                },
+                                e.to_string(),
+                            );
+                            (
+                                http::StatusCode::INTERNAL_SERVER_ERROR,
+                                e.to_string(),
+                                INTERNAL,
+                            )
+                        }
+                    };
+                    BatchItem {
+                        id: id.clone(),
+                        status: status.as_u16(),
+                        case: None,
+                        data: None,
+                        error: Some(ErrorMessage {
+                            code: status.as_u16(),
+                            message,
+                            kind: Some(kind),
+                        }),
+                    }
+                }
+            }
+        })
+        .collect();
+
+    let implicit_case = data.iter().any(|r| r.params.case.is_none());
+    let mut resp = if items.iter().all(|i| i.error.is_none()) {
+        HttpResponse::Ok().json(items)
+    } else {
+        HttpResponse::build(http::StatusCode::MULTI_STATUS).json(items)
+    };
+    if implicit_case && !policy.require_case {
+        resp.headers_mut()
+            .insert(header::WARNING, missing_case_warning(&policy.default_case));
+    }
+    if settings.error_alert.is_some() {
+        let shapes = data
+            .iter()
+            .zip(&outcomes)
+            .filter(|(_, o)| o.error.is_some())
+            .map(|(r, _)| alerts::shape(&r.params))
+            .collect();
+        resp.extensions_mut().insert(FailedShapes(shapes));
+    }
+    if settings.slow_request_ms.is_some() {
+        let params: Vec<&Params> = data.iter().map(|r| &r.params).collect();
+        let logged = redact::params(&params, &settings.redact);
+        resp.extensions_mut().insert(LoggedParams(logged));
+    }
+    resp.extensions_mut().insert(timings);
+    resp.extensions_mut().insert(Outcomes(outcomes));
+    Ok(resp)
+}
+
+/// Routes of the first API version.
+/// A future version with different `Output` semantics gets its own function
+/// and scope next to this one.
+/// Body sizes are checked by `JsonGuard`, which follows reloads of `json_limits`,
+/// the extractor limits are a backstop at the startup values.
+fn api_v1(cfg: &mut web::ServiceConfig, settings: &Settings) {
+    cfg.service(
+        web::resource("/compute")
+            .wrap(Timeout::of("/compute", settings))
+            .wrap(MaintenanceGuard)
+            .wrap(RequireAuth(auth::COMPUTE))
+            .data(errors::json_config(settings.json_limit_of("/compute")))
+            .route(web::post().to(compute_factory))
+            .route(web::method(http::Method::OPTIONS).to(compute_options))
+            .default_service(web::route().to(errors::method_not_allowed("POST, OPTIONS"))),
+    )
+    .service(
+        web::resource("/compute/batch")
+            .wrap(FeatureGate("batch"))
+            .wrap(Timeout::of("/compute/batch", settings))
+            .wrap(MaintenanceGuard)
+            .wrap(RequireAuth(auth::COMPUTE))
+            .data(errors::json_config(
+                settings.json_limit_of("/compute/batch"),
+            ))
+            .route(web::post().to(compute_batch))
+            .default_service(web::route().to(errors::method_not_allowed("POST"))),
+    )
+    .service(
+        trace::resource("/results/{id}")
+            .wrap(RequireAuth(auth::COMPUTE))
+            .route(web::get().to(results::get))
+            .route(web::head().to(results::get))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/schedules")
+            .wrap(FeatureGate("schedules"))
+            .wrap(Timeout::of("/schedules", settings))
+            .wrap(RequireAuth(auth::COMPUTE))
+            .data(errors::json_config(settings.json_limit_of("/schedules")))
+            .route(web::post().to(schedules::create))
+            .route(web::get().to(schedules::list))
+            .default_service(web::route().to(errors::method_not_allowed("GET, POST"))),
+    )
+    .service(
+        trace::resource("/schedules/{id}")
+            .wrap(FeatureGate("schedules"))
+            .wrap(RequireAuth(auth::COMPUTE))
+            .route(web::get().to(schedules::get))
+            .route(web::delete().to(schedules::delete))
+            .default_service(web::route().to(errors::method_not_allowed("GET, DELETE"))),
+    )
+    .service(
+        trace::resource("/cases/{case}/requirements")
+            .route(web::get().to(requirements::get))
+            .route(web::head().to(requirements::get))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/help")
+            .route(web::get().to(help))
+            .route(web::head().to(help))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/encryption-key")
+            .route(web::get().to(jwe::key))
+            .route(web::head().to(jwe::key))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/examples")
+            .route(web::get().to(example))
+            .route(web::head().to(example))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    );
+}
+
+/// Operator routes, not versioned and not affected by maintenance mode
+fn admin(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/maintenance")
+            .route(web::get().to(maintenance::get))
+            .route(web::put().to(maintenance::put))
+            .default_service(web::route().to(errors::method_not_allowed("GET, PUT"))),
+    )
+    .service(
+        web::resource("/config")
+            .route(web::get().to(reload::dump))
+            .route(web::head().to(reload::dump))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/loglevel")
+            .route(web::get().to(logging::get))
+            .route(web::put().to(logging::put))
+            .default_service(web::route().to(errors::method_not_allowed("GET, PUT"))),
+    )
+    .service(
+        web::resource("/features")
+            .route(web::get().to(features::list))
+            .route(web::head().to(features::list))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        trace::resource("/features/{name}")
+            .route(web::put().to(features::put))
+            .default_service(web::route().to(errors::method_not_allowed("PUT"))),
+    )
+    .service(
+        web::resource("/stats/process")
+            .route(web::get().to(process::stats))
+            .route(web::head().to(process::stats))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/stats/webhooks")
+            .route(web::get().to(breaker::list))
+            .route(web::head().to(breaker::list))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/stats/stream")
+            .route(web::get().to(dashboard::stream))
+            .default_service(web::route().to(errors::method_not_allowed("GET"))),
+    )
+    .service(
+        web::resource("/dashboard")
+            .route(web::get().to(dashboard::page))
+            .route(web::head().to(dashboard::page))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        trace::resource("/{name:dashboard\\.(?:js|css)}")
+            .route(web::get().to(dashboard::file))
+            .route(web::head().to(dashboard::file))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/usage")
+            .route(web::get().to(metering::report))
+            .route(web::head().to(metering::report))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        web::resource("/reload")
+            .route(web::post().to(reload::reload))
+            .default_service(web::route().to(errors::method_not_allowed("POST"))),
+    )
+    .service(
+        web::resource("/tls/reload")
+            .route(web::post().to(tls::reload))
+            .default_service(web::route().to(errors::method_not_allowed("POST"))),
+    )
+    .service(
+        web::resource("/keys")
+            .route(web::get().to(keys::list))
+            .route(web::head().to(keys::list))
+            .route(web::post().to(keys::create))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD, POST"))),
+    )
+    .service(
+        trace::resource("/keys/{id}")
+            .route(web::delete().to(keys::revoke))
+            .default_service(web::route().to(errors::method_not_allowed("DELETE"))),
+    )
+    .service(
+        trace::resource("/keys/{id}/rotate")
+            .route(web::post().to(keys::rotate))
+            .default_service(web::route().to(errors::method_not_allowed("POST"))),
+    )
+    .service(
+        web::resource("/dead-letters")
+            .route(web::get().to(deadletter::list))
+            .route(web::head().to(deadletter::list))
+            .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+    )
+    .service(
+        trace::resource("/dead-letters/{id}")
+            .route(web::delete().to(deadletter::discard))
+            .default_service(web::route().to(errors::method_not_allowed("DELETE"))),
+    )
+    .service(
+        trace::resource("/dead-letters/{id}/retry")
+            .route(web::post().to(deadletter::retry))
+            .default_service(web::route().to(errors::method_not_allowed("POST"))),
+    );
 }
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    let matches = cli::matches();
+    if let Some(print) = matches.subcommand_matches("print-schema") {
+        schema::print(print.is_present("openapi"));
+        return Ok(());
+    }
+    let mut settings = Settings::load(matches.value_of("config"), matches.value_of("profile"))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    settings = vault::apply(settings)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    cli::apply(&mut settings, &matches);
+    if matches.is_present("check_config") {
+        return check::run(&settings);
+    }
+    let pid_file = matches.value_of("pid_file").map(PathBuf::from);
+    let tracer = settings
+        .otlp
+        .as_ref()
+        .map(|otlp| {
+            telemetry::check(otlp)?;
+            telemetry::tracer(otlp).map_err(|e| e.to_string())
+        })
+        .transpose()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let log_control = web::Data::new(logging::init(
+        &settings.log_filter,
+        settings.log_format,
+        tracer,
+    ));
+    let live = web::Data::new(Live::new(settings.clone()));
+    let rate_limiter = Arc::new(RateLimiter::new(
+        &settings.rate_limit.clone().unwrap_or_default(),
+    ));
+    let reloader = web::Data::new(Reloader::new(
+        matches,
+        settings.clone(),
+        log_control.clone(),
+        live.clone(),
+        rate_limiter.clone(),
+    ));
+    reload::spawn_on_sighup(reloader.clone());
+    check::startup(&settings)?;
+    if let Some(chaos) = &settings.chaos {
+        warn!("Chaos mode on, injecting faults on {:?}", chaos.paths);
+    }
 
-    HttpServer::new(|| {
+    let settings = web::Data::new(settings);
+    // the app factory below takes `settings`, server tuning reads this handle
+    let tuning = settings.clone();
+    let idempotency = Arc::new(IdempotencyStore::new(Duration::from_secs(
+        settings.idempotency_ttl,
+    )));
+    let dedup = Arc::new(DedupWindow::new(Duration::from_millis(
+        settings.dedup_window_ms.unwrap_or_default(),
+    )));
+    let results = web::Data::new(ResultStore::new(Duration::from_secs(settings.results_ttl)));
+    let schedules = web::Data::new(Schedules::default());
+    let maintenance = web::Data::new(Maintenance::new(settings.maintenance_windows.clone()));
+    let features = web::Data::new(Features::new(settings.features.clone()));
+    let stats = web::Data::new(Stats::default());
+    let error_monitor = Arc::new(ErrorMonitor::new(
+        settings.error_alert.clone().unwrap_or_default(),
+    ));
+    let final_stats = stats.clone();
+    let quotas = web::Data::new(Quotas::load(
+        settings.key_limits.clone(),
+        settings.usage_file.clone(),
+    ));
+    let final_quotas = quotas.clone();
+    quota::spawn_persist(quotas.clone());
+    let meter = web::Data::new(Meter::load(settings.metering_file.clone()));
+    let final_meter = meter.clone();
+    metering::spawn_persist(meter.clone());
+    let templates = Arc::new(
+        ResponseTemplates::new(&settings.response_templates)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let limiter = Arc::new(Limiter::new(
+        settings.max_in_flight.unwrap_or(usize::MAX),
+        settings.max_queue,
+    ));
+    let mirror = Arc::new(Mirror::new(&settings.mirror.clone().unwrap_or_default()));
+    let process = web::Data::new(Process::new(
+        settings.max_in_flight.map(|_| limiter.clone()),
+    ));
+    let readiness = web::Data::new(Readiness::new(
+        settings.max_in_flight.map(|_| limiter.clone()),
+        settings.ready_max_queued,
+    ));
+    let jwks = settings.jwt.as_ref().map(|jwt| Arc::new(Jwks::new(jwt)));
+    if let Some(jwks) = &jwks {
+        jwt::spawn_refresh(jwks.clone());
+    }
+    let introspector = settings
+        .introspection
+        .as_ref()
+        .map(|i| Arc::new(Introspector::new(i)));
+    let signatures = Some(&settings.signing_clients)
+        .filter(|clients| !clients.is_empty())
+        .map(|clients| Signatures::new(clients, settings.signature_tolerance));
+    let key_store = web::Data::new(
+        KeyStore::load(settings.key_file.clone())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+    );
+    let sessions = web::Data::new(Sessions::new(Duration::from_secs(settings.session_ttl)));
+    let authenticator = Arc::new(
+        Authenticator::new(
+            &settings.api_keys,
+            key_store.clone(),
+            sessions.clone(),
+            jwks,
+            introspector,
+            signatures,
+            settings.lockout.as_ref().map(Lockout::new),
+        )
+        .with_bootstrap(settings.admin_bootstrap_secret.as_deref()),
+    );
+    let proxies = Arc::new(
+        TrustedProxies::new(&settings.trusted_proxies)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let access = Arc::new(
+        AccessControl::new(&settings.access)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let signer = settings
+        .response_signing
+        .as_ref()
+        .map(|signing| ResponseSigner::new(signing).map(Arc::new))
+        .transpose()?;
+    let decrypter = settings
+        .encryption
+        .as_ref()
+        .map(|encryption| Decrypter::new(encryption).map(Arc::new))
+        .transpose()?;
+    let decrypter = web::Data::new(decrypter);
+    let audit = settings
+        .audit
+        .as_ref()
+        .map(|audit| AuditLog::open(audit, &settings.redact).map(Arc::new))
+        .transpose()?;
+    let certs = settings
+        .tls
+        .as_ref()
+        .map(|tls| CertStore::new(tls).map(Arc::new))
+        .transpose()?;
+    if let Some(certs) = &certs {
+        tls::spawn_watcher(certs.clone());
+    }
+    let tls_config = certs.clone().map(tls::server_config).transpose()?;
+    let certs = web::Data::new(certs);
+    let breakers = web::Data::new(Breakers::new(settings.webhook_breaker.clone()));
+    let dead_letters = web::Data::new(DeadLetters::load(settings.dead_letter_file.clone()));
+    let final_dead_letters = dead_letters.clone();
+    deadletter::spawn_persist(dead_letters.clone());
+    schedules::spawn_runner(
+        schedules.clone(),
+        breakers.clone(),
+        dead_letters.clone(),
+        settings.default_case.clone(),
+        settings.webhook_private_hosts,
+    );
+    if let Some(heartbeat) = &settings.heartbeat {
+        heartbeat::spawn(heartbeat.clone(), stats.clone(), readiness.clone());
+    }
+
+    let mut servers = vec![];
+    if let Some(addr) = &settings.admin_bind {
+        servers.push(admin_server(
+            addr,
+            settings.clone(),
+            maintenance.clone(),
+            log_control.clone(),
+            reloader.clone(),
+            features.clone(),
+            certs.clone(),
+            key_store.clone(),
+            sessions.clone(),
+            stats.clone(),
+            process.clone(),
+            breakers.clone(),
+            dead_letters.clone(),
+            meter.clone(),
+            access.clone(),
+            proxies.clone(),
+            authenticator.clone(),
+            audit.clone(),
+        )?);
+    }
+
+    let app = move || {
         App::new()
+            // innermost, checks bodies as handlers get them
+            .wrap(JsonGuard(settings.json_shape()))
+            // rewrite plain response bodies
+            .wrap(Templating(templates.clone()))
+            .wrap(JsonTransform)
+            .wrap(Enveloping)
+            .wrap(PrettyJson)
+            .wrap(TagErrors)
+            .wrap(SignResponses(signer.clone()))
+            .wrap(Encryption(decrypter.get_ref().clone()))
+            // copies bodies as clients sent them, still encrypted
+            .wrap(middleware::Condition::new(
+                settings.mirror.is_some(),
+                MirrorTraffic(mirror.clone()),
+            ))
+            .wrap(Idempotency(idempotency.clone()))
+            .wrap(middleware::Condition::new(
+                settings.dedup_window_ms.is_some(),
+                Dedup(dedup.clone()),
+            ))
+            .wrap(middleware::Condition::new(
+                settings.max_in_flight.is_some(),
+                ConcurrencyLimit(limiter.clone()),
+            ))
+            .wrap(middleware::Condition::new(
+                settings.chaos.is_some(),
+                Chaos(settings.chaos.clone().unwrap_or_default()),
+            ))
             // enable logger
-            .wrap(middleware::Logger::default())
-            .data(web::JsonConfig::default().limit(4096)) // <- limit size of the payload (global configuration)
-            .service(web::resource("/").route(web::get().to(index)))
-            .service(web::resource("/compute").route(web::post().to(compute_factory)))
-            .service(web::resource("/help").route(web::get().to(help)))
+            .wrap(AccessLog(settings.access_log.clone()))
+            .wrap(DeprecationHeaders::new(settings.deprecations.clone()))
+            .wrap(StatsRecorder(stats.clone()))
+            .wrap(Metering(meter.clone()))
+            .wrap(middleware::Condition::new(
+                settings.error_alert.is_some(),
+                WatchErrors(error_monitor.clone()),
+            ))
+            .wrap(middleware::Condition::new(
+                settings.slow_request_ms.is_some(),
+                SlowRequests(Duration::from_millis(
+                    settings.slow_request_ms.unwrap_or_default(),
+                )),
+            ))
+            .wrap(RequestSpan)
+            .wrap(Audit(audit.clone()))
+            .wrap(Authenticate(authenticator.clone()))
+            .wrap_fn(|req, srv| {
+                req.extensions_mut().insert(RequestStart(Instant::now()));
+                srv.call(req)
+            })
+            .wrap_fn({
+                let max = settings.max_header_bytes;
+                move |req, srv| {
+                    if limit::head_size(&req) > max {
+                        Either::Left(future::err(limit::head_too_large(max)))
+                    } else {
+                        Either::Right(srv.call(req))
+                    }
+                }
+            })
+            // before authentication, so floods of bad credentials are limited too
+            .wrap(middleware::Condition::new(
+                settings.rate_limit.is_some(),
+                IpRateLimit(rate_limiter.clone()),
+            ))
+            .wrap(IpAccess(access.clone()))
+            .wrap_fn({
+                let proxies = proxies.clone();
+                let redacted = settings.redact.clone();
+                move |mut req, srv| {
+                    proxies.tag(&mut req);
+                    redact::tag(&mut req, &redacted);
+                    srv.call(req)
+                }
+            })
+            .wrap(AssignRequestId)
+            .wrap(SecurityHeaders::new(
+                &settings.security_headers,
+                settings.tls.is_some(),
+            ))
+            .app_data(settings.clone())
+            .app_data(schedules.clone())
+            .app_data(results.clone())
+            .app_data(maintenance.clone())
+            .app_data(features.clone())
+            .app_data(log_control.clone())
+            .app_data(reloader.clone())
+            .app_data(certs.clone())
+            .app_data(key_store.clone())
+            .app_data(sessions.clone())
+            .app_data(decrypter.clone())
+            .app_data(stats.clone())
+            .app_data(readiness.clone())
+            .app_data(quotas.clone())
+            .app_data(process.clone())
+            .app_data(breakers.clone())
+            .app_data(dead_letters.clone())
+            .app_data(live.clone())
+            // payload size is checked by `JsonGuard` against the live `json_limit`,
+            // this is a backstop at the startup one
+            .data(errors::json_config(settings.json_limit))
+            .service(
+                web::resource("/")
+                    .route(web::get().to(index))
+                    .route(web::head().to(index))
+                    .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+            )
+            .service(
+                web::resource("/health")
+                    .route(web::get().to(health::health))
+                    .route(web::head().to(health::health))
+                    .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+            )
+            .service(
+                web::resource("/readyz")
+                    .route(web::get().to(health::readyz))
+                    .route(web::head().to(health::readyz))
+                    .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+            )
+            .service(
+                web::resource("/selftest")
+                    .route(web::get().to(selftest::selftest))
+                    .route(web::head().to(selftest::selftest))
+                    .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+            )
+            .service(
+                web::resource("/session")
+                    .route(web::post().to(session::open))
+                    .route(web::delete().to(session::close))
+                    .default_service(web::route().to(errors::method_not_allowed("POST, DELETE"))),
+            )
+            .service(
+                web::resource("/openapi.json")
+                    .route(web::get().to(schema::document))
+                    .route(web::head().to(schema::document))
+                    .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+            )
+            .service(
+                web::resource("/stats")
+                    .route(web::get().to(stats::summary))
+                    .route(web::head().to(stats::summary))
+                    .default_service(web::route().to(errors::method_not_allowed("GET, HEAD"))),
+            )
+            .configure(|cfg| {
+                if settings.assets {
+                    cfg.service(
+                        trace::resource("/assets/{path:.*}")
+                            .route(web::get().to(assets::get))
+                            .route(web::head().to(assets::get))
+                            .default_service(
+                                web::route().to(errors::method_not_allowed("GET, HEAD")),
+                            ),
+                    );
+                }
+            })
+            .configure(|cfg| {
+                // with an internal listener, admin routes are served only there
+                if settings.admin_bind.is_none() {
+                    cfg.service(
+                        web::scope("/admin")
+                            .wrap(RequireAuth(auth::OPS))
+                            .configure(admin),
+                    );
+                }
+            })
+            .service(web::scope("/v1").configure(|cfg| api_v1(cfg, &settings)))
+            // deprecated unversioned alias of v1
+            .configure(|cfg| api_v1(cfg, &settings))
+            .default_service(web::route().to(errors::not_found))
+    };
+    // a socket passed by systemd (`LISTEN_FDS`) or `systemfd` takes the place of `bind`,
+    // allowing restarts without refused connections
+    let inherited = ListenFd::from_env().take_tcp_listener(0)?;
+    if let Some(listener) = &inherited {
+        info!("Listening on inherited socket {:?}", listener.local_addr());
+    }
+    servers.push(match tls_config {
+        // accepted outside `HttpServer` to see each TLS session after its handshake
+        Some(config) => {
+            let mut listeners = match inherited {
+                Some(listener) => vec![listener],
+                None => vec![TcpListener::bind(&tuning.bind)?],
+            };
+            for addr in &tuning.binds {
+                listeners.push(TcpListener::bind(addr)?);
+            }
+            tls::serve(app, listeners, config, &tuning)?
+        }
+        None => {
+            let mut server = HttpServer::new(app)
+                .disable_signals()
+                .shutdown_timeout(tuning.shutdown_timeout)
+                .keep_alive(match tuning.keep_alive {
+                    0 => None,
+                    secs => Some(secs),
+                })
+                .client_timeout(tuning.client_timeout)
+                .client_shutdown(tuning.client_shutdown)
+                .maxconn(tuning.max_connections)
+                .maxconnrate(tuning.max_connection_rate);
+            server = match inherited {
+                Some(listener) => server.listen(listener)?,
+                None => server.bind(&tuning.bind)?,
+            };
+            for addr in &tuning.binds {
+                server = server.bind(addr)?;
+            }
+            match tuning.workers {
+                Some(n) => server.workers(n).run(),
+                None => server.run(),
+            }
+        }
+    });
+    // written once listening, so supervisors never see the pid of a process failing to bind
+    let _pid_file = pid_file.map(PidFile::create).transpose()?;
+    let registration = match &tuning.consul {
+        Some(consul) => consul::register(consul, &tuning.bind, tuning.tls.is_some()).await,
+        None => None,
+    };
+    actix_rt::spawn(shutdown::on_signal(
+        servers.clone(),
+        readiness,
+        Duration::from_secs(tuning.pre_stop_delay),
+        registration,
+    ));
+    let result = future::try_join_all(servers).await.map(|_| ());
+    shutdown::flush(&final_stats);
+    final_quotas.save();
+    final_meter.save();
+    final_dead_letters.save();
+    telemetry::shutdown();
+    result
+}
+
+/// Internal listener serving nothing but the admin routes
+#[allow(clippy::too_many_arguments)]
+fn admin_server(
+    addr: &str,
+    settings: web::Data<Settings>,
+    maintenance: web::Data<Maintenance>,
+    log_control: web::Data<LogControl>,
+    reloader: web::Data<Reloader>,
+    features: web::Data<Features>,
+    certs: web::Data<Option<Arc<CertStore>>>,
+    key_store: web::Data<KeyStore>,
+    sessions: web::Data<Sessions>,
+    stats: web::Data<Stats>,
+    process: web::Data<Process>,
+    breakers: web::Data<Breakers>,
+    dead_letters: web::Data<DeadLetters>,
+    meter: web::Data<Meter>,
+    access: Arc<AccessControl>,
+    proxies: Arc<TrustedProxies>,
+    authenticator: Arc<Authenticator>,
+    audit: Option<Arc<AuditLog>>,
+) -> std::io::Result<Server> {
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(AccessLog(settings.access_log.clone()))
+            .wrap(Audit(audit.clone()))
+            .wrap(Authenticate(authenticator.clone()))
+            .wrap(IpAccess(access.clone()))
+            .wrap_fn({
+                let proxies = proxies.clone();
+                let redacted = settings.redact.clone();
+                move |mut req, srv| {
+                    proxies.tag(&mut req);
+                    redact::tag(&mut req, &redacted);
+                    srv.call(req)
+                }
+            })
+            .wrap(AssignRequestId)
+            .wrap(SecurityHeaders::new(&settings.security_headers, false))
+            .app_data(settings.clone())
+            .app_data(maintenance.clone())
+            .app_data(log_control.clone())
+            .app_data(reloader.clone())
+            .app_data(features.clone())
+            .app_data(certs.clone())
+            .app_data(key_store.clone())
+            .app_data(sessions.clone())
+            .app_data(stats.clone())
+            .app_data(process.clone())
+            .app_data(breakers.clone())
+            .app_data(dead_letters.clone())
+            .app_data(meter.clone())
+            .data(errors::json_config(settings.json_limit))
+            .service(
+                web::scope("/admin")
+                    .wrap(RequireAuth(auth::OPS))
+                    .configure(admin),
+            )
+            .default_service(web::route().to(errors::not_found))
     })
-    .bind("127.0.0.1:3030")?
-    .run()
-    .await
+    .disable_signals()
+    .workers(1)
+    .bind(addr)?;
+    Ok(server.run())
 }
 
-fn compute(p: &Params) -> Result<Output> {
+fn compute(p: &Params, default: &Case) -> Result<Output> {
+    let case = p.case.clone().unwrap_or_else(|| default.clone());
+
+    output(classify(p, &case), p, case).and_then(finite)
+}
+
+/// Matches A/B/C against the rules of the case
+fn classify(p: &Params, case: &Case) -> H {
     let Params { a, b, c, .. } = p;
-    let case = p.case.clone().map_or(Case::B, |v| v);
 
     // TODO: find a better way to handle this stuff
     match case {
         Case::B | Case::C1 => match (a, b, c) {
-            (Some(true), Some(true), Some(false)) => output(H::M, &p, case),
-            (Some(true), Some(true), Some(true)) => output(H::P, &p, case),
-            (Some(false), Some(true), Some(true)) => output(H::T, &p, case),
-            (_, _, _) => output(H::E, &p, case),
+            (Some(true), Some(true), Some(false)) => H::M,
+            (Some(true), Some(true), Some(true)) => H::P,
+            (Some(false), Some(true), Some(true)) => H::T,
+            (_, _, _) => H::E,
         },
         Case::C2 => match (a, b, c) {
-            (Some(true), Some(true), Some(false)) => output(H::M, &p, case),
-            (Some(true), Some(false), Some(true)) => output(H::M, &p, case),
-            (Some(true), Some(true), Some(true)) => output(H::P, &p, case),
-            (Some(false), Some(true), Some(true)) => output(H::T, &p, case),
-            (_, _, _) => output(H::E, &p, case),
+            (Some(true), Some(true), Some(false)) => H::M,
+            (Some(true), Some(false), Some(true)) => H::M,
+            (Some(true), Some(true), Some(true)) => H::P,
+            (Some(false), Some(true), Some(true)) => H::T,
+            (_, _, _) => H::E,
         },
     }
 }
 
 fn output(h: H, p: &Params, case: Case) -> Result<Output> {
-    // TODO: figure out how to convert D, F, E params from Option<T> to T
-    // and pass error if it rises in essential places (basically every expect(..))
-    let d = p.d.expect("no D param");
+    let d = p.d.ok_or(Fault::MissingParam("D"))?;
 
     match h {
         H::M => {
-            let e: f64 = p.e.expect("no E param").into();
+            let e: f64 = p.e.ok_or(Fault::MissingParam("E"))?.into();
 
             let k = match case {
                 Case::C2 => {
-                    let f: f64 = p.f.expect("no F param").into();
+                    let f: f64 = p.f.ok_or(Fault::MissingParam("F"))?.into();
                     f + d + ((d * e) / 100.0)
                 }
                 _ => d + (d * e / 10.0),
@@ -159,8 +1200,8 @@ fn output(h: H, p: &Params, case: Case) -> Result<Output> {
             Ok(Output { h: H::M, k })
         }
         H::P => {
-            let e: f64 = p.e.expect("no E param").into();
-            let f: f64 = p.f.expect("no F param").into();
+            let e: f64 = p.e.ok_or(Fault::MissingParam("E"))?.into();
+            let f: f64 = p.f.ok_or(Fault::MissingParam("F"))?.into();
 
             let k = match case {
                 Case::C1 => 2.0 * d + ((d * e) / 100.0),
@@ -170,27 +1211,29 @@ fn output(h: H, p: &Params, case: Case) -> Result<Output> {
             Ok(Output { h: H::M, k })
         }
         H::T => {
-            let f: f64 = p.f.expect("no F param").into();
+            let f: f64 = p.f.ok_or(Fault::MissingParam("F"))?.into();
 
             Ok(Output {
                 h: H::M,
                 k: d - (d * f / 30.0),
             })
         }
-        H::E => Err(anyhow!("Set of parameters is not supported.")),
+        H::E => Err(Fault::Unsupported.into()),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use actix_web::dev::Service;
-    use actix_web::{http, test, web, App};
+    use actix_web::{test, web, App};
 
     #[actix_rt::test]
     async fn correct_input() -> Result<(), Error> {
         let mut app = test::init_service(
-            App::new().service(web::resource("/compute").route(web::post().to(compute_factory))),
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
         )
         .await;
 
@@ -224,7 +1267,10 @@ mod tests {
     #[actix_rt::test]
     async fn incorrect_base_input() -> Result<(), Error> {
         let mut app = test::init_service(
-            App::new().service(web::resource("/compute").route(web::post().to(compute_factory))),
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
         )
         .await;
 
@@ -256,10 +1302,84 @@ mod tests {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn localized_error() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute")
+            .header(header::ACCEPT_LANGUAGE, "de-DE,de;q=0.9")
+            .set_json(&Params {
+                a: Some(true),
+                b: Some(true),
+                c: Some(false),
+                d: Some(3.7),
+                e: None,
+                f: Some(2),
+                case: None,
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("x-error-code").unwrap(), "missing_param");
+
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+
+        assert_eq!(response_body, "Falsche Parameter: Parameter E fehlt");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn required_case() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings {
+                    require_case: true,
+                    ..Settings::default()
+                })
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute")
+            .set_json(&Params {
+                a: Some(true),
+                b: Some(true),
+                c: Some(false),
+                d: Some(3.7),
+                e: Some(5),
+                f: Some(2),
+                case: None,
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("x-error-code").unwrap(), "missing_case");
+
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn correct_c1_input() -> Result<(), Error> {
         let mut app = test::init_service(
-            App::new().service(web::resource("/compute").route(web::post().to(compute_factory))),
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
         )
         .await;
 
@@ -292,7 +1412,10 @@ mod tests {
     #[actix_rt::test]
     async fn incorrect_c1_input() -> Result<(), Error> {
         let mut app = test::init_service(
-            App::new().service(web::resource("/compute").route(web::post().to(compute_factory))),
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
         )
         .await;
 
@@ -326,7 +1449,10 @@ mod tests {
     #[actix_rt::test]
     async fn correct_c2_input() -> Result<(), Error> {
         let mut app = test::init_service(
-            App::new().service(web::resource("/compute").route(web::post().to(compute_factory))),
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
         )
         .await;
 
@@ -356,4 +1482,666 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn example_is_valid() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new().service(web::resource("/examples").route(web::get().to(example))),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/examples?case=C2&h=M")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+        let params: Params = serde_json::from_slice(response_body).unwrap();
+        let case = params.case.clone().unwrap_or(Case::B);
+
+        assert!(output(classify(&params, &case), &params, case).is_ok());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn unknown_path_is_json_404() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory)))
+                .default_service(web::route().to(errors::not_found)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/nope").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+
+        assert_eq!(
+            response_body,
+            r#"{"code":404,"message":"Resource not found."}"#
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn wrong_method_is_json_405() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(
+                    web::resource("/compute")
+                        .route(web::post().to(compute_factory))
+                        .default_service(web::route().to(errors::method_not_allowed("POST"))),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/compute").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers().get(http::header::ALLOW).unwrap(), "POST");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn compute_options_lists_methods() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(
+                    web::resource("/compute")
+                        .route(web::post().to(compute_factory))
+                        .route(web::method(http::Method::OPTIONS).to(compute_options)),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/compute")
+            .method(http::Method::OPTIONS)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers().get(http::header::ALLOW).unwrap(),
+            "POST, OPTIONS"
+        );
+        assert_eq!(
+            resp.headers().get("Accept-Post").unwrap(),
+            "application/json"
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn versioned_and_alias_compute() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(DeprecationHeaders::new(deprecation::defaults()))
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::scope("/v1").configure(|cfg| api_v1(cfg, &Settings::default())))
+                .configure(|cfg| api_v1(cfg, &Settings::default())),
+        )
+        .await;
+
+        for uri in &["/v1/compute", "/compute"] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .set_json(&Params {
+                    a: Some(true),
+                    b: Some(true),
+                    c: Some(false),
+                    d: Some(3.7),
+                    e: Some(5),
+                    f: Some(2),
+                    case: None,
+                })
+                .to_request();
+            let resp = app.call(req).await.unwrap();
+
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            assert_eq!(
+                resp.headers().contains_key("deprecation"),
+                *uri == "/compute"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn enveloped_error() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .wrap(Enveloping)
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute?envelope=true")
+            .set_json(&Params {
+                a: Some(false),
+                b: Some(false),
+                c: Some(false),
+                d: Some(3.7),
+                e: Some(5),
+                f: Some(2),
+                case: None,
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+        let body: serde_json::Value = serde_json::from_slice(response_body).unwrap();
+
+        assert!(body["data"].is_null());
+        assert_eq!(body["error"]["code"], 400);
+        assert!(body["error"]["kind"].is_string());
+        assert!(body["meta"]["duration_ms"].is_number());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn pretty_output() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(PrettyJson)
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute?pretty=true")
+            .set_json(&Params {
+                a: Some(true),
+                b: Some(true),
+                c: Some(true),
+                d: Some(3.7),
+                e: Some(5),
+                f: Some(2),
+                case: Some(Case::C1),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+
+        assert_eq!(response_body, "{\n  \"h\": \"M\",\n  \"k\": 7.585\n}");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn server_timing_header() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute")
+            .set_json(&Params {
+                a: Some(true),
+                b: Some(true),
+                c: Some(false),
+                d: Some(3.7),
+                e: Some(5),
+                f: Some(2),
+                case: None,
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        let timing = resp
+            .headers()
+            .get("server-timing")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(timing.starts_with("validate;dur="));
+        assert!(timing.contains(", compute;dur="));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn payload_too_large() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .data(errors::json_config(16))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute")
+            .set_json(&Params {
+                a: Some(true),
+                b: Some(true),
+                c: Some(false),
+                d: Some(3.7),
+                e: Some(5),
+                f: Some(2),
+                case: Some(Case::B),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+
+        assert_eq!(
+            response_body,
+            r#"{"code":413,"message":"Payload is larger than the limit of 16 bytes"}"#
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn batch_partial_failure() -> Result<(), Error> {
+        let dead_letters = web::Data::new(DeadLetters::load(None));
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .app_data(dead_letters.clone())
+                .service(web::resource("/compute/batch").route(web::post().to(compute_batch))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute/batch")
+            .set_json(&vec![
+                BatchRequest {
+                    id: None,
+                    params: Params {
+                        a: Some(true),
+                        b: Some(true),
+                        c: Some(false),
+                        d: Some(3.7),
+                        e: Some(5),
+                        f: Some(2),
+                        case: None,
+                    },
+                },
+                BatchRequest {
+                    id: Some("second".into()),
+                    params: Params {
+                        a: Some(true),
+                        b: Some(true),
+                        c: Some(false),
+                        ..Params::default()
+                    },
+                },
+            ])
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::MULTI_STATUS);
+
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+        let body: serde_json::Value = serde_json::from_slice(response_body).unwrap();
+
+        assert_eq!(body[0]["status"], 200);
+        assert!(body[0].get("id").is_none());
+        assert_eq!(body[1]["status"], 400);
+        assert_eq!(body[1]["id"], "second");
+        assert!(dead_letters.list().is_empty());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn batch_dead_letters_server_faults() -> Result<(), Error> {
+        let dead_letters = web::Data::new(DeadLetters::load(None));
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .app_data(dead_letters.clone())
+                .service(web::resource("/compute/batch").route(web::post().to(compute_batch))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute/batch")
+            .set_json(&vec![BatchRequest {
+                id: Some("huge".into()),
+                params: Params {
+                    a: Some(true),
+                    b: Some(true),
+                    c: Some(false),
+                    d: Some(f64::MAX),
+                    e: Some(5),
+                    f: Some(2),
+                    case: Some(Case::B),
+                },
+            }])
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::MULTI_STATUS);
+
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+        let body: serde_json::Value = serde_json::from_slice(response_body).unwrap();
+
+        assert_eq!(body[0]["status"], 500);
+        assert_eq!(body[0]["error"]["kind"], "internal");
+        let dead = serde_json::to_value(dead_letters.list()).unwrap();
+        assert_eq!(dead[0]["kind"], "batch_item");
+        assert_eq!(dead[0]["error"], "K is out of range");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn idempotent_replay() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Idempotency(Arc::new(IdempotencyStore::new(
+                    Duration::from_secs(60),
+                ))))
+                .service(web::resource("/examples").route(web::post().to(example))),
+        )
+        .await;
+
+        let mut bodies = vec![];
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/examples?h=P")
+                .header("Idempotency-Key", "abc")
+                .to_request();
+            let resp = app.call(req).await.unwrap();
+
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            bodies.push(test::read_body(resp).await);
+        }
+
+        assert_eq!(bodies[0], bodies[1]);
+
+        let req = test::TestRequest::post()
+            .uri("/examples?h=P")
+            .header("Idempotency-Key", "abc")
+            .set_payload("{}")
+            .to_request();
+        let status = match app.call(req).await {
+            Ok(resp) => resp.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        assert_eq!(status, http::StatusCode::UNPROCESSABLE_ENTITY);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn admin_allowlist_covers_encoded_paths() -> Result<(), Error> {
+        let mut lists = access::AccessLists::default();
+        lists.admin.allow = vec!["10.0.0.0/8".into()];
+        let access = Arc::new(AccessControl::new(&lists).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(IpAccess(access))
+                .service(web::resource("/admin/keys").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for uri in &["/admin/keys", "/%61dmin/keys"] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .peer_addr("198.51.100.7:4000".parse().unwrap())
+                .to_request();
+            let status = match app.call(req).await {
+                Ok(resp) => resp.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            assert_eq!(status, http::StatusCode::FORBIDDEN, "{}", uri);
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn dedup_replays_identical_body() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Dedup(Arc::new(DedupWindow::new(Duration::from_secs(60)))))
+                .service(web::resource("/examples").route(web::post().to(example))),
+        )
+        .await;
+
+        let mut bodies = vec![];
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/examples")
+                .peer_addr("10.0.0.1:4000".parse().unwrap())
+                .header(http::header::CONTENT_LENGTH, "2")
+                .set_payload("{}")
+                .to_request();
+            let resp = app.call(req).await.unwrap();
+            bodies.push(test::read_body(resp).await);
+        }
+
+        assert_eq!(bodies[0], bodies[1]);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn etag_not_modified() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory)))
+                .service(trace::resource("/v1/results/{id}").route(web::get().to(results::get))),
+        )
+        .await;
+
+        let params = Params {
+            a: Some(true),
+            b: Some(true),
+            c: Some(false),
+            d: Some(3.7),
+            e: Some(5),
+            f: Some(2),
+            case: None,
+        };
+        let req = test::TestRequest::post()
+            .uri("/compute")
+            .set_json(&params)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        let tag = resp.headers().get(http::header::ETAG).unwrap().clone();
+        let location = resp
+            .headers()
+            .get(http::header::CONTENT_LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(tag.to_str().unwrap().starts_with("W/"));
+
+        // a POST is computed again whatever the client holds
+        let req = test::TestRequest::post()
+            .uri("/compute")
+            .header(http::header::IF_NONE_MATCH, tag.clone())
+            .set_json(&params)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri(&location)
+            .header(http::header::IF_NONE_MATCH, tag)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::NOT_MODIFIED);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn stored_result_is_retrievable() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::scope("/v1").configure(|cfg| api_v1(cfg, &Settings::default()))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/compute")
+            .set_json(&Params {
+                a: Some(true),
+                b: Some(true),
+                c: Some(true),
+                d: Some(3.7),
+                e: Some(5),
+                f: Some(2),
+                case: Some(Case::C1),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        let location = resp
+            .headers()
+            .get(http::header::CONTENT_LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let req = test::TestRequest::get().uri(&location).to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, r##"{"h":"M","k":7.585}"##);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn maintenance_mode_blocks_compute() -> Result<(), Error> {
+        let mut app = test::init_service(
+            App::new()
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .data(Maintenance::default())
+                .service(web::scope("/admin").configure(admin))
+                .service(web::scope("/v1").configure(|cfg| api_v1(cfg, &Settings::default()))),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/admin/maintenance")
+            .set_json(&maintenance::MaintenanceState {
+                enabled: true,
+                retry_after: 120,
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/compute")
+            .set_json(&Params::default())
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            resp.headers().get(http::header::RETRY_AFTER).unwrap(),
+            "120"
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn stats_count_computes() -> Result<(), Error> {
+        let stats = web::Data::new(Stats::default());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(StatsRecorder(stats.clone()))
+                .data(Settings::default())
+                .data(ResultStore::new(Duration::from_secs(60)))
+                .service(web::resource("/compute").route(web::post().to(compute_factory))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/compute")
+            .set_json(&Params {
+                a: Some(false),
+                b: Some(true),
+                c: Some(true),
+                d: Some(3.7),
+                e: Some(5),
+                f: Some(2),
+                case: Some(Case::C2),
+            })
+            .to_request();
+        app.call(req).await.unwrap();
+
+        let summary = stats.summary();
+
+        assert_eq!(summary.requests, 1);
+        assert_eq!(summary.by_case["C2"], 1);
+        assert_eq!(summary.by_h["T"], 1);
+
+        Ok(())
+    }
 }
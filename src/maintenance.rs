@@ -0,0 +1,180 @@
+//! Maintenance mode makes compute routes answer 503 with `Retry-After`:
+//!
+//! ``` curl -H "Content-Type: application/json" -X PUT -d '{"enabled": true, "retry_after": 120}' localhost:3030/admin/maintenance ```
+//!
+//! Planned `maintenance_windows` (`start`/`end` in RFC 3339) do the same automatically,
+//! with `Retry-After` counting down to the window end.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, Error, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::future::{ok, Either, Ready};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::json_error;
+
+/// Maintenance mode switch and scheduled windows, shared between workers
+#[derive(Debug, Default)]
+pub struct Maintenance {
+    enabled: AtomicBool,
+    retry_after: AtomicU64,
+    windows: Vec<MaintenanceWindow>,
+}
+
+/// Planned downtime, compute routes answer 503 from `start` until `end`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MaintenanceWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MaintenanceState {
+    pub enabled: bool,
+    /// Seconds clients are asked to wait, sent as `Retry-After`
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+}
+
+fn default_retry_after() -> u64 {
+    60
+}
+
+impl Maintenance {
+    pub fn new(windows: Vec<MaintenanceWindow>) -> Self {
+        Maintenance {
+            windows,
+            ..Maintenance::default()
+        }
+    }
+
+    pub fn state(&self) -> MaintenanceState {
+        MaintenanceState {
+            enabled: self.enabled.load(Ordering::SeqCst),
+            retry_after: self.retry_after.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn set(&self, state: &MaintenanceState) {
+        self.retry_after.store(state.retry_after, Ordering::SeqCst);
+        self.enabled.store(state.enabled, Ordering::SeqCst);
+    }
+
+    /// `Retry-After` seconds while in maintenance
+    pub fn retry_after(&self) -> Option<u64> {
+        self.retry_after_at(Utc::now())
+    }
+
+    /// The toggle wins over windows, a window lasts until its end
+    fn retry_after_at(&self, now: DateTime<Utc>) -> Option<u64> {
+        let state = self.state();
+        if state.enabled {
+            return Some(state.retry_after);
+        }
+        self.windows
+            .iter()
+            .filter(|w| w.start <= now && now < w.end)
+            .map(|w| (w.end - now).num_seconds().max(1) as u64)
+            .max()
+    }
+}
+
+pub fn unavailable(retry_after: u64) -> HttpResponse {
+    let mut resp = json_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Down for maintenance, retry later.",
+    );
+    resp.headers_mut()
+        .insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+    resp
+}
+
+pub async fn get(maintenance: web::Data<Maintenance>) -> HttpResponse {
+    HttpResponse::Ok().json(maintenance.state())
+}
+
+pub async fn put(
+    data: web::Json<MaintenanceState>,
+    maintenance: web::Data<Maintenance>,
+) -> HttpResponse {
+    maintenance.set(&data);
+    HttpResponse::Ok().json(maintenance.state())
+}
+
+/// Middleware answering 503 with `Retry-After` while maintenance is on.
+/// Wraps the compute resources only, so health and admin routes stay up.
+pub struct MaintenanceGuard;
+
+impl<S, B> Transform<S> for MaintenanceGuard
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MaintenanceGuardMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MaintenanceGuardMiddleware { service })
+    }
+}
+
+pub struct MaintenanceGuardMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for MaintenanceGuardMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let retry_after = req.app_data::<Maintenance>().and_then(|m| m.retry_after());
+
+        match retry_after {
+            Some(secs) => Either::Right(ok(req.into_response(unavailable(secs).into_body()))),
+            None => Either::Left(self.service.call(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn window_retry_after() {
+        let now = Utc::now();
+        let maintenance = Maintenance::new(vec![MaintenanceWindow {
+            start: now - Duration::minutes(5),
+            end: now + Duration::minutes(10),
+        }]);
+
+        assert_eq!(maintenance.retry_after_at(now), Some(600));
+        assert_eq!(
+            maintenance.retry_after_at(now + Duration::minutes(10)),
+            None
+        );
+        assert_eq!(maintenance.retry_after_at(now - Duration::minutes(6)), None);
+    }
+}
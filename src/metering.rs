@@ -0,0 +1,392 @@
+//! Requests, computes, batches with their items and the milliseconds spent computing are
+//! metered per UTC day, key and configured tenant (`-` for none, also for requests refused
+//! before their tenant was checked), kept in `metering_file` across restarts. `GET
+//! /admin/usage` reports them, `from`/`to` limit the days, `period=month` sums them per
+//! month and `format=csv` answers CSV for chargeback, fields starting like a spreadsheet
+//! formula prefixed with `'`:
+//!
+//! ``` curl "localhost:3030/admin/usage?from=2026-10-01&period=month&format=csv" ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::Auth;
+use crate::stats::Outcomes;
+use crate::tenants::AcceptedTenant;
+use crate::timing::Timings;
+
+/// Seconds between writes of the metering file
+const PERSIST_INTERVAL: u64 = 60;
+
+/// Days of usage kept, older ones are dropped
+const RETENTION_DAYS: i64 = 400;
+
+/// Key or tenant of requests that came without one
+const NONE: &str = "-";
+
+/// What a key used within a tenant on one UTC day
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Usage {
+    pub requests: u64,
+    /// Single compute requests
+    pub computes: u64,
+    pub batches: u64,
+    pub batch_items: u64,
+    /// Milliseconds spent answering computes and batches
+    pub compute_ms: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.computes += other.computes;
+        self.batches += other.batches;
+        self.batch_items += other.batch_items;
+        self.compute_ms += other.compute_ms;
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Row {
+    /// UTC day as `2026-10-16`, or month as `2026-10`
+    pub period: String,
+    pub key: String,
+    pub tenant: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Default for Period {
+    fn default() -> Self {
+        Period::Day
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Json
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ReportQuery {
+    /// First day reported, inclusive
+    pub from: Option<NaiveDate>,
+    /// Last day reported, inclusive
+    pub to: Option<NaiveDate>,
+    pub period: Period,
+    pub format: Format,
+}
+
+/// Usage per UTC day, key and tenant, kept across restarts through `file`
+pub struct Meter {
+    file: Option<String>,
+    days: Mutex<BTreeMap<(NaiveDate, String, String), Usage>>,
+}
+
+impl Meter {
+    /// Starts from the usage saved in `file`, if any
+    pub fn load(file: Option<String>) -> Self {
+        let rows: Vec<Row> = file
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        let days = rows
+            .into_iter()
+            .filter_map(|row| {
+                let day = row.period.parse::<NaiveDate>().ok()?;
+                Some(((day, row.key, row.tenant), row.usage))
+            })
+            .collect();
+        Meter {
+            file,
+            days: Mutex::new(days),
+        }
+    }
+
+    pub fn record(
+        &self,
+        at: DateTime<Utc>,
+        key: Option<&str>,
+        tenant: Option<&str>,
+        usage: &Usage,
+    ) {
+        let day = at.date().naive_utc();
+        let mut days = self.days.lock().unwrap();
+        let id = (
+            day,
+            key.unwrap_or(NONE).to_string(),
+            tenant.unwrap_or(NONE).to_string(),
+        );
+        days.entry(id).or_default().add(usage);
+
+        let oldest = day - chrono::Duration::days(RETENTION_DAYS);
+        while days.keys().next().map_or(false, |(d, _, _)| *d < oldest) {
+            let first = days.keys().next().cloned().unwrap();
+            days.remove(&first);
+        }
+    }
+
+    /// Usage between `from` and `to`, summed per period, key and tenant
+    pub fn report(&self, query: &ReportQuery) -> Vec<Row> {
+        let days = self.days.lock().unwrap();
+        let mut periods: BTreeMap<(String, String, String), Usage> = BTreeMap::new();
+        for ((day, key, tenant), usage) in days.iter() {
+            if query.from.map_or(false, |from| *day < from)
+                || query.to.map_or(false, |to| *day > to)
+            {
+                continue;
+            }
+            let period = match query.period {
+                Period::Day => day.format("%Y-%m-%d").to_string(),
+                Period::Month => day.format("%Y-%m").to_string(),
+            };
+            periods
+                .entry((period, key.clone(), tenant.clone()))
+                .or_default()
+                .add(usage);
+        }
+        periods
+            .into_iter()
+            .map(|((period, key, tenant), usage)| Row {
+                period,
+                key,
+                tenant,
+                usage,
+            })
+            .collect()
+    }
+
+    /// Writes the daily usage to `file`
+    pub fn save(&self) {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return,
+        };
+        let rows = self.report(&ReportQuery::default());
+        let json = serde_json::to_vec(&rows).unwrap_or_default();
+        if let Err(e) = fs::write(path, json) {
+            warn!("Could not save metering to {}: {:?}", path, e);
+        }
+    }
+}
+
+/// Saves usage every `PERSIST_INTERVAL` seconds, so a crash loses at most that much
+pub fn spawn_persist(meter: web::Data<Meter>) {
+    if meter.file.is_none() {
+        return;
+    }
+    actix_rt::spawn(async move {
+        let mut tick = actix_rt::time::interval(Duration::from_secs(PERSIST_INTERVAL));
+        loop {
+            tick.tick().await;
+            meter.save();
+        }
+    });
+    info!("Persisting usage reports every {}s", PERSIST_INTERVAL);
+}
+
+/// Quotes a CSV field when it holds a separator, a quote or a line break. Fields a
+/// spreadsheet would take for a formula get a leading `'`.
+fn csv_field(value: &str) -> String {
+    let value = if value != NONE && value.starts_with(&['=', '+', '-', '@', '\t', '\r'][..]) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn csv(rows: &[Row]) -> String {
+    let mut out =
+        String::from("period,key,tenant,requests,computes,batches,batch_items,compute_ms\n");
+    for row in rows {
+        let u = &row.usage;
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{},{}\n",
+            csv_field(&row.period),
+            csv_field(&row.key),
+            csv_field(&row.tenant),
+            u.requests,
+            u.computes,
+            u.batches,
+            u.batch_items,
+            u.compute_ms
+        ));
+    }
+    out
+}
+
+/// Usage report for chargeback, as JSON or with `format=csv` as CSV
+pub async fn report(meter: web::Data<Meter>, query: web::Query<ReportQuery>) -> HttpResponse {
+    let rows = meter.report(&query);
+    match query.format {
+        Format::Json => HttpResponse::Ok().json(rows),
+        Format::Csv => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .header(
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"usage.csv\"",
+            )
+            .body(csv(&rows)),
+    }
+}
+
+/// Middleware counting requests, batch items and compute time into `Meter`
+pub struct Metering(pub web::Data<Meter>);
+
+impl<S, B> Transform<S> for Metering
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MeteringMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MeteringMiddleware {
+            service,
+            meter: self.0.clone(),
+        })
+    }
+}
+
+pub struct MeteringMiddleware<S> {
+    service: S,
+    meter: web::Data<Meter>,
+}
+
+impl<S, B> Service for MeteringMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let meter = self.meter.clone();
+        let key = match req.extensions().get::<Auth>() {
+            Some(Auth::Identified(identity)) => Some(identity.name.clone()),
+            _ => None,
+        };
+        let batch = req.path().ends_with("/batch");
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            // only tenants `tenants::policy` accepted, so unknown names cannot grow the report
+            let tenant = res
+                .request()
+                .extensions()
+                .get::<AcceptedTenant>()
+                .map(|t| t.0.clone());
+            let mut usage = Usage {
+                requests: 1,
+                ..Usage::default()
+            };
+            if let Some(Outcomes(outcomes)) = res.response().extensions().get::<Outcomes>() {
+                if batch {
+                    usage.batches = 1;
+                    usage.batch_items = outcomes.len() as u64;
+                } else {
+                    usage.computes = 1;
+                }
+                if let Some(timings) = res.response().extensions().get::<Timings>() {
+                    usage.compute_ms = timings.total("compute").as_millis() as u64;
+                }
+            }
+            meter.record(Utc::now(), key.as_deref(), tenant.as_deref(), &usage);
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn reports_per_period() {
+        let meter = Meter::load(None);
+        let at = |d| Utc.ymd(2026, 10, d).and_hms(12, 0, 0);
+        let batch = Usage {
+            requests: 1,
+            batches: 1,
+            batch_items: 3,
+            compute_ms: 7,
+            ..Usage::default()
+        };
+        meter.record(at(1), Some("partner"), Some("acme"), &batch);
+        meter.record(at(2), Some("partner"), Some("acme"), &batch);
+        meter.record(at(2), None, None, &Usage::default());
+
+        let days = meter.report(&ReportQuery::default());
+        assert_eq!(days.len(), 3);
+        assert_eq!(days[0].period, "2026-10-01");
+
+        let months = meter.report(&ReportQuery {
+            from: Some(NaiveDate::from_ymd(2026, 10, 1)),
+            period: Period::Month,
+            ..ReportQuery::default()
+        });
+        let partner = months.iter().find(|r| r.key == "partner").unwrap();
+        assert_eq!(partner.period, "2026-10");
+        assert_eq!(
+            (partner.usage.batch_items, partner.usage.compute_ms),
+            (6, 14)
+        );
+
+        let rows = csv(&months);
+        assert!(rows.starts_with("period,key,tenant,"));
+        assert!(rows.contains("2026-10,partner,acme,2,0,2,6,14\n"));
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+        assert_eq!(csv_field("=1+2"), "'=1+2");
+        assert_eq!(csv_field("-"), "-");
+    }
+}
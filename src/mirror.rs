@@ -0,0 +1,245 @@
+//! With `[mirror]` set, `sample_ratio` of the compute requests of callers identified with
+//! `compute` are also POSTed to the same path under its `url`, e.g. a new build, with
+//! `X-Mirrored: 1`. Bodies are copied as received, encrypted ones stay encrypted. Only
+//! `Content-Type`, `Accept-Language`, `X-Tenant` and `X-Request-Id` are passed on, never
+//! credentials, and the shadow's answers are ignored. Nothing is mirrored while
+//! authentication is off.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::client::Client;
+use actix_web::dev::{Payload, PayloadStream, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, HeaderMap, Method};
+use actix_web::{Error, HttpMessage};
+use bytes::{Bytes, BytesMut};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::{stream, StreamExt};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::{Auth, COMPUTE};
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::tenants::TENANT_HEADER;
+
+/// Bodies above this size are never mirrored
+const MAX_BODY: u64 = 64 * 1024;
+
+/// Marks requests sent by the mirror, so the shadow can tell them apart
+const MIRRORED_HEADER: &str = "x-mirrored";
+
+/// Request headers passed on to the shadow, credentials never are
+const FORWARDED: &[&str] = &[
+    "content-type",
+    "accept-language",
+    TENANT_HEADER,
+    REQUEST_ID_HEADER,
+];
+
+/// Shadow instance a sample of compute requests is copied to
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MirrorSettings {
+    /// Base URL of the shadow, e.g. `http://shadow:3030`, the request path is appended
+    pub url: String,
+    /// Share of compute requests copied, from 0 to 1
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+    /// Milliseconds a mirrored request may take before it is given up
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_sample_ratio() -> f64 {
+    0.1
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+impl Default for MirrorSettings {
+    fn default() -> Self {
+        MirrorSettings {
+            url: String::new(),
+            sample_ratio: default_sample_ratio(),
+            timeout_ms: default_timeout_ms(),
+        }
+    }
+}
+
+/// Sends copies of requests to the shadow, its answers are dropped
+pub struct Mirror {
+    url: String,
+    sample_ratio: f64,
+    timeout: Duration,
+}
+
+impl Mirror {
+    pub fn new(settings: &MirrorSettings) -> Self {
+        Mirror {
+            url: settings.url.trim_end_matches('/').to_string(),
+            sample_ratio: settings.sample_ratio,
+            timeout: Duration::from_millis(settings.timeout_ms),
+        }
+    }
+
+    /// POST compute requests of callers identified with `compute`, with a known, small
+    /// enough body, picked at `sample_ratio`
+    fn wants(&self, req: &ServiceRequest) -> bool {
+        let allowed = match req.extensions().get::<Auth>() {
+            Some(Auth::Identified(identity)) => identity.has_role(COMPUTE),
+            _ => false,
+        };
+        let small = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .map_or(false, |len| len <= MAX_BODY);
+        allowed
+            && small
+            && *req.method() == Method::POST
+            && req.path().starts_with("/v1/compute")
+            && rand::random::<f64>() < self.sample_ratio
+    }
+
+    fn send(&self, path_and_query: String, headers: &HeaderMap, body: Bytes) {
+        let url = format!("{}{}", self.url, path_and_query);
+        let client = Client::build().timeout(self.timeout).finish();
+        let mut request = client.post(url.as_str()).header(MIRRORED_HEADER, "1");
+        for name in FORWARDED {
+            if let Some(value) = headers.get(*name) {
+                request = request.header(*name, value.clone());
+            }
+        }
+        actix_rt::spawn(async move {
+            match request.send_body(body).await {
+                Ok(resp) => debug!("Mirrored to {}: {}", url, resp.status()),
+                Err(e) => debug!("Could not mirror to {}: {:?}", url, e),
+            }
+        });
+    }
+}
+
+/// Middleware copying a sample of compute requests to `Mirror`
+pub struct MirrorTraffic(pub Arc<Mirror>);
+
+impl<S, B> Transform<S> for MirrorTraffic
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MirrorTrafficMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MirrorTrafficMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            mirror: self.0.clone(),
+        })
+    }
+}
+
+pub struct MirrorTrafficMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    mirror: Arc<Mirror>,
+}
+
+impl<S, B> Service for MirrorTrafficMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        if !self.mirror.wants(&req) {
+            return Box::pin(self.service.borrow_mut().call(req));
+        }
+        let service = self.service.clone();
+        let mirror = self.mirror.clone();
+
+        Box::pin(async move {
+            let mut body = BytesMut::new();
+            let mut payload = req.take_payload();
+            while let Some(chunk) = payload.next().await {
+                body.extend_from_slice(&chunk?);
+            }
+            let body = body.freeze();
+            let replay: PayloadStream = Box::pin(stream::once(futures::future::ok::<
+                _,
+                PayloadError,
+            >(body.clone())));
+            req.set_payload(Payload::from(replay));
+
+            let path_and_query = req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.path().to_string(), |p| p.to_string());
+            mirror.send(path_and_query, req.headers(), body);
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Identity;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn samples_small_compute_posts() {
+        let mirror = |sample_ratio| {
+            Mirror::new(&MirrorSettings {
+                url: "http://shadow:3030/".into(),
+                sample_ratio,
+                ..MirrorSettings::default()
+            })
+        };
+        let post = |path: &str, len: &str| {
+            let req = TestRequest::post()
+                .uri(path)
+                .header(header::CONTENT_LENGTH, len)
+                .to_srv_request();
+            req.extensions_mut().insert(Auth::Identified(Identity {
+                name: "partner".into(),
+                roles: vec![COMPUTE.into()],
+            }));
+            req
+        };
+
+        let always = mirror(1.0);
+        assert_eq!(always.url, "http://shadow:3030");
+        assert!(always.wants(&post("/v1/compute", "20")));
+        assert!(always.wants(&post("/v1/compute/batch", "20")));
+        assert!(!always.wants(&post("/v1/schedules", "20")));
+        assert!(!always.wants(&post("/v1/compute", "1000000")));
+        assert!(!mirror(0.0).wants(&post("/v1/compute", "20")));
+
+        let anonymous = TestRequest::post()
+            .uri("/v1/compute")
+            .header(header::CONTENT_LENGTH, "20")
+            .to_srv_request();
+        assert!(!always.wants(&anonymous));
+    }
+}
@@ -0,0 +1,27 @@
+//! The server always runs in the foreground, meant to be supervised by systemd or an init
+//! script. `--pid-file FILE` writes its pid once listening and removes it on exit. Signals:
+//! SIGINT/SIGTERM drain and stop, SIGHUP reloads the config.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use log::warn;
+
+/// Pid file of the running process, removed again when dropped
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("Could not remove pid file {:?}: {:?}", self.0, e);
+        }
+    }
+}
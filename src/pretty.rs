@@ -0,0 +1,91 @@
+//! Any endpoint returns indented JSON with `?pretty=true`.
+
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{web, Error};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_derive::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PrettyQuery {
+    #[serde(default)]
+    pub pretty: bool,
+}
+
+/// Middleware re-indenting JSON response bodies when the request has `?pretty=true`.
+/// Works on plain `Body`, so it has to be registered right after `Enveloping`.
+pub struct PrettyJson;
+
+impl<S> Transform<S> for PrettyJson
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = PrettyJsonMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(PrettyJsonMiddleware { service })
+    }
+}
+
+pub struct PrettyJsonMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service for PrettyJsonMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let pretty = web::Query::<PrettyQuery>::from_query(req.query_string())
+            .map(|q| q.pretty)
+            .unwrap_or(false);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            if !pretty || !is_json(&res) {
+                return Ok(res);
+            }
+
+            Ok(res.map_body(|_, body| match body {
+                ResponseBody::Body(Body::Bytes(bytes)) => ResponseBody::Body(indent(bytes)),
+                body => body,
+            }))
+        })
+    }
+}
+
+pub fn is_json(res: &ServiceResponse<Body>) -> bool {
+    res.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("application/json"))
+}
+
+fn indent(bytes: bytes::Bytes) -> Body {
+    match serde_json::from_slice::<serde_json::Value>(&bytes)
+        .and_then(|v| serde_json::to_vec_pretty(&v))
+    {
+        Ok(pretty) => Body::from(pretty),
+        Err(_) => Body::Bytes(bytes),
+    }
+}
@@ -0,0 +1,114 @@
+//! `GET /admin/stats/process` reports uptime, resident memory (current and peak), the data
+//! segment holding the heap, threads, open descriptors and sockets from `/proc/self`, and
+//! the requests in flight and queued when `max_in_flight` is set.
+
+use std::fs;
+use std::sync::Arc;
+use std::time::Instant;
+
+use actix_web::{web, HttpResponse};
+use serde_derive::Serialize;
+
+use crate::limit::Limiter;
+
+/// What `/admin/stats/process` reports on besides `/proc/self`
+pub struct Process {
+    started: Instant,
+    /// Limiter of `max_in_flight`, none if requests are not limited
+    limiter: Option<Arc<Limiter>>,
+}
+
+/// Figures of the running process, absent where `/proc` does not offer them
+#[derive(Debug, Serialize)]
+pub struct ProcessStats {
+    pub uptime_secs: u64,
+    pub rss_bytes: Option<u64>,
+    pub peak_rss_bytes: Option<u64>,
+    /// Data segment, the heap of the system allocator included
+    pub heap_bytes: Option<u64>,
+    pub threads: Option<u64>,
+    pub open_fds: Option<usize>,
+    /// Open sockets, the listeners included
+    pub open_sockets: Option<usize>,
+    /// Requests holding a `max_in_flight` slot, none if that is not set
+    pub in_flight: Option<usize>,
+    /// Requests waiting for a slot
+    pub queued: Option<usize>,
+}
+
+/// Value of `field` in `/proc/self/status` text, converted from kB if given in kB
+fn status_field(status: &str, field: &str) -> Option<u64> {
+    let line = status
+        .lines()
+        .find(|line| line.split(':').next() == Some(field))?;
+    let mut words = line.splitn(2, ':').nth(1)?.split_whitespace();
+    let value: u64 = words.next()?.parse().ok()?;
+    match words.next() {
+        Some("kB") => Some(value * 1024),
+        _ => Some(value),
+    }
+}
+
+/// Open file descriptors, and how many of them are sockets
+fn descriptors() -> Option<(usize, usize)> {
+    let mut fds = 0;
+    let mut sockets = 0;
+    for entry in fs::read_dir("/proc/self/fd").ok()? {
+        fds += 1;
+        let target = entry.ok().and_then(|e| fs::read_link(e.path()).ok());
+        if target.map_or(false, |t| t.to_string_lossy().starts_with("socket:")) {
+            sockets += 1;
+        }
+    }
+    Some((fds, sockets))
+}
+
+impl Process {
+    pub fn new(limiter: Option<Arc<Limiter>>) -> Self {
+        Process {
+            started: Instant::now(),
+            limiter,
+        }
+    }
+
+    pub fn stats(&self) -> ProcessStats {
+        let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let descriptors = descriptors();
+        let load = self.limiter.as_ref().map(|limiter| limiter.load());
+        ProcessStats {
+            uptime_secs: self.started.elapsed().as_secs(),
+            rss_bytes: status_field(&status, "VmRSS"),
+            peak_rss_bytes: status_field(&status, "VmHWM"),
+            heap_bytes: status_field(&status, "VmData"),
+            threads: status_field(&status, "Threads"),
+            open_fds: descriptors.map(|(fds, _)| fds),
+            open_sockets: descriptors.map(|(_, sockets)| sockets),
+            in_flight: load.map(|(in_flight, _)| in_flight),
+            queued: load.map(|(_, queued)| queued),
+        }
+    }
+}
+
+pub async fn stats(process: web::Data<Process>) -> HttpResponse {
+    HttpResponse::Ok().json(process.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_status_fields() {
+        let status =
+            "Name:\trest-test-params\nVmHWM:\t   20480 kB\nVmRSS:\t   10240 kB\nThreads:\t5\n";
+        assert_eq!(status_field(status, "VmRSS"), Some(10240 * 1024));
+        assert_eq!(status_field(status, "VmHWM"), Some(20480 * 1024));
+        assert_eq!(status_field(status, "Threads"), Some(5));
+        assert_eq!(status_field(status, "VmData"), None);
+
+        let limiter = Arc::new(Limiter::new(4, 8));
+        let stats = Process::new(Some(limiter)).stats();
+        assert_eq!(stats.in_flight, Some(0));
+        assert_eq!(stats.queued, Some(0));
+    }
+}
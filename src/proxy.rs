@@ -0,0 +1,155 @@
+//! Behind a load balancer list it in `trusted_proxies` (CIDRs), the client address for
+//! logs, rate limits and deduplication is then taken from `Forwarded`/`X-Forwarded-For`.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{header, HeaderMap};
+use actix_web::HttpMessage;
+use ipnet::{AddrParseError, IpNet};
+
+/// Request header the resolved client address is put in, overwriting any sent value
+pub const CLIENT_IP_HEADER: &str = "x-client-ip";
+
+/// `Logger` format using the resolved client address instead of the peer, the
+/// authenticated caller (`-` if none), the request line with redacted query and the
+/// request id
+pub const LOG_FORMAT: &str = r#"%{x-client-ip}i %{x-identity}i "%{x-request-line}i" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}i"#;
+
+/// Address of the client behind trusted proxies, kept in request extensions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Proxies whose `Forwarded`/`X-Forwarded-For` headers are believed
+pub struct TrustedProxies(Vec<IpNet>);
+
+/// Parses CIDRs like `10.0.0.0/8`, a plain address stands for just itself
+pub fn parse_nets(cidrs: &[String]) -> Result<Vec<IpNet>, AddrParseError> {
+    cidrs
+        .iter()
+        .map(|c| match IpAddr::from_str(c) {
+            Ok(ip) => Ok(IpNet::from(ip)),
+            Err(_) => IpNet::from_str(c),
+        })
+        .collect()
+}
+
+impl TrustedProxies {
+    pub fn new(cidrs: &[String]) -> Result<Self, AddrParseError> {
+        parse_nets(cidrs).map(TrustedProxies)
+    }
+
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    /// Walks the forwarded chain from the `peer` backwards, the first hop not
+    /// being a trusted proxy is the client
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(&peer) {
+            return peer;
+        }
+        let hops = forwarded_for(headers);
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            client = hop;
+            if !self.trusts(&hop) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Stores the client address of `req` as `ClientIp` and in `CLIENT_IP_HEADER`
+    pub fn tag(&self, req: &mut ServiceRequest) {
+        let peer = match req.peer_addr() {
+            Some(addr) => addr.ip(),
+            None => return,
+        };
+        let ip = self.resolve(peer, req.headers());
+        req.extensions_mut().insert(ClientIp(ip));
+        if let Ok(v) = header::HeaderValue::from_str(&ip.to_string()) {
+            req.headers_mut()
+                .insert(header::HeaderName::from_static(CLIENT_IP_HEADER), v);
+        }
+    }
+}
+
+/// Hops of `Forwarded` or else `X-Forwarded-For`, closest to the client first
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    let forwarded: Vec<_> = headers
+        .get_all(header::FORWARDED)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|hop| {
+            hop.split(';')
+                .filter_map(|pair| {
+                    let mut kv = pair.trim().splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k.eq_ignore_ascii_case("for") => Some(v),
+                        _ => None,
+                    }
+                })
+                .next()
+        })
+        .filter_map(parse_node)
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(parse_node)
+        .collect()
+}
+
+/// Address of a node like `192.0.2.1`, `"192.0.2.1:80"` or `"[2001:db8::1]:80"`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(ip);
+    }
+    let host = match node.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?,
+        None => node.rsplitn(2, ':').last()?,
+    };
+    IpAddr::from_str(host).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert(
+            header::HeaderName::from_static(name),
+            header::HeaderValue::from_static(value),
+        );
+        map
+    }
+
+    #[test]
+    fn resolves_behind_trusted_proxies() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".into()]).unwrap();
+        let lb: IpAddr = "10.0.0.1".parse().unwrap();
+        let xff = headers("x-forwarded-for", "6.6.6.6, 203.0.113.7, 10.1.1.1");
+
+        assert_eq!(
+            proxies.resolve(lb, &xff),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        let outsider: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(proxies.resolve(outsider, &xff), outsider);
+
+        let fwd = headers("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#);
+        assert_eq!(
+            proxies.resolve(lb, &fwd),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
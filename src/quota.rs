@@ -0,0 +1,208 @@
+//! `key_limits` caps requests of an identity `per_minute` and `per_day` (UTC), answering
+//! `429` with `Retry-After`. Its responses, errors included, carry `X-RateLimit-*` headers
+//! for the limit with the fewest requests left, in place of the client address ones. Daily
+//! usage is kept in `usage_file` across restarts.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web;
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::ratelimit::Budget;
+
+/// Seconds between writes of the usage file
+const PERSIST_INTERVAL: u64 = 60;
+
+/// Limits of one caller, unset ones do not apply
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct KeyLimit {
+    pub per_minute: Option<u32>,
+    pub per_day: Option<u64>,
+}
+
+/// Requests of a caller in the current minute and UTC day
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct Usage {
+    #[serde(skip)]
+    minute: i64,
+    #[serde(skip)]
+    this_minute: u32,
+    day: Option<NaiveDate>,
+    today: u64,
+}
+
+/// Why a request was refused, `budget` tells how long the caller should wait
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exceeded {
+    pub reason: &'static str,
+    pub budget: Budget,
+}
+
+/// Per-identity request limits, daily usage survives restarts through `file`
+pub struct Quotas {
+    limits: HashMap<String, KeyLimit>,
+    file: Option<String>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl Quotas {
+    /// Starts from the usage saved in `file`, if any
+    pub fn load(limits: HashMap<String, KeyLimit>, file: Option<String>) -> Self {
+        let usage = file
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Quotas {
+            limits,
+            file,
+            usage: Mutex::new(usage),
+        }
+    }
+
+    /// Counts a request of `identity` unless it is over one of its limits, the budget
+    /// is that of the limit with the fewest requests left, `None` if there are none
+    pub fn admit(&self, identity: &str, now: DateTime<Utc>) -> Result<Option<Budget>, Exceeded> {
+        let limit = match self.limits.get(identity) {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(identity.to_string()).or_default();
+        let (minute, day) = (now.timestamp() / 60, now.date().naive_utc());
+        if usage.minute != minute {
+            usage.minute = minute;
+            usage.this_minute = 0;
+        }
+        if usage.day != Some(day) {
+            usage.day = Some(day);
+            usage.today = 0;
+        }
+
+        let reset = |period: i64| (period - now.timestamp().rem_euclid(period)) as u64;
+        let windows = [
+            (
+                limit.per_day,
+                usage.today,
+                reset(86_400),
+                "Daily quota exhausted",
+            ),
+            (
+                limit.per_minute.map(u64::from),
+                u64::from(usage.this_minute),
+                reset(60),
+                "Rate limit exceeded",
+            ),
+        ];
+        let windows = windows
+            .iter()
+            .filter_map(|&(max, used, reset, reason)| max.map(|max| (max, used, reset, reason)));
+
+        let exhausted = windows.clone().find(|&(max, used, ..)| used >= max);
+        if let Some((max, _, reset, reason)) = exhausted {
+            return Err(Exceeded {
+                reason,
+                budget: Budget {
+                    limit: max,
+                    remaining: 0,
+                    reset,
+                    retry_after: Some(reset),
+                },
+            });
+        }
+        usage.this_minute += 1;
+        usage.today += 1;
+        Ok(windows
+            .map(|(max, used, reset, _)| Budget {
+                limit: max,
+                remaining: max - used - 1,
+                reset,
+                retry_after: None,
+            })
+            .min_by_key(|budget| budget.remaining))
+    }
+
+    /// Writes the daily usage to `file`
+    pub fn save(&self) {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return,
+        };
+        let json = serde_json::to_vec(&*self.usage.lock().unwrap()).unwrap_or_default();
+        if let Err(e) = fs::write(path, json) {
+            warn!("Could not save usage to {}: {:?}", path, e);
+        }
+    }
+}
+
+/// Saves usage every `PERSIST_INTERVAL` seconds, so a crash loses at most that much
+pub fn spawn_persist(quotas: web::Data<Quotas>) {
+    if quotas.file.is_none() {
+        return;
+    }
+    actix_rt::spawn(async move {
+        let mut tick = actix_rt::time::interval(Duration::from_secs(PERSIST_INTERVAL));
+        loop {
+            tick.tick().await;
+            quotas.save();
+        }
+    });
+    info!("Persisting API usage every {}s", PERSIST_INTERVAL);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn enforces_minute_and_day() {
+        let limits = vec![(
+            "partner".to_string(),
+            KeyLimit {
+                per_minute: Some(2),
+                per_day: Some(3),
+            },
+        )];
+        let quotas = Quotas::load(limits.into_iter().collect(), None);
+        let at = |h, m, s| Utc.ymd(2020, 8, 1).and_hms(h, m, s);
+
+        assert!(quotas.admit("partner", at(10, 0, 0)).is_ok());
+        assert_eq!(
+            quotas.admit("partner", at(10, 0, 1)),
+            Ok(Some(Budget {
+                limit: 2,
+                remaining: 0,
+                reset: 59,
+                retry_after: None,
+            }))
+        );
+        assert_eq!(
+            quotas.admit("partner", at(10, 0, 50)),
+            Err(Exceeded {
+                reason: "Rate limit exceeded",
+                budget: Budget {
+                    limit: 2,
+                    remaining: 0,
+                    reset: 10,
+                    retry_after: Some(10),
+                },
+            })
+        );
+        assert!(quotas.admit("partner", at(10, 1, 0)).is_ok());
+        assert_eq!(
+            quotas.admit("partner", at(10, 2, 0)).unwrap_err().reason,
+            "Daily quota exhausted"
+        );
+        assert!(quotas
+            .admit("partner", at(0, 0, 0) + chrono::Duration::days(1))
+            .is_ok());
+        assert_eq!(quotas.admit("other", at(10, 2, 0)), Ok(None));
+    }
+}
@@ -0,0 +1,291 @@
+//! `[rate_limit]` gives every client address (behind `trusted_proxies` the forwarded one) a
+//! token bucket of `burst` requests refilled at `per_second`, answering `429` with
+//! `Retry-After`. Responses, errors included, carry `X-RateLimit-Limit` (`burst`),
+//! `X-RateLimit-Remaining` and `X-RateLimit-Reset`, the seconds until the bucket is full
+//! again.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderName};
+use actix_web::http::{HeaderMap, StatusCode};
+use actix_web::{Error, HttpMessage};
+use futures::future::{err, ok, Either, LocalBoxFuture, Ready};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::json_error;
+use crate::proxy::ClientIp;
+
+/// Clients tracked before idle ones are forgotten
+const MAX_CLIENTS: usize = 10_000;
+
+/// Token bucket per client address
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RateLimitSettings {
+    /// Sustained requests per second
+    pub per_second: f64,
+    /// Requests a client may send at once after being idle
+    pub burst: u32,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            per_second: 10.0,
+            burst: 20,
+        }
+    }
+}
+
+/// State of a client's bucket or a caller's quota after a request, sent as
+/// `X-RateLimit-*` headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    /// Requests a full bucket or quota window holds
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the bucket is full again or the window starts over
+    pub reset: u64,
+    /// Seconds until a request is admitted, set if this one was refused
+    pub retry_after: Option<u64>,
+}
+
+impl Budget {
+    /// Leaves headers already written further in, by the caller's quota, as they are
+    pub fn write(&self, headers: &mut HeaderMap) {
+        if headers.contains_key("x-ratelimit-limit") {
+            return;
+        }
+        let pairs = [
+            ("x-ratelimit-limit", self.limit),
+            ("x-ratelimit-remaining", self.remaining),
+            ("x-ratelimit-reset", self.reset),
+        ];
+        for (name, value) in pairs.iter() {
+            headers.insert(HeaderName::from_static(*name), (*value).into());
+        }
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(header::RETRY_AFTER, retry_after.into());
+        }
+    }
+
+    /// Writes the budget to the response of `result`, error responses included
+    pub fn apply<B>(
+        &self,
+        result: Result<ServiceResponse<B>, Error>,
+    ) -> Result<ServiceResponse<B>, Error> {
+        match result {
+            Ok(mut res) => {
+                self.write(res.headers_mut());
+                Ok(res)
+            }
+            Err(e) => {
+                let mut resp = e.as_response_error().error_response();
+                self.write(resp.headers_mut());
+                Err(InternalError::from_response(e.to_string(), resp).into())
+            }
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    /// Tokens per second and bucket size, swapped on config reload
+    limits: RwLock<(f64, f64)>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        RateLimiter {
+            limits: RwLock::new((settings.per_second, f64::from(settings.burst))),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Applies new limits, buckets keep their tokens up to the new burst
+    pub fn set(&self, settings: &RateLimitSettings) {
+        *self.limits.write().unwrap() = (settings.per_second, f64::from(settings.burst));
+    }
+
+    /// Takes a token of `ip` if one is available
+    pub fn take(&self, ip: IpAddr, now: Instant) -> Budget {
+        let (rate, burst) = *self.limits.read().unwrap();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&ip) {
+            buckets.retain(|_, b| {
+                b.tokens + rate * now.saturating_duration_since(b.updated).as_secs_f64() < burst
+            });
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        };
+        Budget {
+            limit: burst as u64,
+            remaining: bucket.tokens.floor() as u64,
+            reset: ((burst - bucket.tokens) / rate).ceil() as u64,
+            retry_after,
+        }
+    }
+}
+
+fn too_many(budget: Budget) -> Error {
+    let mut resp = json_error(StatusCode::TOO_MANY_REQUESTS, "Too many requests.");
+    budget.write(resp.headers_mut());
+    InternalError::from_response("rate limited", resp).into()
+}
+
+/// Middleware answering 429 with `Retry-After` to clients over their rate, and telling
+/// every client its budget in `X-RateLimit-Limit`, `-Remaining` and `-Reset`, on errors
+/// too
+pub struct IpRateLimit(pub Arc<RateLimiter>);
+
+impl<S, B> Transform<S> for IpRateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IpRateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IpRateLimitMiddleware {
+            service,
+            limiter: self.0.clone(),
+        })
+    }
+}
+
+pub struct IpRateLimitMiddleware<S> {
+    service: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service for IpRateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+        Ready<Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let ip = match req.extensions().get::<ClientIp>() {
+            Some(ClientIp(ip)) => Some(*ip),
+            None => req.peer_addr().map(|a| a.ip()),
+        };
+        let budget = match ip.map(|ip| self.limiter.take(ip, Instant::now())) {
+            Some(budget) if budget.retry_after.is_some() => {
+                return Either::Right(err(too_many(budget)))
+            }
+            budget => budget,
+        };
+        let fut = self.service.call(req);
+
+        Either::Left(Box::pin(async move {
+            match budget {
+                Some(budget) => budget.apply(fut.await),
+                None => fut.await,
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::Body;
+    use std::time::Duration;
+
+    #[test]
+    fn refills_at_the_sustained_rate() {
+        let limiter = RateLimiter::new(&RateLimitSettings {
+            per_second: 0.5,
+            burst: 2,
+        });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.take(ip, now).remaining, 1);
+        assert_eq!(
+            limiter.take(ip, now),
+            Budget {
+                limit: 2,
+                remaining: 0,
+                reset: 4,
+                retry_after: None,
+            }
+        );
+        assert_eq!(limiter.take(ip, now).retry_after, Some(2));
+        assert_eq!(
+            limiter.take("10.0.0.2".parse().unwrap(), now).retry_after,
+            None
+        );
+        assert_eq!(
+            limiter.take(ip, now + Duration::from_secs(2)).retry_after,
+            None
+        );
+
+        limiter.set(&RateLimitSettings {
+            per_second: 1.0,
+            burst: 5,
+        });
+        assert_eq!(limiter.take("10.0.0.3".parse().unwrap(), now).limit, 5);
+    }
+
+    #[test]
+    fn writes_the_budget_to_error_responses() {
+        let budget = Budget {
+            limit: 2,
+            remaining: 0,
+            reset: 4,
+            retry_after: None,
+        };
+        let error = InternalError::from_response(
+            "bad",
+            json_error(StatusCode::BAD_REQUEST, "Bad request."),
+        );
+        let error = budget.apply::<Body>(Err(error.into())).err().unwrap();
+        let resp = error.as_response_error().error_response();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers()["x-ratelimit-limit"], "2");
+        assert_eq!(resp.headers()["x-ratelimit-reset"], "4");
+    }
+}
@@ -0,0 +1,111 @@
+//! Values of the params and query parameters listed in `redact`, e.g. `["d"]`, show as
+//! `***` in the access log, in warnings about failed computes and in audit records.
+
+use std::borrow::Cow;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Request header the request line is put in for the access log, with redacted query values
+pub const REQUEST_LINE_HEADER: &str = "x-request-line";
+
+const MASK: &str = "***";
+
+fn masked(name: &str, fields: &[String]) -> bool {
+    fields.iter().any(|f| f.eq_ignore_ascii_case(name))
+}
+
+/// `query` with the values of `fields` masked
+pub fn query<'a>(query: &'a str, fields: &[String]) -> Cow<'a, str> {
+    if fields.is_empty() || query.is_empty() {
+        return Cow::Borrowed(query);
+    }
+    let pairs: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.splitn(2, '=').next() {
+            Some(name) if masked(name, fields) => format!("{}={}", name, MASK),
+            _ => pair.to_string(),
+        })
+        .collect();
+    Cow::Owned(pairs.join("&"))
+}
+
+fn mask(value: &mut Value, fields: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if masked(k, fields) && !v.is_null() {
+                    *v = Value::String(MASK.into());
+                } else {
+                    mask(v, fields);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| mask(v, fields)),
+        _ => {}
+    }
+}
+
+/// `params` as JSON with the values of `fields` masked, e.g. to store them
+pub fn value<T: Serialize>(params: &T, fields: &[String]) -> Value {
+    let mut value = serde_json::to_value(params).unwrap_or(Value::Null);
+    mask(&mut value, fields);
+    value
+}
+
+/// JSON of `params` for log messages, the values of `fields` masked
+pub fn params<T: Serialize>(params: &T, fields: &[String]) -> String {
+    value(params, fields).to_string()
+}
+
+/// Puts the request line of `req` with redacted query in `REQUEST_LINE_HEADER`,
+/// overwriting any sent value. Quotes and backslashes are escaped, as the access log
+/// layouts quote it.
+pub fn tag(req: &mut ServiceRequest, fields: &[String]) {
+    let query = query(req.query_string(), fields);
+    let line = if query.is_empty() {
+        format!("{} {} {:?}", req.method(), req.path(), req.version())
+    } else {
+        format!(
+            "{} {}?{} {:?}",
+            req.method(),
+            req.path(),
+            query,
+            req.version()
+        )
+    };
+    let line = line.replace('\\', "\\\\").replace('"', "\\\"");
+    match header::HeaderValue::from_str(&line) {
+        Ok(v) => {
+            req.headers_mut()
+                .insert(header::HeaderName::from_static(REQUEST_LINE_HEADER), v);
+        }
+        Err(_) => {
+            req.headers_mut().remove(REQUEST_LINE_HEADER);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Params;
+
+    #[test]
+    fn masks_configured_fields() {
+        let fields = vec!["d".to_string()];
+        let p = Params {
+            d: Some(1.5),
+            e: Some(2),
+            ..Params::default()
+        };
+
+        let logged = params(&p, &fields);
+        assert!(logged.contains(r#""d":"***""#));
+        assert!(logged.contains(r#""e":2"#));
+        assert_eq!(query("case=C1&D=4.7", &fields), "case=C1&D=***");
+        assert_eq!(query("case=C1", &[]), "case=C1");
+    }
+}
@@ -0,0 +1,212 @@
+//! Re-reads the config file and environment on SIGHUP or with the call below. The log
+//! filter, `rate_limit` (unless it is turned on or off), `json_limit(s)` and
+//! `request_timeout(s)_ms` apply to the next request, other changed settings are logged and
+//! need a restart. `GET /admin/config` shows the settings in effect.
+//!
+//! ``` curl -X POST localhost:3030/admin/reload ```
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_web::{error, web, Error, HttpResponse};
+use clap::ArgMatches;
+use log::{info, warn};
+use serde_derive::Serialize;
+use serde_json::Value;
+
+use crate::cli;
+use crate::config::{self, Settings};
+use crate::logging::LogControl;
+use crate::ratelimit::RateLimiter;
+use crate::vault;
+
+/// Settings applied to the running server, all others need a restart. `rate_limit` is
+/// live while it stays set, turning it on or off needs a restart. Body limits can be
+/// lowered live, raising one above its startup value needs a restart.
+const LIVE: &[&str] = &[
+    "log_filter",
+    "rate_limit",
+    "json_limit",
+    "json_limits",
+    "request_timeout_ms",
+    "request_timeouts_ms",
+];
+
+/// Body limits and timeouts read on every request, so a reload applies to the next one
+pub struct Live(RwLock<Settings>);
+
+impl Live {
+    pub fn new(settings: Settings) -> Self {
+        Live(RwLock::new(settings))
+    }
+
+    pub fn json_limit_of(&self, path: &str) -> usize {
+        self.0.read().unwrap().json_limit_of(path)
+    }
+
+    pub fn timeout_of(&self, path: &str) -> Duration {
+        self.0.read().unwrap().timeout_of(path)
+    }
+
+    fn set(&self, next: &Settings) {
+        let mut live = self.0.write().unwrap();
+        live.json_limit = next.json_limit;
+        live.json_limits = next.json_limits.clone();
+        live.request_timeout_ms = next.request_timeout_ms;
+        live.request_timeouts_ms = next.request_timeouts_ms.clone();
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Changes {
+    pub applied: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+/// Re-reads the config sources the server was started with
+pub struct Reloader {
+    path: Option<String>,
+    matches: ArgMatches<'static>,
+    current: Mutex<Settings>,
+    log_control: web::Data<LogControl>,
+    live: web::Data<Live>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl Reloader {
+    pub fn new(
+        matches: ArgMatches<'static>,
+        settings: Settings,
+        log_control: web::Data<LogControl>,
+        live: web::Data<Live>,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> Self {
+        Reloader {
+            path: matches.value_of("config").map(String::from),
+            matches,
+            current: Mutex::new(settings),
+            log_control,
+            live,
+            rate_limiter,
+        }
+    }
+
+    /// Applies changed live settings and logs every setting that differs. Vault is read
+    /// again, so its values keep overriding the file and rotated secrets are noticed.
+    pub async fn reload(&self) -> anyhow::Result<Changes> {
+        let next = Settings::load(self.path.as_deref(), self.matches.value_of("profile"))?;
+        let mut next = vault::apply(next)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        cli::apply(&mut next, &self.matches);
+
+        let mut current = self.current.lock().unwrap();
+        let changes = changes(&current, &next);
+
+        if changes.applied.iter().any(|k| k == "log_filter") {
+            self.log_control
+                .set(&next.log_filter)
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            current.log_filter = next.log_filter.clone();
+        }
+        if changes.applied.iter().any(|k| k == "rate_limit") {
+            if let Some(rate_limit) = &next.rate_limit {
+                self.rate_limiter.set(rate_limit);
+            }
+            current.rate_limit = next.rate_limit.clone();
+        }
+        self.live.set(&next);
+        current.json_limit = next.json_limit;
+        current.json_limits = next.json_limits;
+        current.request_timeout_ms = next.request_timeout_ms;
+        current.request_timeouts_ms = next.request_timeouts_ms;
+        Ok(changes)
+    }
+}
+
+/// Settings differing between `current` and `next`, logged with their values unless they
+/// hold secrets, which are compared as they are so rotating one is noticed
+fn changes(current: &Settings, next: &Settings) -> Changes {
+    let old = serde_json::to_value(current).unwrap_or(Value::Null);
+    let new = serde_json::to_value(next).unwrap_or(Value::Null);
+    let mut changes = Changes::default();
+    if let Value::Object(new) = &new {
+        for (key, value) in new {
+            let before = old.get(key).unwrap_or(&Value::Null);
+            if before == value {
+                continue;
+            }
+            if config::holds_secret(key) {
+                info!("Setting {} changed", key);
+            } else {
+                info!("Setting {} changed: {} -> {}", key, before, value);
+            }
+            // the rate limiting middleware is only there if it was on at startup
+            let toggled =
+                key == "rate_limit" && current.rate_limit.is_some() != next.rate_limit.is_some();
+            if LIVE.contains(&key.as_str()) && !toggled {
+                changes.applied.push(key.clone());
+            } else {
+                changes.restart_required.push(key.clone());
+            }
+        }
+    }
+    changes
+}
+
+/// Reloads on every SIGHUP
+pub fn spawn_on_sighup(reloader: web::Data<Reloader>) {
+    actix_rt::spawn(async move {
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(hup) => hup,
+            Err(e) => {
+                warn!("Could not listen for SIGHUP: {:?}", e);
+                return;
+            }
+        };
+        while hup.recv().await.is_some() {
+            match reloader.reload().await {
+                Ok(changes) => info!("Reloaded config: {:?}", changes),
+                Err(e) => warn!("Could not reload config: {:?}", e),
+            }
+        }
+    });
+}
+
+/// Settings in effect, with the live ones as last reloaded
+pub async fn dump(reloader: web::Data<Reloader>) -> HttpResponse {
+    HttpResponse::Ok().json(reloader.current.lock().unwrap().redacted())
+}
+
+pub async fn reload(reloader: web::Data<Reloader>) -> Result<HttpResponse, Error> {
+    let changes = reloader
+        .reload()
+        .await
+        .map_err(|e| error::ErrorBadRequest(format!("Could not reload config: {}", e)))?;
+    Ok(HttpResponse::Ok().json(changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_rotated_secrets() {
+        let current = Settings {
+            admin_bootstrap_secret: Some("before".into()),
+            ..Settings::default()
+        };
+        let next = Settings {
+            admin_bootstrap_secret: Some("after".into()),
+            json_limit: current.json_limit * 2,
+            ..current.clone()
+        };
+        let changes = changes(&current, &next);
+
+        assert_eq!(changes.applied, vec!["json_limit"]);
+        assert_eq!(changes.restart_required, vec!["admin_bootstrap_secret"]);
+        assert!(config::holds_secret("tls"));
+        assert!(!config::holds_secret("key_file"));
+    }
+}
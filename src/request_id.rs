@@ -0,0 +1,213 @@
+//! Every request gets an id, the incoming `X-Request-Id` if it is up to 128 letters, digits
+//! or `-_.:`, a random one otherwise. It is sent back in `X-Request-Id`, added as
+//! `request_id` to JSON error bodies, ends the access log line and is a field of the
+//! `request` span.
+
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_json::Value;
+
+/// Taken from the request if it is a sensible one, generated otherwise, and sent back
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest client-sent id that is kept
+const MAX_LEN: usize = 128;
+
+/// Id of the request, kept in request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+fn acceptable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// The incoming `X-Request-Id` of `req`, a random one if it has none or a malformed one
+fn of(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| acceptable(id))
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()))
+}
+
+/// Adds `request_id` to a JSON object body, leaves anything else alone
+fn with_id(bytes: bytes::Bytes, id: &str) -> Body {
+    match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut map)) => {
+            map.insert("request_id".into(), Value::String(id.into()));
+            Body::from(Value::Object(map).to_string())
+        }
+        _ => Body::Bytes(bytes),
+    }
+}
+
+/// Middleware giving every request an id, in `REQUEST_ID_HEADER` of the request for the
+/// access log and of the response. Errors of the services it wraps are turned into
+/// responses here so they carry it too.
+pub struct AssignRequestId;
+
+impl<S, B> Transform<S> for AssignRequestId
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AssignRequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AssignRequestIdMiddleware { service })
+    }
+}
+
+pub struct AssignRequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AssignRequestIdMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let id = of(&req);
+        // only made of header-safe characters, see `acceptable`
+        let value = HeaderValue::from_str(&id).unwrap();
+        let name = HeaderName::from_static(REQUEST_ID_HEADER);
+        req.headers_mut().insert(name.clone(), value.clone());
+        req.extensions_mut().insert(RequestId(id.clone()));
+        let request = req.request().clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = match fut.await {
+                Ok(res) => res,
+                Err(e) => ServiceResponse::from_err(e, request).map_body(|_, body| match body {
+                    ResponseBody::Other(Body::Bytes(bytes)) => {
+                        ResponseBody::Other(with_id(bytes, &id))
+                    }
+                    body => body,
+                }),
+            };
+            res.headers_mut().insert(name, value);
+            Ok(res)
+        })
+    }
+}
+
+/// Middleware adding the request id to JSON error bodies of handlers. Works on plain
+/// `Body`, so it is registered among the body rewriting middlewares.
+pub struct TagErrors;
+
+impl<S> Transform<S> for TagErrors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TagErrorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TagErrorsMiddleware { service })
+    }
+}
+
+pub struct TagErrorsMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service for TagErrorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let id = req.extensions().get::<RequestId>().map(|r| r.0.clone());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            let id = match id {
+                Some(id) if res.status().is_client_error() || res.status().is_server_error() => id,
+                _ => return Ok(res),
+            };
+            Ok(res.map_body(|_, body| match body {
+                ResponseBody::Body(Body::Bytes(bytes)) => ResponseBody::Body(with_id(bytes, &id)),
+                body => body,
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn propagates_and_tags_errors() {
+        let mut app =
+            test::init_service(App::new().wrap(TagErrors).wrap(AssignRequestId).route(
+                "/",
+                web::get().to(|| async {
+                    HttpResponse::BadRequest().json(serde_json::json!({"code": 400}))
+                }),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER], "abc-123");
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["request_id"], "abc-123");
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "bad id")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER].len(), 32);
+    }
+}
@@ -0,0 +1,89 @@
+//! Valid A/B/C combinations of a case with the params they need and the formula applied:
+//!
+//! ``` curl 'localhost:3030/v1/cases/C2/requirements' ```
+
+use actix_web::{error, web, Error, HttpResponse};
+use serde_derive::Serialize;
+use serde_json::Value;
+
+use crate::classify;
+use crate::types::*;
+
+/// What a valid A/B/C combination of a case needs and computes
+#[derive(Debug, Serialize)]
+pub struct Requirement {
+    pub a: bool,
+    pub b: bool,
+    pub c: bool,
+    pub h: H,
+    pub required: &'static [&'static str],
+    pub formula: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Matrix {
+    pub case: Case,
+    pub combinations: Vec<Requirement>,
+}
+
+/// Params `output` reads and the formula it applies, kept in sync with `output`
+fn rule(case: &Case, h: &H) -> Option<(&'static [&'static str], &'static str)> {
+    match (case, h) {
+        (Case::C2, H::M) => Some((&["D", "E", "F"], "K = F + D + (D * E / 100)")),
+        (_, H::M) => Some((&["D", "E"], "K = D + (D * E / 10)")),
+        (Case::C1, H::P) => Some((&["D", "E", "F"], "K = 2D + (D * E / 100)")),
+        (_, H::P) => Some((&["D", "E", "F"], "K = D + (D * (E - F) / 25.5)")),
+        (_, H::T) => Some((&["D", "F"], "K = D - (D * F / 30)")),
+        (_, H::E) => None,
+    }
+}
+
+/// Every A/B/C combination `classify` accepts for `case`
+pub fn matrix(case: Case) -> Matrix {
+    let mut combinations = vec![];
+    for &a in &[true, false] {
+        for &b in &[true, false] {
+            for &c in &[true, false] {
+                let p = Params {
+                    a: Some(a),
+                    b: Some(b),
+                    c: Some(c),
+                    ..Params::default()
+                };
+                let h = classify(&p, &case);
+                if let Some((required, formula)) = rule(&case, &h) {
+                    combinations.push(Requirement {
+                        a,
+                        b,
+                        c,
+                        h,
+                        required,
+                        formula,
+                    });
+                }
+            }
+        }
+    }
+    Matrix { case, combinations }
+}
+
+pub async fn get(case: web::Path<String>) -> Result<HttpResponse, Error> {
+    match serde_json::from_value::<Case>(Value::String(case.clone())) {
+        Ok(case) => Ok(HttpResponse::Ok().json(matrix(case))),
+        Err(_) => Err(error::ErrorNotFound(format!("No case {}", case))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c2_has_two_m_combinations() {
+        let m = matrix(Case::C2);
+
+        assert_eq!(m.combinations.len(), 4);
+        assert_eq!(m.combinations.iter().filter(|r| r.h == H::M).count(), 2);
+        assert_eq!(matrix(Case::B).combinations.len(), 3);
+    }
+}
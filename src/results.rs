@@ -0,0 +1,68 @@
+//! Successful computes point to their stored result in `Content-Location`, e.g.
+//! `/v1/results/{id}`, retrievable for an hour.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header;
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+
+use crate::auth::Caller;
+use crate::etag;
+use crate::types::Output;
+
+/// Compute results kept for re-fetching until `ttl` passes, shared between workers
+pub struct ResultStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Option<String>, Output)>>,
+}
+
+impl ResultStore {
+    pub fn new(ttl: Duration) -> Self {
+        ResultStore {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stores `output` of `owner`, dropping expired results, and returns its id
+    pub fn insert(&self, output: Output, owner: Option<&str>) -> String {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, (at, _, _)| at.elapsed() < ttl);
+        let owner = owner.map(String::from);
+        entries.insert(id.clone(), (Instant::now(), owner, output));
+        id
+    }
+
+    /// Result `id` if `caller` may see it
+    pub fn get(&self, id: &str, caller: &Caller) -> Option<Output> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(id)
+            .filter(|(at, owner, _)| at.elapsed() < self.ttl && caller.may_see(owner.as_deref()))
+            .map(|(_, _, output)| output.clone())
+    }
+}
+
+/// Results of others are as unknown as expired ones, `304` if the client holds the result
+pub async fn get(
+    id: web::Path<String>,
+    store: web::Data<ResultStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    match store.get(&id, &Caller::of(&req)) {
+        Some(output) => {
+            let tag = etag::of(&output);
+            if etag::matches(&req, &tag) {
+                return Ok(HttpResponse::NotModified()
+                    .header(header::ETAG, tag)
+                    .finish());
+            }
+            Ok(HttpResponse::Ok().header(header::ETAG, tag).json(output))
+        }
+        None => Err(error::ErrorNotFound(format!("No result {}", id))),
+    }
+}
@@ -0,0 +1,443 @@
+//! Recurring computes, results kept per schedule and optionally POSTed to a webhook:
+//!
+//! ``` curl -H "Content-Type: application/json" -X POST -d '{"cron": "0 */5 * * * *", "params": {"a":true,"b":true,"c":false,"d":1.5,"e":2}, "webhook": "https://hooks.example.com/run"}' localhost:3030/v1/schedules ```
+//!
+//! Webhooks must be http(s) URLs of public hosts, `webhook_private_hosts = true` lets them
+//! reach loopback and private addresses (e.g. for local tests). The host is resolved again
+//! before every delivery and retry, runs to a host that turned internal are dead-lettered.
+//! A client keeps up to `schedules_per_owner` (100) schedules, more are answered `429`.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::client::Client;
+use actix_web::error::BlockingError;
+use actix_web::http::Uri;
+use actix_web::{error, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info_span;
+use tracing_futures::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::auth::Caller;
+use crate::breaker::Breakers;
+use crate::config::Settings;
+use crate::deadletter::{DeadLetters, Work};
+use crate::telemetry::{self, TraceContext};
+use crate::types::{Case, Output, Params};
+
+/// Runs kept per schedule
+const HISTORY: usize = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleRequest {
+    /// Cron expression with seconds, e.g. `0 */5 * * * *`
+    pub cron: String,
+    pub params: Params,
+    /// URL every run is POSTed to
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleRun {
+    pub at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<Output>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Schedule {
+    pub id: u64,
+    pub cron: String,
+    pub params: Params,
+    pub webhook: Option<String>,
+    pub next_run: Option<DateTime<Utc>>,
+    pub runs: VecDeque<ScheduleRun>,
+    /// Identity that created the schedule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Trace of the request that created the schedule, continued by its webhook calls
+    #[serde(skip)]
+    trace: Option<TraceContext>,
+    #[serde(skip)]
+    expr: cron::Schedule,
+}
+
+/// Recurring computes, shared between workers and the runner
+#[derive(Default)]
+pub struct Schedules {
+    next_id: AtomicU64,
+    entries: Mutex<HashMap<u64, Schedule>>,
+}
+
+impl Schedules {
+    /// Adds a schedule of `owner`, refused with a bad cron expression or once the owner
+    /// has `max` of them
+    fn insert(
+        &self,
+        req: ScheduleRequest,
+        owner: Option<&str>,
+        trace: Option<TraceContext>,
+        max: usize,
+    ) -> Result<Schedule, Error> {
+        let expr = cron::Schedule::from_str(&req.cron)
+            .map_err(|e| error::ErrorBadRequest(format!("Wrong cron expression: {}", e)))?;
+        let mut entries = self.entries.lock().unwrap();
+        let owned = entries.values().filter(|s| s.owner.as_deref() == owner);
+        if owned.count() >= max {
+            return Err(error::ErrorTooManyRequests(format!(
+                "No more than {} schedules per client",
+                max
+            )));
+        }
+        let schedule = Schedule {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst) + 1,
+            next_run: expr.upcoming(Utc).next(),
+            cron: req.cron,
+            params: req.params,
+            webhook: req.webhook,
+            runs: VecDeque::new(),
+            owner: owner.map(String::from),
+            trace,
+            expr,
+        };
+        entries.insert(schedule.id, schedule.clone());
+        Ok(schedule)
+    }
+
+    /// Computes every schedule that is due, returning the runs to deliver with the webhook
+    /// and trace of their schedule
+    fn run_due(
+        &self,
+        now: DateTime<Utc>,
+        default: &Case,
+    ) -> Vec<(Option<String>, Option<TraceContext>, ScheduleRun)> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut done = vec![];
+        for schedule in entries.values_mut() {
+            if schedule.next_run.map_or(true, |at| at > now) {
+                continue;
+            }
+            let run = match crate::compute(&schedule.params, default) {
+                Ok(output) => ScheduleRun {
+                    at: now,
+                    output: Some(output),
+                    error: None,
+                },
+                Err(e) => ScheduleRun {
+                    at: now,
+                    output: None,
+                    error: Some(e.to_string()),
+                },
+            };
+            schedule.runs.push_front(run.clone());
+            schedule.runs.truncate(HISTORY);
+            schedule.next_run = schedule.expr.after(&now).next();
+            done.push((schedule.webhook.clone(), schedule.trace.clone(), run));
+        }
+        done
+    }
+}
+
+/// Ticks every second, computing due schedules and delivering their webhooks
+pub fn spawn_runner(
+    schedules: web::Data<Schedules>,
+    breakers: web::Data<Breakers>,
+    dead_letters: web::Data<DeadLetters>,
+    default_case: Case,
+    private_hosts: bool,
+) {
+    actix_rt::spawn(async move {
+        let mut tick = actix_rt::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            for (webhook, trace, run) in schedules.run_due(Utc::now(), &default_case) {
+                if let Some(url) = webhook {
+                    actix_rt::spawn(deliver(
+                        breakers.clone(),
+                        dead_letters.clone(),
+                        url,
+                        trace,
+                        run,
+                        private_hosts,
+                    ));
+                }
+            }
+        }
+    });
+}
+
+/// POSTs `body` to a webhook, passing the trace of the run on. The webhook is checked again
+/// first, its host may resolve to another address than when the schedule was created.
+pub async fn send(
+    url: &str,
+    trace: Option<&TraceContext>,
+    body: &Value,
+    private_hosts: bool,
+) -> Result<(), String> {
+    check(url.to_string(), private_hosts)
+        .await
+        .map_err(|reason| format!("Refusing to deliver to {}: {}", url, reason))?;
+    let span = info_span!("webhook", url = url);
+    if let Some(trace) = trace {
+        span.set_parent(trace.context());
+    }
+    let mut request = Client::new().post(url);
+    for (name, value) in telemetry::outbound(&span, trace) {
+        request = request.header(name.as_str(), value);
+    }
+    match request.send_json(body).instrument(span).await {
+        Ok(resp) if resp.status().is_success() => Ok(()),
+        Ok(resp) => Err(format!("Webhook {} answered {}", url, resp.status())),
+        Err(e) => Err(format!("Could not deliver to {}: {}", url, e)),
+    }
+}
+
+/// Delivers a run to its webhook, dead-lettering it when the webhook does not take it
+async fn deliver(
+    breakers: web::Data<Breakers>,
+    dead_letters: web::Data<DeadLetters>,
+    url: String,
+    trace: Option<TraceContext>,
+    run: ScheduleRun,
+    private_hosts: bool,
+) {
+    let run = serde_json::to_value(&run).unwrap_or_default();
+    let result = if breakers.allow(&url, Instant::now()) {
+        let sent = send(&url, trace.as_ref(), &run, private_hosts).await;
+        breakers.record(&url, sent.is_ok(), Instant::now());
+        sent
+    } else {
+        Err(format!("Circuit of {} is open", url))
+    };
+    match result {
+        Ok(()) => info!("Delivered schedule run to {}", url),
+        Err(e) => {
+            warn!("{}, dead-lettering schedule run", e);
+            dead_letters.push(Work::Webhook { url, run }, e);
+        }
+    }
+}
+
+/// Loopback, private, link-local and other addresses no webhook is sent to
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // shared address space of carrier-grade NAT
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local fc00::/7 and link-local fe80::/10
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+                || ip.to_ipv4().map_or(false, |v4| is_internal(IpAddr::V4(v4)))
+        }
+    }
+}
+
+/// Refuses webhooks other than http(s) and, unless `private_hosts`, those whose host is or
+/// resolves to an internal address. Resolves names, so it blocks.
+fn check_webhook(url: &str, private_hosts: bool) -> Result<(), &'static str> {
+    let uri = url.parse::<Uri>().map_err(|_| "Webhook is not a URL")?;
+    let port = match uri.scheme_str() {
+        Some("http") => 80,
+        Some("https") => 443,
+        _ => return Err("Webhook must be an http or https URL"),
+    };
+    let host = uri.host().ok_or("Webhook has no host")?;
+    if private_hosts {
+        return Ok(());
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = (host, uri.port_u16().unwrap_or(port))
+        .to_socket_addrs()
+        .map_err(|_| "Webhook host does not resolve")?;
+    let mut addrs = addrs.peekable();
+    if addrs.peek().is_none() {
+        return Err("Webhook host does not resolve");
+    }
+    if addrs.any(|addr| is_internal(addr.ip())) {
+        return Err("Webhook host is not public");
+    }
+    Ok(())
+}
+
+/// `check_webhook` off the event loop
+async fn check(url: String, private_hosts: bool) -> Result<(), &'static str> {
+    web::block(move || check_webhook(&url, private_hosts))
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(reason) => reason,
+            BlockingError::Canceled => "Webhook not checked",
+        })
+}
+
+pub async fn create(
+    data: web::Json<ScheduleRequest>,
+    schedules: web::Data<Schedules>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let caller = Caller::of(&req);
+    caller.require_case(data.params.case.as_ref().unwrap_or(&settings.default_case))?;
+    if let Some(url) = data.webhook.clone() {
+        check(url, settings.webhook_private_hosts)
+            .await
+            .map_err(error::ErrorBadRequest)?;
+    }
+    let trace = req.extensions().get::<TraceContext>().cloned();
+    let schedule = schedules.insert(
+        data.into_inner(),
+        caller.owner(),
+        trace,
+        settings.schedules_per_owner,
+    )?;
+    Ok(HttpResponse::Created().json(schedule))
+}
+
+/// Schedules the caller may see, everyone's for `ops`
+pub async fn list(schedules: web::Data<Schedules>, req: HttpRequest) -> HttpResponse {
+    let caller = Caller::of(&req);
+    let entries = schedules.entries.lock().unwrap();
+    let mut all: Vec<&Schedule> = entries
+        .values()
+        .filter(|s| caller.may_see(s.owner.as_deref()))
+        .collect();
+    all.sort_by_key(|s| s.id);
+    HttpResponse::Ok().json(all)
+}
+
+/// Schedules of others are as unknown as missing ones
+pub async fn get(
+    id: web::Path<u64>,
+    schedules: web::Data<Schedules>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let caller = Caller::of(&req);
+    match schedules.entries.lock().unwrap().get(&id) {
+        Some(schedule) if caller.may_see(schedule.owner.as_deref()) => {
+            Ok(HttpResponse::Ok().json(schedule))
+        }
+        _ => Err(error::ErrorNotFound(format!("No schedule {}", id))),
+    }
+}
+
+pub async fn delete(
+    id: web::Path<u64>,
+    schedules: web::Data<Schedules>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let caller = Caller::of(&req);
+    let mut entries = schedules.entries.lock().unwrap();
+    match entries.get(&id) {
+        Some(schedule) if caller.may_see(schedule.owner.as_deref()) => {
+            entries.remove(&id);
+            Ok(HttpResponse::NoContent().finish())
+        }
+        _ => Err(error::ErrorNotFound(format!("No schedule {}", id))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn due_schedule_runs_once() {
+        let schedules = Schedules::default();
+        let schedule = schedules
+            .insert(
+                ScheduleRequest {
+                    cron: "0 0 * * * *".into(),
+                    params: Params::default(),
+                    webhook: None,
+                },
+                None,
+                None,
+                10,
+            )
+            .unwrap();
+        let due = schedule.next_run.unwrap();
+
+        assert!(schedules
+            .run_due(due - Duration::seconds(1), &Case::B)
+            .is_empty());
+        assert_eq!(schedules.run_due(due, &Case::B).len(), 1);
+        assert!(schedules.run_due(due, &Case::B).is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_cron() {
+        let schedules = Schedules::default();
+        let req = ScheduleRequest {
+            cron: "whenever".into(),
+            params: Params::default(),
+            webhook: None,
+        };
+
+        assert!(schedules.insert(req, None, None, 10).is_err());
+    }
+
+    #[test]
+    fn caps_schedules_per_owner() {
+        let schedules = Schedules::default();
+        let req = || ScheduleRequest {
+            cron: "0 0 * * * *".into(),
+            params: Params::default(),
+            webhook: None,
+        };
+
+        assert!(schedules.insert(req(), Some("partner"), None, 1).is_ok());
+        assert!(schedules.insert(req(), Some("partner"), None, 1).is_err());
+        assert!(schedules.insert(req(), Some("other"), None, 1).is_ok());
+    }
+
+    #[test]
+    fn refuses_internal_webhooks() {
+        assert!(check_webhook("http://93.184.216.34/hook", false).is_ok());
+        assert_eq!(
+            check_webhook("ftp://93.184.216.34/hook", false),
+            Err("Webhook must be an http or https URL")
+        );
+        for url in &[
+            "http://127.0.0.1:8000/hook",
+            "http://10.1.2.3/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:192.168.0.1]/hook",
+        ] {
+            assert_eq!(
+                check_webhook(url, false),
+                Err("Webhook host is not public"),
+                "{}",
+                url
+            );
+        }
+        assert!(check_webhook("http://10.1.2.3/hook", true).is_ok());
+    }
+}
@@ -0,0 +1,123 @@
+//! JSON Schemas of `Params` and `Output` for client generation, and with `--openapi` the
+//! OpenAPI document the server also serves at `/openapi.json`:
+//!
+//! ``` cargo run -- print-schema```
+//!
+//! ``` cargo run -- print-schema --openapi```
+
+use actix_web::HttpResponse;
+use schemars::gen::SchemaSettings;
+use schemars::schema_for;
+use serde_json::{json, Value};
+
+use crate::types::{Output, Params};
+
+/// JSON Schemas of the `/compute` request and response bodies
+pub fn schemas() -> Value {
+    json!({
+        "Params": schema_for!(Params),
+        "Output": schema_for!(Output),
+    })
+}
+
+/// OpenAPI 3 document of `/v1/compute` and `/v1/results/{id}`
+pub fn openapi() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let params = gen.subschema_for::<Params>();
+    let output = gen.subschema_for::<Output>();
+    let body = |description: &str, schema: &Value| {
+        json!({
+            "description": description,
+            "content": {"application/json": {"schema": schema}},
+        })
+    };
+    let text = |description: &str| {
+        json!({
+            "description": description,
+            "content": {"text/plain": {"schema": {"type": "string"}}},
+        })
+    };
+    let output = serde_json::to_value(output).unwrap_or_default();
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/v1/compute": {
+                "post": {
+                    "summary": "Computes the output of one set of params",
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/json": {"schema": params}},
+                    },
+                    "responses": {
+                        "200": body("Output, stored under `Content-Location`", &output),
+                        "400": text("Params without an output"),
+                    },
+                },
+            },
+            "/v1/results/{id}": {
+                "get": {
+                    "summary": "Output of an earlier compute",
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": {"type": "string"},
+                    }],
+                    "responses": {
+                        "200": body("Stored output", &output),
+                        "404": text("No such result"),
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "apiKey": {"type": "apiKey", "in": "header", "name": "X-Api-Key"},
+            },
+        },
+        "security": [{"apiKey": []}],
+    })
+}
+
+pub async fn document() -> HttpResponse {
+    HttpResponse::Ok().json(openapi())
+}
+
+/// Prints the JSON Schemas, or the OpenAPI document with `openapi`
+pub fn print(openapi: bool) {
+    let document = if openapi { self::openapi() } else { schemas() };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&document).unwrap_or_default()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_params_and_output() {
+        let schemas = schemas();
+
+        assert!(schemas["Params"]["properties"]["case"].is_object());
+        assert_eq!(schemas["Output"]["required"], json!(["h", "k"]));
+    }
+
+    #[test]
+    fn documents_compute() {
+        let document = openapi();
+        let compute = &document["paths"]["/v1/compute"]["post"];
+
+        assert_eq!(
+            compute["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/Params"
+        );
+        assert!(document["components"]["schemas"]["Output"]["properties"]["k"].is_object());
+    }
+}
@@ -0,0 +1,184 @@
+//! Every response carries `X-Content-Type-Options`, `Referrer-Policy`, HTML ones a
+//! `Content-Security-Policy`, and with TLS `Strict-Transport-Security`. Their values are
+//! set in `[security_headers]`, an empty one is not sent.
+
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_derive::{Deserialize, Serialize};
+
+/// Values of the security headers, an empty one is not sent
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SecurityHeaderSettings {
+    /// `Strict-Transport-Security`, sent only while TLS is enabled
+    pub strict_transport_security: String,
+    /// `X-Content-Type-Options`
+    pub content_type_options: String,
+    /// `Content-Security-Policy` of HTML responses
+    pub content_security_policy: String,
+    /// `Referrer-Policy`
+    pub referrer_policy: String,
+}
+
+impl Default for SecurityHeaderSettings {
+    fn default() -> Self {
+        SecurityHeaderSettings {
+            strict_transport_security: "max-age=31536000; includeSubDomains".into(),
+            content_type_options: "nosniff".into(),
+            content_security_policy: "default-src 'self'".into(),
+            referrer_policy: "no-referrer".into(),
+        }
+    }
+}
+
+struct Headers {
+    all: Vec<(HeaderName, HeaderValue)>,
+    html: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Middleware adding the security headers to every response, errors included
+pub struct SecurityHeaders(Rc<Headers>);
+
+impl SecurityHeaders {
+    pub fn new(settings: &SecurityHeaderSettings, tls: bool) -> Self {
+        let pair = |name: HeaderName, value: &str| {
+            HeaderValue::from_str(value)
+                .ok()
+                .filter(|_| !value.is_empty())
+                .map(|v| (name, v))
+        };
+        let hsts = if tls {
+            &settings.strict_transport_security
+        } else {
+            ""
+        };
+        let all = vec![
+            pair(header::STRICT_TRANSPORT_SECURITY, hsts),
+            pair(
+                header::X_CONTENT_TYPE_OPTIONS,
+                &settings.content_type_options,
+            ),
+            pair(header::REFERRER_POLICY, &settings.referrer_policy),
+        ];
+        let html = vec![pair(
+            header::CONTENT_SECURITY_POLICY,
+            &settings.content_security_policy,
+        )];
+        SecurityHeaders(Rc::new(Headers {
+            all: all.into_iter().flatten().collect(),
+            html: html.into_iter().flatten().collect(),
+        }))
+    }
+}
+
+impl<S, B> Transform<S> for SecurityHeaders
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SecurityHeadersMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SecurityHeadersMiddleware {
+            service,
+            headers: self.0.clone(),
+        })
+    }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+    headers: Rc<Headers>,
+}
+
+impl<S, B> Service for SecurityHeadersMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let headers = self.headers.clone();
+        let request = req.request().clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            // errors of inner middlewares become responses here, so they get the headers too
+            let mut res = match fut.await {
+                Ok(res) => res,
+                Err(e) => ServiceResponse::from_err(e, request),
+            };
+            let is_html = res
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map_or(false, |v| v.starts_with("text/html"));
+            let extra: &[_] = if is_html { &headers.html } else { &[] };
+            for (name, value) in headers.all.iter().chain(extra) {
+                res.headers_mut().insert(name.clone(), value.clone());
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn csp_only_on_html() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(SecurityHeaders::new(
+                    &SecurityHeaderSettings::default(),
+                    false,
+                ))
+                .route(
+                    "/page",
+                    web::get()
+                        .to(|| async { HttpResponse::Ok().content_type("text/html").body("<p>") }),
+                )
+                .route(
+                    "/json",
+                    web::get().to(|| async { HttpResponse::Ok().json(1) }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/page").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert!(resp.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+        assert!(!resp
+            .headers()
+            .contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+        let req = test::TestRequest::get().uri("/json").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers()[header::REFERRER_POLICY], "no-referrer");
+        assert!(!resp.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+    }
+}
@@ -0,0 +1,112 @@
+//! `GET /selftest` runs known input/output vectors against the engine, 500 if any fails.
+
+use actix_web::HttpResponse;
+use serde_derive::Serialize;
+
+use crate::types::{Case, Params, H};
+
+/// Known input/output vector, one per case and H. `h` is what the input classifies as,
+/// the engine reports `H::M` in the output of every one.
+struct Vector {
+    case: Case,
+    abc: (bool, bool, bool),
+    h: H,
+    k: f64,
+}
+
+const D: f64 = 3.7;
+const E: i32 = 5;
+const F: i32 = 2;
+
+fn vectors() -> Vec<Vector> {
+    let v = |case, abc, h, k| Vector { case, abc, h, k };
+    vec![
+        v(Case::B, (true, true, false), H::M, 5.55),
+        v(Case::B, (true, true, true), H::P, 4.135294117647059),
+        v(Case::B, (false, true, true), H::T, 3.4533333333333336),
+        v(Case::C1, (true, true, false), H::M, 5.55),
+        v(Case::C1, (true, true, true), H::P, 7.585),
+        v(Case::C1, (false, true, true), H::T, 3.4533333333333336),
+        v(Case::C2, (true, false, true), H::M, 5.885),
+        v(Case::C2, (true, true, true), H::P, 4.135294117647059),
+        v(Case::C2, (false, true, true), H::T, 3.4533333333333336),
+    ]
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub case: Case,
+    pub h: H,
+    pub passed: bool,
+    pub expected_k: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual_k: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub passed: bool,
+    pub checks: Vec<Check>,
+}
+
+/// Runs every vector through the engine
+pub fn run() -> Report {
+    let checks: Vec<Check> = vectors()
+        .into_iter()
+        .map(|v| {
+            let (a, b, c) = v.abc;
+            let p = Params {
+                a: Some(a),
+                b: Some(b),
+                c: Some(c),
+                d: Some(D),
+                e: Some(E),
+                f: Some(F),
+                case: Some(v.case.clone()),
+            };
+            let h = crate::classify(&p, &v.case);
+            let (actual_k, error) = match crate::output(h.clone(), &p, v.case.clone()) {
+                Ok(o) => (Some(o.k), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            let passed = h == v.h && actual_k.map_or(false, |k| (k - v.k).abs() < 1e-9);
+
+            Check {
+                case: v.case,
+                h: v.h,
+                passed,
+                expected_k: v.k,
+                actual_k,
+                error,
+            }
+        })
+        .collect();
+
+    Report {
+        passed: checks.iter().all(|c| c.passed),
+        checks,
+    }
+}
+
+pub async fn selftest() -> HttpResponse {
+    let report = run();
+    if report.passed {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::InternalServerError().json(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engine_passes_vectors() {
+        let report = run();
+
+        assert!(report.passed, "{:?}", report);
+    }
+}
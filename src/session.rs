@@ -0,0 +1,197 @@
+//! With authentication on the playground signs in with an API key at `POST /session`,
+//! getting a `SameSite=Strict`, `HttpOnly` session cookie valid for `session_ttl` seconds
+//! (3600) and a CSRF token. Requests carrying only the cookie need that token in
+//! `X-CSRF-Token` unless they are `GET`/`HEAD`, ones with API keys, bearer tokens or
+//! signatures need none. `DELETE /session` signs out, also with the token. Rotating or
+//! revoking a key ends the sessions opened with it.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde_derive::Serialize;
+
+use crate::auth::{Auth, Identity};
+use crate::config::Settings;
+use crate::errors::problem;
+
+/// Cookie carrying the session of a browser signed in to the playground
+pub const SESSION_COOKIE: &str = "rtp_session";
+/// Token a session has to send with every request changing state
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+struct Session {
+    started: Instant,
+    identity: Identity,
+    csrf_token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Opened {
+    csrf_token: String,
+}
+
+fn random_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Browser sessions opened with an API key, valid for `ttl`
+pub struct Sessions {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Self {
+        Sessions {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a session of `identity`, returning its id and CSRF token
+    fn open(&self, identity: Identity) -> (String, String) {
+        let (id, csrf_token) = (random_token(), random_token());
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, s| s.started.elapsed() < ttl);
+        entries.insert(
+            id.clone(),
+            Session {
+                started: Instant::now(),
+                identity,
+                csrf_token: csrf_token.clone(),
+            },
+        );
+        (id, csrf_token)
+    }
+
+    /// Ends session `id` if `csrf` is its token, so other sites cannot sign the browser out
+    fn close(&self, id: &str, csrf: Option<&str>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(id) {
+            Some(session) if csrf == Some(session.csrf_token.as_str()) => {
+                entries.remove(id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Ends every session opened as `name`, once its key was rotated or revoked
+    pub fn close_all(&self, name: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, s| s.identity.name != name);
+    }
+
+    /// Caller of session `id`, requests that are not reads also need its `csrf` token
+    pub fn check(&self, id: &str, csrf: Option<&str>, method: &Method) -> Auth {
+        let entries = self.entries.lock().unwrap();
+        let session = match entries.get(id).filter(|s| s.started.elapsed() < self.ttl) {
+            Some(session) => session,
+            None => return Auth::Invalid("Unknown or expired session"),
+        };
+        let safe = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+        if !safe && csrf != Some(session.csrf_token.as_str()) {
+            return Auth::Invalid("Missing or wrong CSRF token");
+        }
+        Auth::Identified(session.identity.clone())
+    }
+}
+
+/// Opens a session for the caller's credentials, the CSRF token is only in the answer
+pub async fn open(
+    req: HttpRequest,
+    sessions: web::Data<Sessions>,
+    settings: web::Data<Settings>,
+) -> HttpResponse {
+    let identity = match req.extensions().get::<Auth>() {
+        Some(Auth::Identified(identity)) => identity.clone(),
+        _ => return problem(StatusCode::UNAUTHORIZED, "Missing credentials"),
+    };
+    let (id, csrf_token) = sessions.open(identity);
+    let cookie = Cookie::build(SESSION_COOKIE, id)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(settings.tls.is_some())
+        .max_age(settings.session_ttl as i64)
+        .finish();
+    HttpResponse::Created()
+        .cookie(cookie)
+        .json(Opened { csrf_token })
+}
+
+pub async fn close(req: HttpRequest, sessions: web::Data<Sessions>) -> HttpResponse {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        let csrf = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+        if !sessions.close(cookie.value(), csrf) {
+            return problem(StatusCode::FORBIDDEN, "Missing or wrong CSRF token");
+        }
+    }
+    let mut cookie = Cookie::named(SESSION_COOKIE);
+    cookie.set_path("/");
+    HttpResponse::NoContent().del_cookie(&cookie).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::default_roles;
+
+    #[test]
+    fn unsafe_methods_need_the_csrf_token() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let identity = Identity {
+            name: "partner".into(),
+            roles: default_roles(),
+        };
+        let (id, csrf) = sessions.open(identity.clone());
+
+        assert_eq!(
+            sessions.check(&id, None, &Method::GET),
+            Auth::Identified(identity.clone())
+        );
+        assert_eq!(
+            sessions.check(&id, None, &Method::POST),
+            Auth::Invalid("Missing or wrong CSRF token")
+        );
+        assert_eq!(
+            sessions.check(&id, Some(&csrf), &Method::POST),
+            Auth::Identified(identity)
+        );
+        assert_eq!(
+            sessions.check("forged", Some(&csrf), &Method::GET),
+            Auth::Invalid("Unknown or expired session")
+        );
+
+        assert!(!sessions.close(&id, None));
+        assert!(sessions.close(&id, Some(&csrf)));
+    }
+
+    #[test]
+    fn rotated_keys_end_their_sessions() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let identity = |name: &str| Identity {
+            name: name.into(),
+            roles: default_roles(),
+        };
+        let (partner, _) = sessions.open(identity("partner"));
+        let (other, _) = sessions.open(identity("other"));
+
+        sessions.close_all("partner");
+        assert_eq!(
+            sessions.check(&partner, None, &Method::GET),
+            Auth::Invalid("Unknown or expired session")
+        );
+        assert_eq!(
+            sessions.check(&other, None, &Method::GET),
+            Auth::Identified(identity("other"))
+        );
+    }
+}
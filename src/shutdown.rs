@@ -0,0 +1,64 @@
+//! On SIGINT/SIGTERM the server stops accepting connections, lets in-flight requests finish
+//! for up to `shutdown_timeout` seconds (30) and logs the final stats. Before that, `GET
+//! /readyz` turns `503` at once and the listeners stay open for `pre_stop_delay` seconds so
+//! Kubernetes can drop the endpoint.
+
+use std::time::{Duration, Instant};
+
+use actix_rt::signal;
+use actix_rt::signal::unix::SignalKind;
+use actix_web::dev::Server;
+use actix_web::web;
+use futures::future::{self, Either};
+use log::{info, warn};
+
+use crate::consul::Registration;
+use crate::health::Readiness;
+use crate::stats::Stats;
+
+/// Stops `servers` on SIGINT or SIGTERM. Readiness fails and the Consul `registration` is
+/// removed at once, the listeners keep accepting for `pre_stop` so endpoint removal can
+/// propagate, then in-flight requests get the configured `shutdown_timeout` to finish.
+pub async fn on_signal(
+    servers: Vec<Server>,
+    readiness: web::Data<Readiness>,
+    pre_stop: Duration,
+    registration: Option<Registration>,
+) {
+    let mut term = match signal::unix::signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(e) => {
+            warn!("Could not listen for SIGTERM: {:?}", e);
+            return;
+        }
+    };
+    let ctrl_c = signal::ctrl_c();
+    let sigterm = term.recv();
+    futures::pin_mut!(ctrl_c, sigterm);
+    let received = match future::select(ctrl_c, sigterm).await {
+        Either::Left(_) => "SIGINT",
+        Either::Right(_) => "SIGTERM",
+    };
+
+    let started = Instant::now();
+    readiness.drain();
+    info!("Received {}, reporting not ready", received);
+    if let Some(registration) = registration {
+        registration.deregister().await;
+    }
+    if pre_stop > Duration::from_secs(0) {
+        info!("Waiting {:?} before closing the listeners", pre_stop);
+        actix_rt::time::delay_for(pre_stop).await;
+    }
+    info!("Shutting down, draining in-flight requests");
+    future::join_all(servers.iter().map(|s| s.stop(true))).await;
+    info!("Stopped {:?} after {}", started.elapsed(), received);
+}
+
+/// Writes out the in-memory counters that would otherwise be lost with the process
+pub fn flush(stats: &Stats) {
+    match serde_json::to_string(&stats.summary()) {
+        Ok(summary) => info!("Final stats: {}", summary),
+        Err(e) => warn!("Could not serialize final stats: {:?}", e),
+    }
+}
@@ -0,0 +1,279 @@
+//! Machine clients listed in `signing_clients` sign their requests instead of sending a key,
+//! with `X-Signature: key=<name>,t=<unix time>,nonce=<unique>,sig=<hex HMAC-SHA256>` over
+//! `"<METHOD>\n<path?query>\n<t>\n<nonce>\n<body>"`, the path and query as sent. Timestamps
+//! more than `signature_tolerance` seconds (300) off are refused, and a nonce is accepted
+//! once per client within that time, so captured requests cannot be replayed. While 100000
+//! nonces are within that time, signed requests get `503` with `Retry-After`.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use actix_web::dev::{Payload, PayloadStream, ServiceRequest};
+use actix_web::error::PayloadError;
+use actix_web::HttpMessage;
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::auth::{default_roles, Identity};
+
+/// `key=<client>,t=<unix seconds>,nonce=<unique>,sig=<hex HMAC-SHA256>`, signing
+/// `<METHOD>\n<path?query>\n<t>\n<nonce>\n<body>`
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Signed bodies above this size are rejected rather than buffered
+pub const MAX_BODY: usize = 1024 * 1024;
+
+/// Nonces remembered at most, signatures are refused with `503` while as many are in range
+const MAX_NONCES: usize = 100_000;
+
+/// Why `Signatures::verify` did not accept a signature
+#[derive(Debug, PartialEq)]
+pub enum Refused {
+    Invalid(&'static str),
+    /// `MAX_NONCES` are remembered, for the seconds until the oldest expires
+    Full(u64),
+}
+
+/// Client and nonce of accepted signatures until their timestamp falls out of range,
+/// queued in the order they were accepted
+#[derive(Default)]
+struct Nonces {
+    until: HashMap<(String, String), i64>,
+    queue: VecDeque<(i64, (String, String))>,
+}
+
+/// Machine client signing its requests with a shared secret
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SigningClient {
+    pub name: String,
+    pub secret: String,
+    #[serde(default = "default_roles")]
+    pub roles: Vec<String>,
+}
+
+struct Signature {
+    key: String,
+    timestamp: i64,
+    nonce: String,
+    mac: Vec<u8>,
+}
+
+fn parse(header: &str) -> Option<Signature> {
+    let mut fields: HashMap<&str, &str> = header
+        .split(',')
+        .filter_map(|part| {
+            let mut kv = part.trim().splitn(2, '=');
+            Some((kv.next()?, kv.next()?))
+        })
+        .collect();
+    Some(Signature {
+        key: fields.remove("key")?.to_string(),
+        timestamp: fields.remove("t")?.parse().ok()?,
+        nonce: fields
+            .remove("nonce")
+            .filter(|n| !n.is_empty())?
+            .to_string(),
+        mac: hex::decode(fields.remove("sig")?).ok()?,
+    })
+}
+
+/// Secrets of the signing clients, the clock skew allowed on timestamps and the nonces
+/// seen within it
+pub struct Signatures {
+    clients: HashMap<String, SigningClient>,
+    tolerance: i64,
+    nonces: Mutex<Nonces>,
+}
+
+impl Signatures {
+    pub fn new(clients: &[SigningClient], tolerance: u64) -> Self {
+        Signatures {
+            clients: clients
+                .iter()
+                .map(|c| (c.name.clone(), c.clone()))
+                .collect(),
+            tolerance: tolerance as i64,
+            nonces: Mutex::new(Nonces::default()),
+        }
+    }
+
+    /// Client of a valid `header` over `method`, `target` (path and query as sent) and
+    /// `body` at `now`, the reason it is rejected otherwise. Old timestamps are refused and
+    /// nonces accepted once, so captured requests cannot be replayed, nor their signature
+    /// moved to another route.
+    pub fn verify(
+        &self,
+        header: &str,
+        method: &str,
+        target: &str,
+        body: &[u8],
+        now: i64,
+    ) -> Result<Identity, Refused> {
+        let signature = parse(header).ok_or(Refused::Invalid("Malformed X-Signature header"))?;
+        let client = self
+            .clients
+            .get(&signature.key)
+            .ok_or(Refused::Invalid("Unknown signing client"))?;
+        if (now - signature.timestamp).abs() > self.tolerance {
+            return Err(Refused::Invalid("Signature timestamp out of range"));
+        }
+        let mut mac = Hmac::<Sha256>::new_varkey(client.secret.as_bytes())
+            .map_err(|_| Refused::Invalid("Unusable signing secret"))?;
+        let signed = format!(
+            "{}\n{}\n{}\n{}\n",
+            method, target, signature.timestamp, signature.nonce
+        );
+        mac.update(signed.as_bytes());
+        mac.update(body);
+        mac.verify(&signature.mac)
+            .map_err(|_| Refused::Invalid("Invalid signature"))?;
+        self.remember(signature.key, signature.nonce, signature.timestamp, now)?;
+        Ok(Identity {
+            name: client.name.clone(),
+            roles: client.roles.clone(),
+        })
+    }
+
+    /// Records a nonce of `client`, refusing one already seen. Expired nonces are dropped
+    /// from the front of the queue, those accepted later expire later give or take the
+    /// tolerance, so some are kept a little longer than needed.
+    fn remember(
+        &self,
+        client: String,
+        nonce: String,
+        timestamp: i64,
+        now: i64,
+    ) -> Result<(), Refused> {
+        let mut nonces = self.nonces.lock().unwrap();
+        let Nonces { until, queue } = &mut *nonces;
+        while queue.front().map_or(false, |(expiry, _)| *expiry < now) {
+            if let Some((expiry, key)) = queue.pop_front() {
+                // a nonce used again once expired is queued a second time
+                if until.get(&key) == Some(&expiry) {
+                    until.remove(&key);
+                }
+            }
+        }
+        let key = (client, nonce);
+        if until.get(&key).map_or(false, |expiry| *expiry >= now) {
+            return Err(Refused::Invalid("Replayed nonce"));
+        }
+        if let Some((oldest, _)) = queue.front().filter(|_| queue.len() >= MAX_NONCES) {
+            return Err(Refused::Full((oldest - now + 1) as u64));
+        }
+        let expiry = timestamp + self.tolerance;
+        until.insert(key.clone(), expiry);
+        queue.push_back((expiry, key));
+        Ok(())
+    }
+}
+
+/// Buffers the body of `req` and puts it back for the handler, `None` if it is larger
+/// than `limit`
+pub async fn read_body(
+    req: &mut ServiceRequest,
+    limit: usize,
+) -> Result<Option<Bytes>, PayloadError> {
+    let mut body = BytesMut::new();
+    let mut payload = req.take_payload();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > limit {
+            return Ok(None);
+        }
+    }
+    let body = body.freeze();
+    set_body(req, body.clone());
+    Ok(Some(body))
+}
+
+/// Makes `body` what the handler of `req` reads
+pub fn set_body(req: &mut ServiceRequest, body: Bytes) {
+    let replay: PayloadStream =
+        Box::pin(stream::once(futures::future::ok::<_, PayloadError>(body)));
+    req.set_payload(Payload::from(replay));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, target: &str, timestamp: i64, nonce: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.update(format!("POST\n{}\n{}\n{}\n", target, timestamp, nonce).as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn verifies_fresh_signatures() {
+        let signatures = Signatures::new(
+            &[SigningClient {
+                name: "hook".into(),
+                secret: "s3cr3t".into(),
+                roles: default_roles(),
+            }],
+            300,
+        );
+        let body = br#"{"a":true}"#;
+        let signed = |nonce| {
+            let sig = sign("s3cr3t", "/v1/compute?pretty=true", 1000, nonce, body);
+            format!("key=hook,t=1000,nonce={},sig={}", nonce, sig)
+        };
+        let header = signed("n1");
+        let verify = |header: &str, method, target, body: &[u8], now| {
+            signatures.verify(header, method, target, body, now)
+        };
+        let target = "/v1/compute?pretty=true";
+
+        assert_eq!(
+            verify(&header, "POST", target, body, 1100).map(|i| i.name),
+            Ok("hook".into())
+        );
+        assert_eq!(
+            verify(&header, "POST", target, body, 1101),
+            Err(Refused::Invalid("Replayed nonce"))
+        );
+        assert!(verify(&signed("n2"), "POST", target, body, 1101).is_ok());
+        assert_eq!(
+            verify(&header, "POST", target, body, 2000),
+            Err(Refused::Invalid("Signature timestamp out of range"))
+        );
+        assert_eq!(
+            verify(&header, "POST", target, b"{}", 1100),
+            Err(Refused::Invalid("Invalid signature"))
+        );
+        assert_eq!(
+            verify(&signed("n3"), "POST", "/v1/schedules", body, 1100),
+            Err(Refused::Invalid("Invalid signature"))
+        );
+        assert_eq!(
+            verify(&signed("n4"), "PUT", target, body, 1100),
+            Err(Refused::Invalid("Invalid signature"))
+        );
+        assert_eq!(
+            verify("key=hook", "POST", target, body, 1100),
+            Err(Refused::Invalid("Malformed X-Signature header"))
+        );
+    }
+    #[test]
+    fn refuses_signatures_while_nonces_are_full() {
+        let signatures = Signatures::new(&[], 300);
+        let remember =
+            |nonce: usize, now| signatures.remember("hook".into(), nonce.to_string(), now, now);
+        for nonce in 0..MAX_NONCES {
+            assert!(remember(nonce, 1000).is_ok());
+        }
+
+        assert_eq!(remember(MAX_NONCES, 1100), Err(Refused::Full(201)));
+        assert!(remember(MAX_NONCES, 1301).is_ok());
+        assert_eq!(signatures.nonces.lock().unwrap().queue.len(), 1);
+        assert_eq!(
+            remember(MAX_NONCES, 1302),
+            Err(Refused::Invalid("Replayed nonce"))
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! `slow_request_ms` logs requests taking longer at WARN, with the duration of each compute
+//! phase as in `Server-Timing` and the params, `redact` applied.
+
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use log::warn;
+
+use crate::timing::Timings;
+
+/// Params of a compute request as they may be logged, `redact` applied, attached to
+/// response extensions by handlers while slow requests are logged
+pub struct LoggedParams(pub String);
+
+fn headline(line: &str, took: Duration) -> String {
+    format!(
+        "Slow request {} took {:.3}ms",
+        line,
+        took.as_secs_f64() * 1000.0
+    )
+}
+
+/// Warning line of a request that took `took`, with the phases and params the handler
+/// attached
+fn describe(line: &str, took: Duration, res: &ServiceResponse<impl Sized>) -> String {
+    let mut message = headline(line, took);
+    let extensions = res.response().extensions();
+    if let Some(timings) = extensions.get::<Timings>() {
+        message.push_str(&format!(" ({})", timings.header_value()));
+    }
+    if let Some(LoggedParams(params)) = extensions.get::<LoggedParams>() {
+        message.push_str(&format!(", params {}", params));
+    }
+    message
+}
+
+/// Middleware logging requests slower than the threshold at WARN
+pub struct SlowRequests(pub Duration);
+
+impl<S, B> Transform<S> for SlowRequests
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SlowRequestsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SlowRequestsMiddleware {
+            service,
+            threshold: self.0,
+        })
+    }
+}
+
+pub struct SlowRequestsMiddleware<S> {
+    service: S,
+    threshold: Duration,
+}
+
+impl<S, B> Service for SlowRequestsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let threshold = self.threshold;
+        let line = format!("{} {}", req.method(), req.path());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let took = started.elapsed();
+            if took > threshold {
+                match &res {
+                    Ok(res) => warn!("{}", describe(&line, took, res)),
+                    // e.g. the 504 of a request that hit its timeout
+                    Err(e) => warn!(
+                        "{}, failed with {}",
+                        headline(&line, took),
+                        e.as_response_error().status_code()
+                    ),
+                }
+            }
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::HttpResponse;
+
+    #[test]
+    fn describes_phases_and_params() {
+        let mut resp = HttpResponse::Ok().finish();
+        let mut timings = Timings::default();
+        timings.record("compute", Duration::from_millis(2));
+        timings.record("compute", Duration::from_millis(1));
+        resp.extensions_mut().insert(timings);
+        resp.extensions_mut()
+            .insert(LoggedParams(r#"{"d":"***"}"#.into()));
+        let res = ServiceResponse::new(TestRequest::default().to_http_request(), resp);
+
+        let message = describe("POST /v1/compute", Duration::from_millis(3), &res);
+        assert_eq!(
+            message,
+            r#"Slow request POST /v1/compute took 3.000ms (compute;dur=3.000), params {"d":"***"}"#
+        );
+    }
+}
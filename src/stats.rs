@@ -0,0 +1,462 @@
+//! `GET /stats` summarizes computes since startup: counts by case, H and error type, plus
+//! request latency percentiles and, in `latency_by_case`, cumulative histograms of the
+//! compute phase of each payload per case and resulting H. `h_by_case` and `h_by_tenant`
+//! count each H per case and per `X-Tenant` (`-` without one).
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_derive::Serialize;
+
+use crate::auth::{Auth, Caller};
+use crate::errors::problem;
+use crate::i18n::Fault;
+use crate::tenants::TENANT_HEADER;
+use crate::types::{Case, Output, H};
+
+/// Latency samples kept for percentiles
+const WINDOW: usize = 1024;
+
+/// Compute requests kept for `Stats::recent`
+const RECENT: usize = 50;
+
+/// Key of requests without `X-Tenant` in `h_by_tenant`
+const NO_TENANT: &str = "-";
+
+/// Upper bounds in milliseconds of the compute histogram buckets
+const BUCKETS_MS: &[f64] = &[1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+/// What a compute request resolved to, attached to response extensions by handlers
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub case: Case,
+    pub h: H,
+    pub error: Option<&'static str>,
+    /// Time spent in the compute phase of this payload alone
+    pub compute: Duration,
+}
+
+impl Outcome {
+    pub fn of(case: &Case, h: &H, result: &anyhow::Result<Output>, compute: Duration) -> Self {
+        let error = match (result, h) {
+            (Ok(_), _) => None,
+            (Err(e), _) if e.downcast_ref::<Fault>().is_none() => Some("internal"),
+            (Err(_), H::E) => Some("unsupported_params"),
+            (Err(_), _) => Some("missing_params"),
+        };
+        Outcome {
+            case: case.clone(),
+            h: h.clone(),
+            error,
+            compute,
+        }
+    }
+}
+
+/// Outcomes of every computed payload of a response
+pub struct Outcomes(pub Vec<Outcome>);
+
+#[derive(Default)]
+struct Inner {
+    requests: u64,
+    by_case: BTreeMap<String, u64>,
+    by_h: BTreeMap<String, u64>,
+    by_error: BTreeMap<String, u64>,
+    by_identity: BTreeMap<String, u64>,
+    latencies_ms: VecDeque<f64>,
+    latency_by_case: BTreeMap<String, BTreeMap<String, Histogram>>,
+    h_by_case: BTreeMap<String, BTreeMap<String, u64>>,
+    h_by_tenant: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl Inner {
+    fn add(
+        &mut self,
+        outcomes: &[Outcome],
+        identity: Option<&str>,
+        tenant: Option<&str>,
+        latency: Duration,
+    ) {
+        self.requests += 1;
+        if let Some(identity) = identity {
+            *self.by_identity.entry(identity.to_string()).or_default() += 1;
+        }
+        let tenant = tenant.unwrap_or(NO_TENANT);
+        for o in outcomes {
+            let case = format!("{:?}", o.case);
+            let h = if o.error.is_some() { H::E } else { o.h.clone() };
+            let h = format!("{:?}", h);
+            *self.by_case.entry(case.clone()).or_default() += 1;
+            *self.by_h.entry(h.clone()).or_default() += 1;
+            *self
+                .h_by_case
+                .entry(case.clone())
+                .or_default()
+                .entry(h.clone())
+                .or_default() += 1;
+            *self
+                .h_by_tenant
+                .entry(tenant.to_string())
+                .or_default()
+                .entry(h.clone())
+                .or_default() += 1;
+            if let Some(e) = o.error {
+                *self.by_error.entry(e.to_string()).or_default() += 1;
+            }
+            self.latency_by_case
+                .entry(case)
+                .or_default()
+                .entry(h)
+                .or_insert_with(Histogram::new)
+                .observe(o.compute);
+        }
+        if self.latencies_ms.len() == WINDOW {
+            self.latencies_ms.pop_front();
+        }
+        self.latencies_ms.push_back(latency.as_secs_f64() * 1000.0);
+    }
+
+    fn summary(&self, uptime: Duration) -> Summary {
+        let mut sorted: Vec<f64> = self.latencies_ms.iter().cloned().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let pct = |p: f64| {
+            if sorted.is_empty() {
+                0.0
+            } else {
+                sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+            }
+        };
+
+        Summary {
+            uptime_secs: uptime.as_secs(),
+            requests: self.requests,
+            by_case: self.by_case.clone(),
+            by_h: self.by_h.clone(),
+            by_error: self.by_error.clone(),
+            by_identity: self.by_identity.clone(),
+            latency_ms: Latency {
+                samples: sorted.len(),
+                p50: pct(0.5),
+                p90: pct(0.9),
+                p99: pct(0.99),
+            },
+            latency_by_case: self.latency_by_case.clone(),
+            h_by_case: self.h_by_case.clone(),
+            h_by_tenant: self.h_by_tenant.clone(),
+        }
+    }
+}
+
+/// In-process aggregator of compute requests since startup, overall and per identity
+pub struct Stats {
+    started: Instant,
+    inner: Mutex<Inner>,
+    by_owner: Mutex<HashMap<String, Inner>>,
+    recent: Mutex<VecDeque<Recent>>,
+}
+
+/// A compute request among the last `RECENT` ones
+#[derive(Debug, Clone, Serialize)]
+pub struct Recent {
+    pub at: DateTime<Utc>,
+    pub identity: Option<String>,
+    pub tenant: Option<String>,
+    /// Distinct cases of the payloads
+    pub cases: Vec<String>,
+    /// Payloads computed, more than one for batches
+    pub items: usize,
+    pub failed: usize,
+    pub latency_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Latency {
+    pub samples: usize,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+/// Requests with a latency up to `le_ms`, those of lower buckets included
+#[derive(Debug, Clone, Serialize)]
+pub struct Bucket {
+    pub le_ms: f64,
+    pub count: u64,
+}
+
+/// Cumulative latency histogram since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct Histogram {
+    pub buckets: Vec<Bucket>,
+    /// Every request, slower ones than the last bucket included
+    pub count: u64,
+    pub sum_ms: f64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: BUCKETS_MS
+                .iter()
+                .map(|&le_ms| Bucket { le_ms, count: 0 })
+                .collect(),
+            count: 0,
+            sum_ms: 0.0,
+        }
+    }
+
+    fn observe(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        for bucket in self.buckets.iter_mut().filter(|b| ms <= b.le_ms) {
+            bucket.count += 1;
+        }
+        self.count += 1;
+        self.sum_ms += ms;
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub uptime_secs: u64,
+    pub requests: u64,
+    pub by_case: BTreeMap<String, u64>,
+    pub by_h: BTreeMap<String, u64>,
+    pub by_error: BTreeMap<String, u64>,
+    /// Requests per authenticated caller
+    pub by_identity: BTreeMap<String, u64>,
+    pub latency_ms: Latency,
+    /// Latency histograms keyed by case, then by resulting H
+    pub latency_by_case: BTreeMap<String, BTreeMap<String, Histogram>>,
+    /// Resulting H counts keyed by case
+    pub h_by_case: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Resulting H counts keyed by `X-Tenant`, `-` for requests without
+    pub h_by_tenant: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            inner: Mutex::new(Inner::default()),
+            by_owner: Mutex::new(HashMap::new()),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT)),
+        }
+    }
+}
+
+impl Stats {
+    pub fn record(
+        &self,
+        outcomes: &[Outcome],
+        identity: Option<&str>,
+        tenant: Option<&str>,
+        latency: Duration,
+    ) {
+        self.inner
+            .lock()
+            .unwrap()
+            .add(outcomes, identity, tenant, latency);
+        if let Some(identity) = identity {
+            let mut by_owner = self.by_owner.lock().unwrap();
+            let own = by_owner.entry(identity.to_string()).or_default();
+            own.add(outcomes, Some(identity), tenant, latency);
+        }
+
+        let mut cases = Vec::new();
+        for o in outcomes {
+            let case = format!("{:?}", o.case);
+            if !cases.contains(&case) {
+                cases.push(case);
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(Recent {
+            at: Utc::now(),
+            identity: identity.map(String::from),
+            tenant: tenant.map(String::from),
+            cases,
+            items: outcomes.len(),
+            failed: outcomes.iter().filter(|o| o.error.is_some()).count(),
+            latency_ms: latency.as_secs_f64() * 1000.0,
+        });
+    }
+
+    /// Last compute requests, newest first
+    pub fn recent(&self) -> Vec<Recent> {
+        self.recent.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Failures before any payload got computed, e.g. malformed JSON
+    pub fn record_error(&self, kind: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.requests += 1;
+        *inner.by_error.entry(kind.to_string()).or_default() += 1;
+    }
+
+    pub fn summary(&self) -> Summary {
+        self.inner.lock().unwrap().summary(self.started.elapsed())
+    }
+
+    /// Computes of `identity` only
+    pub fn summary_of(&self, identity: &str) -> Summary {
+        let by_owner = self.by_owner.lock().unwrap();
+        let uptime = self.started.elapsed();
+        match by_owner.get(identity) {
+            Some(own) => own.summary(uptime),
+            None => Inner::default().summary(uptime),
+        }
+    }
+}
+
+/// Everything for `ops` and with authentication off, the caller's own computes otherwise
+pub async fn summary(stats: web::Data<Stats>, req: HttpRequest) -> HttpResponse {
+    let caller = Caller::of(&req);
+    match caller.owner() {
+        _ if caller.sees_all() => HttpResponse::Ok().json(stats.summary()),
+        Some(identity) => HttpResponse::Ok().json(stats.summary_of(identity)),
+        None => problem(StatusCode::UNAUTHORIZED, "Missing credentials"),
+    }
+}
+
+/// Middleware feeding `Outcomes` and request latency into `Stats`
+pub struct StatsRecorder(pub web::Data<Stats>);
+
+impl<S, B> Transform<S> for StatsRecorder
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = StatsRecorderMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(StatsRecorderMiddleware {
+            service,
+            stats: self.0.clone(),
+        })
+    }
+}
+
+pub struct StatsRecorderMiddleware<S> {
+    service: S,
+    stats: web::Data<Stats>,
+}
+
+impl<S, B> Service for StatsRecorderMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let stats = self.stats.clone();
+        let identity = match req.extensions().get::<Auth>() {
+            Some(Auth::Identified(identity)) => Some(identity.name.clone()),
+            _ => None,
+        };
+        // unknown tenants are refused before anything is computed
+        let tenant = req
+            .headers()
+            .get(TENANT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            if let Some(Outcomes(outcomes)) = res.response().extensions().get::<Outcomes>() {
+                let (identity, tenant) = (identity.as_deref(), tenant.as_deref());
+                stats.record(outcomes, identity, tenant, started.elapsed());
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_outcomes() {
+        let stats = Stats::default();
+        let ok = Outcome {
+            case: Case::C1,
+            h: H::P,
+            error: None,
+            compute: Duration::from_millis(2),
+        };
+        let failed = Outcome {
+            case: Case::B,
+            h: H::E,
+            error: Some("unsupported_params"),
+            compute: Duration::from_millis(4),
+        };
+        stats.record(
+            &[ok],
+            Some("partner"),
+            Some("acme"),
+            Duration::from_millis(2),
+        );
+        stats.record(&[failed], None, None, Duration::from_millis(4));
+        stats.record_error("invalid_json");
+
+        let summary = stats.summary();
+
+        assert_eq!(summary.requests, 3);
+        assert_eq!(summary.by_case["C1"], 1);
+        assert_eq!(summary.by_h["E"], 1);
+        assert_eq!(summary.by_error["invalid_json"], 1);
+        assert_eq!(summary.by_identity["partner"], 1);
+        assert_eq!(summary.latency_ms.samples, 2);
+        assert!((summary.latency_ms.p99 - 4.0).abs() < 1e-9);
+        let c1 = &summary.latency_by_case["C1"]["P"];
+        assert_eq!(c1.count, 1);
+        assert_eq!(c1.buckets[0].count, 0);
+        assert_eq!(c1.buckets[1].count, 1);
+        assert_eq!(summary.latency_by_case["B"]["E"].count, 1);
+        assert_eq!(summary.h_by_case["C1"]["P"], 1);
+        assert_eq!(summary.h_by_tenant["acme"]["P"], 1);
+        assert_eq!(summary.h_by_tenant["-"]["E"], 1);
+
+        let own = stats.summary_of("partner");
+        assert_eq!(own.requests, 1);
+        assert_eq!(own.by_case["C1"], 1);
+        assert!(own.by_error.is_empty());
+        assert_eq!(stats.summary_of("other").requests, 0);
+
+        let recent = stats.recent();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(
+            (recent[0].cases.as_slice(), recent[0].failed),
+            (&["B".to_string()][..], 1)
+        );
+        assert_eq!(recent[1].identity.as_deref(), Some("partner"));
+    }
+}
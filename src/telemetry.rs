@@ -0,0 +1,181 @@
+//! With `[otlp]` set, spans are exported over OTLP/gRPC to its `endpoint`, keeping
+//! `sample_ratio` of the traces started here. Computes show their `validate` (matching the
+//! A/B/C rules) and `compute` (K) phases as child spans of the request, deserialization ran
+//! before the handler and is its `deserialize_ms`.
+
+use std::collections::HashMap;
+
+use actix_web::http::HeaderMap;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TraceError;
+use opentelemetry::{global, Context, KeyValue};
+use serde_derive::{Deserialize, Serialize};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// OpenTelemetry collector the spans are exported to over OTLP/gRPC
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OtlpSettings {
+    /// `host:port` of the collector, e.g. `localhost:4317`
+    pub endpoint: String,
+    /// Share of traces started here that are exported, from 0 to 1. Traces continued from a
+    /// caller follow its sampling decision.
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+    /// `service.name` the spans are reported under
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_service_name() -> String {
+    "rest-test-params".into()
+}
+
+/// W3C trace context a request came with, kept in request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub traceparent: String,
+    pub tracestate: Option<String>,
+}
+
+/// `version-traceid-parentid-flags` in lowercase hex, ids not all zeros
+fn well_formed(traceparent: &str) -> bool {
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    let hex = |s: &str, len: usize| {
+        s.len() == len && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    };
+    match parts.as_slice() {
+        [version, trace_id, parent_id, flags] => {
+            hex(version, 2)
+                && *version != "ff"
+                && hex(trace_id, 32)
+                && hex(parent_id, 16)
+                && hex(flags, 2)
+                && trace_id.chars().any(|c| c != '0')
+                && parent_id.chars().any(|c| c != '0')
+        }
+        _ => false,
+    }
+}
+
+impl TraceContext {
+    /// Context sent in `headers`, none if `traceparent` is missing or malformed
+    pub fn of(headers: &HeaderMap) -> Option<Self> {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let traceparent = header(TRACEPARENT).filter(|t| well_formed(t))?;
+        Some(TraceContext {
+            traceparent: traceparent.to_string(),
+            tracestate: header(TRACESTATE).map(String::from),
+        })
+    }
+
+    fn headers(&self) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        headers.insert(TRACEPARENT.to_string(), self.traceparent.clone());
+        if let Some(state) = &self.tracestate {
+            headers.insert(TRACESTATE.to_string(), state.clone());
+        }
+        headers
+    }
+
+    /// Parent of the spans continuing this trace
+    pub fn context(&self) -> Context {
+        global::get_text_map_propagator(|p| p.extract(&self.headers()))
+    }
+}
+
+/// Trace headers of an outbound request made in `span`. Its own context when spans are
+/// exported, otherwise the `received` one is passed on as it came.
+pub fn outbound(span: &Span, received: Option<&TraceContext>) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|p| p.inject_context(&span.context(), &mut headers));
+    match received {
+        Some(received) if headers.is_empty() => received.headers(),
+        _ => headers,
+    }
+}
+
+/// Fails on a `sample_ratio` outside 0 to 1
+pub fn check(settings: &OtlpSettings) -> Result<(), String> {
+    if (0.0..=1.0).contains(&settings.sample_ratio) {
+        Ok(())
+    } else {
+        Err(format!(
+            "sample_ratio {} is not between 0 and 1",
+            settings.sample_ratio
+        ))
+    }
+}
+
+/// Tracer batching spans to the collector of `settings`, installed as the global one along
+/// with W3C trace context propagation
+pub fn tracer(settings: &OtlpSettings) -> Result<Tracer, TraceError> {
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(settings.sample_ratio)));
+    let resource = Resource::new(vec![KeyValue::new(
+        "service.name",
+        settings.service_name.clone(),
+    )]);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    opentelemetry_otlp::new_pipeline()
+        .with_endpoint(&settings.endpoint)
+        .with_trace_config(
+            trace::config()
+                .with_sampler(sampler)
+                .with_resource(resource),
+        )
+        .install_batch(opentelemetry::runtime::AsyncStd)
+}
+
+/// Exports the spans still buffered, called once the servers stopped
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_sample_ratio() {
+        let mut settings = OtlpSettings {
+            endpoint: "localhost:4317".into(),
+            sample_ratio: default_sample_ratio(),
+            service_name: default_service_name(),
+        };
+        assert!(check(&settings).is_ok());
+
+        settings.sample_ratio = 1.5;
+        assert!(check(&settings).is_err());
+    }
+
+    #[test]
+    fn passes_received_context_on() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT.parse().unwrap(), traceparent.parse().unwrap());
+        headers.insert(TRACESTATE.parse().unwrap(), "vendor=1".parse().unwrap());
+        let received = TraceContext::of(&headers).unwrap();
+
+        // no exporter, so spans have no context of their own
+        let sent = outbound(&Span::none(), Some(&received));
+        assert_eq!(sent[TRACEPARENT], traceparent);
+        assert_eq!(sent[TRACESTATE], "vendor=1");
+
+        headers.insert(
+            TRACEPARENT.parse().unwrap(),
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(TraceContext::of(&headers), None);
+    }
+}
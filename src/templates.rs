@@ -0,0 +1,145 @@
+//! Authenticated clients get compute results rendered with the handlebars template of their
+//! identity name in `response_templates`, e.g. `{"result": {"category": "{{h}}", "value":
+//! {{k}}}}`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use handlebars::{no_escape, Handlebars, TemplateError};
+use log::warn;
+
+use crate::auth::Auth;
+use crate::pretty::is_json;
+use crate::stats::Outcomes;
+
+/// Handlebars templates reshaping compute results per authenticated client, e.g.
+/// `{"result": {"category": "{{h}}", "value": {{k}}}}`
+pub struct ResponseTemplates(Handlebars<'static>);
+
+impl ResponseTemplates {
+    pub fn new(templates: &HashMap<String, String>) -> Result<Self, TemplateError> {
+        let mut hb = Handlebars::new();
+        hb.register_escape_fn(no_escape);
+        for (client, template) in templates {
+            hb.register_template_string(client, template)?;
+        }
+        Ok(ResponseTemplates(hb))
+    }
+
+    fn render(&self, client: &str, body: &[u8]) -> Option<Vec<u8>> {
+        if !self.0.has_template(client) {
+            return None;
+        }
+        let data: serde_json::Value = serde_json::from_slice(body).ok()?;
+        match self.0.render(client, &data) {
+            Ok(out) => Some(out.into_bytes()),
+            Err(e) => {
+                warn!("Could not render template of {}: {:?}", client, e);
+                None
+            }
+        }
+    }
+}
+
+/// Middleware rendering successful single compute responses with the client's template.
+/// Works on plain `Body`, so it has to be the innermost `wrap` of the app.
+pub struct Templating(pub Arc<ResponseTemplates>);
+
+impl<S> Transform<S> for Templating
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TemplatingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TemplatingMiddleware {
+            service,
+            templates: self.0.clone(),
+        })
+    }
+}
+
+pub struct TemplatingMiddleware<S> {
+    service: S,
+    templates: Arc<ResponseTemplates>,
+}
+
+impl<S> Service for TemplatingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        // picked by identity, a header naming another client would show its template
+        let client = match req.extensions().get::<Auth>() {
+            Some(Auth::Identified(identity)) => identity.name.clone(),
+            _ => return Box::pin(self.service.call(req)),
+        };
+        let templates = self.templates.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            let single = res
+                .response()
+                .extensions()
+                .get::<Outcomes>()
+                .map_or(false, |o| o.0.len() == 1);
+            if !single || res.status() != 200 || !is_json(&res) {
+                return Ok(res);
+            }
+
+            Ok(res.map_body(|_, body| match body {
+                ResponseBody::Body(Body::Bytes(bytes)) => match templates.render(&client, &bytes) {
+                    Some(out) => ResponseBody::Body(Body::from(out)),
+                    None => ResponseBody::Body(Body::Bytes(bytes)),
+                },
+                body => body,
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_client_template() {
+        let mut config = HashMap::new();
+        config.insert(
+            "legacy".to_string(),
+            r#"{"result": {"category": "{{h}}", "value": {{k}}}}"#.to_string(),
+        );
+        let templates = ResponseTemplates::new(&config).unwrap();
+        let out = templates
+            .render("legacy", br#"{"h":"M","k":7.585}"#)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"result": {"category": "M", "value": 7.585}}"#
+        );
+        assert!(templates.render("other", b"{}").is_none());
+    }
+}
@@ -0,0 +1,103 @@
+//! Customers sharing a deployment send `X-Tenant`, picking their own `default_case` and
+//! `require_case` from `tenants`. Unknown tenants get `400`.
+//!
+//! A missing `case` falls back to `default_case` (`B`) with a `Warning` header,
+//! `require_case = true` rejects it. The case applied is echoed in `X-Applied-Case`, and as
+//! `case` of batch items.
+
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::Settings;
+use crate::errors::json_error;
+use crate::types::Case;
+
+pub const TENANT_HEADER: &str = "x-tenant";
+
+/// Configured tenant a request was served for, stored in request extensions by `policy`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptedTenant(pub String);
+
+/// Overrides of the case defaults for one customer, unset fields keep the global ones
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Tenant {
+    pub default_case: Option<Case>,
+    pub require_case: Option<bool>,
+}
+
+/// Case defaults applying to a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub default_case: Case,
+    pub require_case: bool,
+}
+
+impl Policy {
+    fn of(settings: &Settings, tenant: Option<&Tenant>) -> Self {
+        let tenant = tenant.cloned().unwrap_or_default();
+        Policy {
+            default_case: tenant
+                .default_case
+                .unwrap_or_else(|| settings.default_case.clone()),
+            require_case: tenant.require_case.unwrap_or(settings.require_case),
+        }
+    }
+}
+
+/// Policy of the tenant named in `X-Tenant`, the global one without the header.
+/// Unknown tenants are rejected rather than served with defaults meant for others.
+pub fn policy(settings: &Settings, req: &HttpRequest) -> Result<Policy, Error> {
+    let name = match req.headers().get(TENANT_HEADER) {
+        None => return Ok(Policy::of(settings, None)),
+        Some(v) => v.to_str().unwrap_or_default(),
+    };
+    match settings.tenants.get(name) {
+        Some(tenant) => {
+            req.extensions_mut()
+                .insert(AcceptedTenant(name.to_string()));
+            Ok(Policy::of(settings, Some(tenant)))
+        }
+        None => {
+            let message = format!("Unknown tenant {:?}", name);
+            let resp = json_error(StatusCode::BAD_REQUEST, message.clone());
+            Err(InternalError::from_response(message, resp).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn tenant_overrides_defaults() {
+        let mut settings = Settings::default();
+        settings.tenants.insert(
+            "acme".into(),
+            Tenant {
+                default_case: Some(Case::C2),
+                require_case: None,
+            },
+        );
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(policy(&settings, &req).unwrap().default_case, Case::B);
+
+        let req = TestRequest::with_header(TENANT_HEADER, "acme").to_http_request();
+        let acme = policy(&settings, &req).unwrap();
+        assert_eq!(acme.default_case, Case::C2);
+        assert!(!acme.require_case);
+        assert_eq!(
+            req.extensions().get::<AcceptedTenant>(),
+            Some(&AcceptedTenant("acme".into()))
+        );
+
+        let req = TestRequest::with_header(TENANT_HEADER, "other").to_http_request();
+        assert!(policy(&settings, &req).is_err());
+        assert!(req.extensions().get::<AcceptedTenant>().is_none());
+    }
+}
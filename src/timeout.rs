@@ -0,0 +1,96 @@
+//! Requests running longer than `request_timeout_ms` get `504`, `request_timeouts_ms`
+//! overrides it per route (2s for `/compute`, 60s for `/compute/batch`).
+
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::config::Settings;
+use crate::errors::json_error;
+use crate::reload::Live;
+
+/// Middleware answering 504 when the wrapped resource takes longer than the limit.
+/// The handler future is dropped, so it stops at its next await point.
+pub struct Timeout {
+    route: &'static str,
+    /// Limit at startup, used while no `Live` settings are registered
+    limit: Duration,
+}
+
+impl Timeout {
+    /// Timeout of `route` (within `/v1`), following config reloads
+    pub fn of(route: &'static str, settings: &Settings) -> Self {
+        Timeout {
+            route,
+            limit: settings.timeout_of(route),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Timeout
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TimeoutMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TimeoutMiddleware {
+            service,
+            route: self.route,
+            limit: self.limit,
+        })
+    }
+}
+
+pub struct TimeoutMiddleware<S> {
+    service: S,
+    route: &'static str,
+    limit: Duration,
+}
+
+impl<S, B> Service for TimeoutMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let limit = req
+            .app_data::<Live>()
+            .map_or(self.limit, |live| live.timeout_of(self.route));
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            match actix_rt::time::timeout(limit, fut).await {
+                Ok(res) => res,
+                Err(_) => {
+                    let message = format!("Request took longer than {} ms", limit.as_millis());
+                    let resp = json_error(StatusCode::GATEWAY_TIMEOUT, message.clone());
+                    Err(InternalError::from_response(message, resp).into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+
+use tracing::{info_span, Span};
+
+/// When the request entered the app, stored in request extensions
+pub struct RequestStart(pub Instant);
+
+/// Named phase durations of a request, rendered as a `Server-Timing` header. Every phase
+/// is also a span named after it within the request span.
+#[derive(Debug, Default)]
+pub struct Timings(Vec<(&'static str, Duration)>);
+
+impl Timings {
+    /// Records a phase that already ran, e.g. deserialization before the handler. A span
+    /// opened now could not cover it, so it goes in the `<phase>_ms` field of the current
+    /// span instead, if that declares one.
+    pub fn record(&mut self, phase: &'static str, duration: Duration) {
+        let ms = duration.as_secs_f64() * 1000.0;
+        Span::current().record(format!("{}_ms", phase).as_str(), &ms);
+        self.0.push((phase, duration));
+    }
+
+    /// Runs `f` in a span of `phase`, recording how long it took
+    pub fn measure<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = info_span!("phase", otel.name = phase).in_scope(f);
+        self.0.push((phase, started.elapsed()));
+        result
+    }
+
+    /// Time spent in `phase`, summed over every time it ran
+    pub fn total(&self, phase: &str) -> Duration {
+        self.0
+            .iter()
+            .filter(|(p, _)| *p == phase)
+            .map(|(_, d)| *d)
+            .sum()
+    }
+
+    /// Phases in the order they first ran, repeated ones like the items of a batch summed
+    pub fn header_value(&self) -> String {
+        let mut phases: Vec<&'static str> = vec![];
+        for (phase, _) in &self.0 {
+            if !phases.contains(phase) {
+                phases.push(phase);
+            }
+        }
+        phases
+            .iter()
+            .map(|phase| {
+                let ms = self.total(phase).as_secs_f64() * 1000.0;
+                format!("{};dur={:.3}", phase, ms)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
@@ -0,0 +1,372 @@
+//! With `[tls]` (`cert`, `key` PEM files, or the PEM text) set, `bind` and `binds` serve
+//! HTTPS. The files are checked every 30 seconds and swapped in without a restart once they
+//! change, an invalid pair is logged and the previous certificate stays in use. `client_ca`
+//! (PEM bundle) makes clients present a certificate signed by it, its subject is recorded
+//! as `client_cert` in audit entries and handlers read it with `client_subject`. Reload at
+//! once with:
+//!
+//! ``` curl -X POST localhost:3030/admin/tls/reload ```
+//!
+//! `min_version` (`1.2` or `1.3`) and `cipher_suites` (rustls names such as
+//! `TLS13_AES_256_GCM_SHA384`, all if empty) narrow what is negotiated, `log_handshakes =
+//! true` logs the peer, protocol and cipher suite of every connection, resumed ones
+//! included.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Cursor};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_http::{HttpService, Request};
+use actix_rt::net::TcpStream;
+use actix_service::{map_config, IntoServiceFactory, Service, ServiceFactory};
+use actix_tls::rustls::TlsStream;
+use actix_web::body::MessageBody;
+use actix_web::dev::{AppConfig, Server};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use log::{info, warn};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, ClientHello, NoClientAuth, ProtocolVersion, ResolvesServerCert,
+    RootCertStore, ServerConfig, Session, SupportedCipherSuite, ALL_CIPHERSUITES,
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::Settings;
+use crate::errors::json_error;
+
+/// Seconds between checks of the certificate files for changes
+const WATCH_INTERVAL: u64 = 30;
+
+/// PEM files of the server certificate chain and its private key, or the PEM text itself
+/// (e.g. from Vault)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsSettings {
+    pub cert: String,
+    pub key: String,
+    /// CA bundle client certificates must be signed by, clients need none if absent
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    /// Oldest protocol accepted, `1.2` or `1.3`
+    #[serde(default = "default_min_version")]
+    pub min_version: String,
+    /// Cipher suites offered by rustls name, e.g. `TLS13_AES_256_GCM_SHA384`, all if empty
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// Log the protocol and cipher suite of every connection once its handshake completed
+    #[serde(default)]
+    pub log_handshakes: bool,
+}
+
+fn default_min_version() -> String {
+    "1.2".into()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn is_inline(source: &str) -> bool {
+    source.trim_start().starts_with("-----BEGIN")
+}
+
+/// How `source` shows in messages, never the PEM text of a key
+pub fn describe(source: &str) -> &str {
+    if is_inline(source) {
+        "inline PEM"
+    } else {
+        source
+    }
+}
+
+fn open(source: &str) -> io::Result<Box<dyn BufRead>> {
+    if is_inline(source) {
+        Ok(Box::new(Cursor::new(source.as_bytes().to_vec())))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(source)?)))
+    }
+}
+
+fn load(settings: &TlsSettings) -> io::Result<CertifiedKey> {
+    let chain = certs(&mut open(&settings.cert)?)
+        .map_err(|_| invalid(format!("No certificates in {}", describe(&settings.cert))))?;
+    let read_keys = |pkcs8: bool| -> io::Result<_> {
+        let mut reader = open(&settings.key)?;
+        let keys = if pkcs8 {
+            pkcs8_private_keys(&mut reader)
+        } else {
+            rsa_private_keys(&mut reader)
+        };
+        Ok(keys.unwrap_or_default())
+    };
+    let mut keys = read_keys(true)?;
+    if keys.is_empty() {
+        keys = read_keys(false)?;
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| invalid(format!("No private key in {}", describe(&settings.key))))?;
+    let key = sign::any_supported_type(key).map_err(|_| {
+        invalid(format!(
+            "Unsupported private key in {}",
+            describe(&settings.key)
+        ))
+    })?;
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+/// Certificate served to new connections, swapped in place when the files change
+pub struct CertStore {
+    settings: TlsSettings,
+    current: RwLock<CertifiedKey>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl CertStore {
+    pub fn new(settings: &TlsSettings) -> io::Result<Self> {
+        let store = CertStore {
+            settings: settings.clone(),
+            current: RwLock::new(load(settings)?),
+            modified: Mutex::new(None),
+        };
+        *store.modified.lock().unwrap() = store.files_modified();
+        Ok(store)
+    }
+
+    /// Latest modification of the cert or key file, inline PEM never changes
+    fn files_modified(&self) -> Option<SystemTime> {
+        [&self.settings.cert, &self.settings.key]
+            .iter()
+            .filter(|source| !is_inline(source))
+            .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .max()
+    }
+
+    /// Loads the files again, keeping the current certificate if they are broken
+    pub fn reload(&self) -> io::Result<()> {
+        let key = load(&self.settings)?;
+        *self.current.write().unwrap() = key;
+        *self.modified.lock().unwrap() = self.files_modified();
+        info!("Reloaded TLS certificate {}", describe(&self.settings.cert));
+        Ok(())
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = self.files_modified();
+        if modified == *self.modified.lock().unwrap() {
+            return;
+        }
+        if let Err(e) = self.reload() {
+            warn!("Could not reload TLS certificate: {:?}", e);
+        }
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn client_roots(source: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut open(source)?) {
+        Ok((added, _)) if added > 0 => Ok(roots),
+        _ => Err(invalid(format!(
+            "No CA certificates in {}",
+            describe(source)
+        ))),
+    }
+}
+
+fn versions(min_version: &str) -> io::Result<Vec<ProtocolVersion>> {
+    match min_version {
+        "1.2" => Ok(vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]),
+        "1.3" => Ok(vec![ProtocolVersion::TLSv1_3]),
+        other => Err(invalid(format!(
+            "Unsupported TLS min_version {}, expected 1.2 or 1.3",
+            other
+        ))),
+    }
+}
+
+/// Suites of `names` in rustls' order of preference, all of them if there are none
+fn cipher_suites(names: &[String]) -> io::Result<Vec<&'static SupportedCipherSuite>> {
+    if names.is_empty() {
+        return Ok(ALL_CIPHERSUITES.to_vec());
+    }
+    if let Some(unknown) = names.iter().find(|name| {
+        !ALL_CIPHERSUITES
+            .iter()
+            .any(|s| &format!("{:?}", s.suite) == *name)
+    }) {
+        return Err(invalid(format!("Unknown cipher suite {}", unknown)));
+    }
+    Ok(ALL_CIPHERSUITES
+        .iter()
+        .filter(|s| names.contains(&format!("{:?}", s.suite)))
+        .cloned()
+        .collect())
+}
+
+pub fn server_config(store: Arc<CertStore>) -> io::Result<ServerConfig> {
+    let settings = &store.settings;
+    let mut config = match &settings.client_ca {
+        Some(path) => ServerConfig::new(AllowAnyAuthenticatedClient::new(client_roots(path)?)),
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+    config.versions = versions(&settings.min_version)?;
+    config.ciphersuites = cipher_suites(&settings.cipher_suites)?;
+    if !config
+        .ciphersuites
+        .iter()
+        .any(|s| config.versions.iter().any(|v| s.usable_for_version(*v)))
+    {
+        return Err(invalid(format!(
+            "No cipher suite left for TLS {} and up",
+            settings.min_version
+        )));
+    }
+    config.cert_resolver = store;
+    Ok(config)
+}
+
+/// What the handshake of a connection settled, in the extensions of each of its requests
+#[derive(Debug, Clone)]
+pub struct Peer {
+    /// Subject of the verified client certificate with `client_ca`, e.g. `CN=hook, O=Acme`
+    pub subject: Option<String>,
+}
+
+impl Peer {
+    fn of(io: &TlsStream<TcpStream>) -> Self {
+        let (_, session) = io.get_ref();
+        let subject = session
+            .get_peer_certificates()
+            .and_then(|chain| chain.into_iter().next())
+            .and_then(|cert| {
+                x509_parser::parse_x509_certificate(&cert.0)
+                    .ok()
+                    .map(|(_, cert)| cert.subject().to_string())
+            });
+        Peer { subject }
+    }
+}
+
+/// Client certificate subject of the connection `req` came over, for audit and policies
+pub fn client_subject(req: &impl HttpMessage) -> Option<String> {
+    req.extensions()
+        .get::<Peer>()
+        .and_then(|peer| peer.subject.clone())
+}
+
+/// Logs what the handshake of a connection negotiated, resumed sessions included
+fn log_handshake(io: &TlsStream<TcpStream>) {
+    let (tcp, session) = io.get_ref();
+    info!(
+        "TLS handshake with {:?}: {:?} {:?}",
+        tcp.peer_addr().ok(),
+        session.get_protocol_version(),
+        session.get_negotiated_ciphersuite().map(|s| s.suite)
+    );
+}
+
+/// Serves `app` over TLS on `listeners` with the tuning of `settings`. Connections are
+/// accepted here rather than by `HttpServer::listen_rustls`, which hides the TLS session
+/// once the handshake completed, and their `Peer` goes with every request.
+pub fn serve<F, I, S, B>(
+    app: F,
+    listeners: Vec<TcpListener>,
+    config: ServerConfig,
+    settings: &Settings,
+) -> io::Result<Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S>,
+    S: ServiceFactory<Config = AppConfig, Request = Request>,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<HttpResponse<B>> + 'static,
+    <S::Service as Service>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let log = settings
+        .tls
+        .as_ref()
+        .map_or(false, |tls| tls.log_handshakes);
+    let keep_alive = match settings.keep_alive {
+        0 => None,
+        secs => Some(secs),
+    };
+    let (client_timeout, client_shutdown) = (settings.client_timeout, settings.client_shutdown);
+    actix_tls::max_concurrent_ssl_connect(settings.max_connection_rate);
+    let mut builder = Server::build()
+        .disable_signals()
+        .shutdown_timeout(settings.shutdown_timeout)
+        .maxconn(settings.max_connections);
+    if let Some(n) = settings.workers {
+        builder = builder.workers(n);
+    }
+    for listener in listeners {
+        let addr = listener.local_addr()?;
+        info!("Listening with TLS on {}", addr);
+        let (app, config) = (app.clone(), config.clone());
+        builder = builder.listen(format!("rtp-tls-{}", addr), listener, move || {
+            HttpService::build()
+                .keep_alive(keep_alive)
+                .client_timeout(client_timeout)
+                .client_disconnect(client_shutdown)
+                .on_connect(move |io: &TlsStream<TcpStream>| {
+                    if log {
+                        log_handshake(io);
+                    }
+                    Peer::of(io)
+                })
+                .finish(map_config(app(), |_| AppConfig::default()))
+                .rustls(config.clone())
+        })?;
+    }
+    Ok(builder.run())
+}
+
+/// Polls the certificate files, reloading them once they change
+pub fn spawn_watcher(store: Arc<CertStore>) {
+    actix_rt::spawn(async move {
+        let mut tick = actix_rt::time::interval(Duration::from_secs(WATCH_INTERVAL));
+        loop {
+            tick.tick().await;
+            store.reload_if_changed();
+        }
+    });
+}
+
+pub async fn reload(store: web::Data<Option<Arc<CertStore>>>) -> HttpResponse {
+    match store.as_ref().as_ref().map(|s| s.reload()) {
+        Some(Ok(())) => HttpResponse::NoContent().finish(),
+        Some(Err(e)) => json_error(
+            StatusCode::BAD_REQUEST,
+            format!("Could not reload TLS certificate: {}", e),
+        ),
+        None => json_error(StatusCode::NOT_FOUND, "TLS is not enabled."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn narrows_versions_and_suites() {
+        assert_eq!(versions("1.3").unwrap(), vec![ProtocolVersion::TLSv1_3]);
+        assert!(versions("1.1").is_err());
+
+        let suites = cipher_suites(&["TLS13_AES_256_GCM_SHA384".to_string()]).unwrap();
+        assert_eq!(suites.len(), 1);
+        assert_eq!(cipher_suites(&[]).unwrap().len(), ALL_CIPHERSUITES.len());
+        assert!(cipher_suites(&["TLS_RSA_WITH_RC4_128_MD5".to_string()]).is_err());
+    }
+}
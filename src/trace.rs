@@ -0,0 +1,217 @@
+//! Every request runs in a `request` span carrying its `method`, `route`, `status`,
+//! `latency_ms` and for computes `deserialize_ms`, `case` and `h`, attached to the access
+//! log line and anything logged while handling it.
+//!
+//! A well-formed W3C `traceparent` (and `tracestate`) is recorded on the request span,
+//! which continues that trace when spans are exported. Schedule webhooks carry the trace of
+//! the request that created the schedule on, as received if spans are not exported.
+
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{guard, web, Error, HttpMessage, HttpRequest, Resource};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tracing::{field, info, info_span};
+use tracing_futures::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::request_id::RequestId;
+use crate::stats::Outcomes;
+use crate::telemetry::TraceContext;
+use crate::types::H;
+
+/// Pattern of the resource a request matched, relative to its scope
+#[derive(Debug, Clone, Copy)]
+struct Pattern(&'static str);
+
+/// `web::resource(pattern)` leaving `pattern` on the requests it matches, so spans name
+/// the route rather than the path. Needed for resources with parameters.
+pub fn resource(pattern: &'static str) -> Resource {
+    web::resource(pattern).guard(guard::fn_guard(move |head| {
+        head.extensions_mut().insert(Pattern(pattern));
+        true
+    }))
+}
+
+/// Matched route of `req`, e.g. `/v1/schedules/{id}`, the path for resources without
+/// a `Pattern`
+fn route(req: &HttpRequest) -> String {
+    match req.extensions().get::<Pattern>() {
+        Some(Pattern(pattern)) => {
+            let info = req.match_info();
+            let full = info.get_ref().path();
+            let scope = &full[..full.len().saturating_sub(info.path().len())];
+            format!("{}{}", scope, pattern)
+        }
+        None => req.path().to_string(),
+    }
+}
+
+/// `case` and `h` fields of a span, comma separated for batches
+fn outcome_fields(outcomes: &Outcomes) -> (String, String) {
+    let Outcomes(outcomes) = outcomes;
+    let case: Vec<String> = outcomes.iter().map(|o| format!("{:?}", o.case)).collect();
+    let h: Vec<String> = outcomes
+        .iter()
+        .map(|o| match o.error {
+            Some(_) => format!("{:?}", H::E),
+            None => format!("{:?}", o.h),
+        })
+        .collect();
+    (case.join(","), h.join(","))
+}
+
+/// Middleware running every request in a `request` span carrying its id and the incoming
+/// trace context, recording its route, status, latency and, for computes, the time spent
+/// deserializing, the case and H once answered. The span continues the caller's trace when spans are exported.
+pub struct RequestSpan;
+
+impl<S, B> Transform<S> for RequestSpan
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestSpanMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestSpanMiddleware { service })
+    }
+}
+
+pub struct RequestSpanMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestSpanMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let id = req
+            .extensions()
+            .get::<RequestId>()
+            .map(|RequestId(id)| id.clone());
+        let trace = TraceContext::of(req.headers());
+        let span = info_span!(
+            "request",
+            request_id = id.as_deref().unwrap_or_default(),
+            traceparent = field::Empty,
+            tracestate = field::Empty,
+            method = %req.method(),
+            route = field::Empty,
+            status = field::Empty,
+            latency_ms = field::Empty,
+            deserialize_ms = field::Empty,
+            case = field::Empty,
+            h = field::Empty,
+        );
+        if let Some(trace) = trace {
+            span.record("traceparent", &trace.traceparent.as_str());
+            if let Some(state) = &trace.tracestate {
+                span.record("tracestate", &state.as_str());
+            }
+            span.set_parent(trace.context());
+            req.extensions_mut().insert(trace);
+        }
+        let fut = span.in_scope(|| self.service.call(req));
+        let recorded = span.clone();
+
+        Box::pin(
+            async move {
+                let res = fut.await;
+                recorded.record("latency_ms", &(started.elapsed().as_secs_f64() * 1000.0));
+                match &res {
+                    Ok(res) => {
+                        recorded.record("route", &route(res.request()).as_str());
+                        recorded.record("status", &res.status().as_u16());
+                        if let Some(outcomes) = res.response().extensions().get::<Outcomes>() {
+                            let (case, h) = outcome_fields(outcomes);
+                            recorded.record("case", &case.as_str());
+                            recorded.record("h", &h.as_str());
+                        }
+                    }
+                    Err(e) => {
+                        recorded.record("status", &e.as_response_error().status_code().as_u16());
+                    }
+                }
+                // fields recorded late only reach the layers with the next event
+                info!("Request answered");
+                res
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::Outcome;
+    use crate::types::Case;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn describes_routes_and_outcomes() {
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("/v1")
+                    .service(
+                        resource("/schedules/{id}")
+                            .to(|req: HttpRequest| async move { route(&req) }),
+                    )
+                    .service(
+                        web::resource("/stats").to(|req: HttpRequest| async move { route(&req) }),
+                    ),
+            ),
+        )
+        .await;
+        for (uri, expected) in &[
+            ("/v1/schedules/7", "/v1/schedules/{id}"),
+            ("/v1/schedules/%37", "/v1/schedules/{id}"),
+            ("/v1/stats", "/v1/stats"),
+        ] {
+            let req = test::TestRequest::with_uri(uri).to_request();
+            let body = test::read_response(&mut app, req).await;
+            assert_eq!(body, expected.as_bytes());
+        }
+
+        let outcomes = Outcomes(vec![
+            Outcome {
+                case: Case::C1,
+                h: H::P,
+                error: None,
+                compute: Default::default(),
+            },
+            Outcome {
+                case: Case::B,
+                h: H::M,
+                error: Some("missing_params"),
+                compute: Default::default(),
+            },
+        ]);
+        assert_eq!(
+            outcome_fields(&outcomes),
+            ("C1,B".to_string(), "P,E".to_string())
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Successful JSON responses can be reshaped with a JMESPath expression, e.g.
+//! `?transform=k`.
+
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_derive::Deserialize;
+
+use crate::errors::json_error;
+use crate::pretty::is_json;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TransformQuery {
+    /// JMESPath expression applied to successful JSON responses, e.g. `k`
+    #[serde(default)]
+    pub transform: Option<String>,
+}
+
+/// Middleware reshaping successful JSON responses with `?transform=<JMESPath>`.
+/// Works on plain `Body`, so it has to be registered right after `Templating`.
+pub struct JsonTransform;
+
+impl<S> Transform<S> for JsonTransform
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = JsonTransformMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(JsonTransformMiddleware { service })
+    }
+}
+
+pub struct JsonTransformMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service for JsonTransformMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let expr = web::Query::<TransformQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|q| q.into_inner().transform);
+        let expr = match expr {
+            Some(expr) => expr,
+            None => return Box::pin(self.service.call(req)),
+        };
+        if let Err(e) = jmespath::compile(&expr) {
+            let resp = json_error(StatusCode::BAD_REQUEST, format!("Wrong transform: {}", e));
+            return Box::pin(ok(req.into_response(resp)));
+        }
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            if !res.status().is_success() || !is_json(&res) {
+                return Ok(res);
+            }
+
+            Ok(res.map_body(|_, body| match body {
+                ResponseBody::Body(Body::Bytes(bytes)) => match apply(&expr, &bytes) {
+                    Some(out) => ResponseBody::Body(Body::from(out)),
+                    None => ResponseBody::Body(Body::Bytes(bytes)),
+                },
+                body => body,
+            }))
+        })
+    }
+}
+
+fn apply(expr: &str, bytes: &[u8]) -> Option<Vec<u8>> {
+    let value: serde_json::Value = serde_json::from_slice(bytes).ok()?;
+    let result = jmespath::compile(expr).ok()?.search(value).ok()?;
+    serde_json::to_vec(&*result).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_field() {
+        let out = apply("k", br#"{"h":"M","k":7.585}"#).unwrap();
+
+        assert_eq!(out, b"7.585");
+    }
+}
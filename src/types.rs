@@ -1,6 +1,10 @@
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+/// Version of the built-in rules, bumped whenever a case computes differently
+pub const RULES_VERSION: &str = "1";
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 pub struct Params {
     #[serde(default)]
     pub a: Option<bool>,
@@ -17,13 +21,37 @@ pub struct Params {
     #[serde(default)]
     pub case: Option<Case>,
 }
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 pub struct Output {
     pub h: H,
     pub k: f64,
 }
 
+/// One `/compute/batch` payload, `id` is echoed back on its result
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub params: Params,
+}
+
+/// Result of one `/compute/batch` item
 #[derive(Debug, Serialize)]
+pub struct BatchItem {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    pub status: u16,
+    /// Case the item was computed with, the default one if it had none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub case: Option<Case>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Output>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub enum H {
     M,
     P,
@@ -31,7 +59,7 @@ pub enum H {
     E,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub enum Case {
     B,
     C1,
@@ -48,5 +76,8 @@ impl Default for H {
 pub struct ErrorMessage {
     pub code: u16,
     pub message: String,
+    /// Stable machine-readable reason, `message` may be translated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'static str>,
 }
 
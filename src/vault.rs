@@ -0,0 +1,88 @@
+//! With `[vault]` set, the fields of the secret at `path` (read with `token` or
+//! `VAULT_TOKEN`) override settings at startup and on every reload, keyed by paths like
+//! `tls.key`.
+
+use std::env;
+
+use actix_web::client::Client;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::Settings;
+
+/// Vault secret read at startup and on config reloads, its fields are settings paths like
+/// `api_keys` or `tls.key` and override whatever the config file and environment set
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VaultSettings {
+    /// Base URL, e.g. `https://vault.example.com:8200`
+    pub addr: String,
+    /// Path of the secret below `/v1`, e.g. `secret/data/rest-test-params` for KV v2
+    pub path: String,
+    /// Token to read it with, `VAULT_TOKEN` if absent
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Fields of a KV v2 (`data.data`) or KV v1 (`data`) secret response
+fn fields(body: &Value) -> Option<&serde_json::Map<String, Value>> {
+    let data = body.get("data")?;
+    match data.get("metadata") {
+        Some(_) => data.get("data")?.as_object(),
+        None => data.as_object(),
+    }
+}
+
+/// `settings` with the fields of the configured Vault secret put over them
+pub async fn apply(settings: Settings) -> Result<Settings, String> {
+    let vault = match settings.vault.clone() {
+        Some(vault) => vault,
+        None => return Ok(settings),
+    };
+    let token = vault
+        .token
+        .clone()
+        .or_else(|| env::var("VAULT_TOKEN").ok())
+        .ok_or("No Vault token, set vault.token or VAULT_TOKEN")?;
+    let url = format!(
+        "{}/v1/{}",
+        vault.addr.trim_end_matches('/'),
+        vault.path.trim_start_matches('/')
+    );
+    let mut resp = Client::new()
+        .get(url.as_str())
+        .header("X-Vault-Token", token)
+        .send()
+        .await
+        .map_err(|e| format!("Could not reach Vault at {}: {}", vault.addr, e))?;
+    if !resp.status().is_success() {
+        return Err(format!(
+            "Vault answered {} for {}",
+            resp.status(),
+            vault.path
+        ));
+    }
+    let body: Value = resp
+        .json()
+        .await
+        .map_err(|e| format!("Invalid Vault response: {}", e))?;
+    let secrets = fields(&body).ok_or_else(|| format!("No secret data at {}", vault.path))?;
+    info!("Read {} settings from Vault {}", secrets.len(), vault.path);
+    settings.overlay(secrets).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_kv_v1_and_v2() {
+        let v2 = json!({"data": {"data": {"tls.key": "k"}, "metadata": {"version": 3}}});
+        let v1 = json!({"data": {"tls.key": "k"}});
+
+        assert_eq!(fields(&v2).unwrap()["tls.key"], "k");
+        assert_eq!(fields(&v1).unwrap()["tls.key"], "k");
+        assert!(fields(&json!({"errors": []})).is_none());
+    }
+}